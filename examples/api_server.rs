@@ -1,6 +1,5 @@
-use axum::{Router, routing::get};
+use axum::routing::get;
 use afdb::{api, Config};
-use afdb::semantic::pipeline::{HttpEmbedder, DummyEmbedder, Embedder};
 use afdb::storage::Engine;
 use std::sync::Arc;
 use afdb::org::OrgGraph;
@@ -8,11 +7,16 @@ use afdb::org::OrgGraph;
 #[tokio::main]
 async fn main() {
     let cfg = Config::default();
-    let embedder: Box<dyn Embedder> = match HttpEmbedder::new(cfg.embedding.clone().unwrap(), cfg.vector_dims) {
-        Ok(e) => Box::new(e),
-        Err(_) => Box::new(DummyEmbedder::new("demo-mini", cfg.vector_dims)),
-    };
-    let engine = Arc::new(Engine::new(embedder, cfg.vector_dims));
+    // AFDB_RESTORE_FROM=<backup dir> seeds an empty data_dir from a backup
+    let restore = std::env::var("AFDB_RESTORE_FROM").ok();
+    if let Some(dir) = &restore {
+        Engine::restore_from(dir, &cfg).expect("failed to restore backup");
+    }
+    // Engine::open replays the WAL under cfg.wal_dir before serving
+    let engine = Arc::new(Engine::open(cfg).expect("failed to open engine"));
+    afdb::storage::flush::spawn_flusher(&engine, std::time::Duration::from_secs(1));
+    // persona scopes are resolved through it
+    afdb::persona::ensure_org_unit_index(&engine).expect("failed to index org units");
     let state = api::AppState {
         engine: engine.clone(),
         sessions: Arc::new(parking_lot::RwLock::new(std::collections::HashMap::new())),
@@ -21,7 +25,14 @@ async fn main() {
         contracts: Arc::new(parking_lot::RwLock::new(Vec::new())),
         taxonomy: Arc::new(parking_lot::RwLock::new(Vec::new())),
         policies: Arc::new(parking_lot::RwLock::new(Vec::new())),
+        // /admin routes need `Authorization: Bearer $AFDB_ADMIN_TOKEN`, and
+        // /admin/backup writes below $AFDB_BACKUP_ROOT
+        admin_token: std::env::var("AFDB_ADMIN_TOKEN").ok(),
+        backup_root: std::env::var("AFDB_BACKUP_ROOT").ok().map(std::path::PathBuf::from),
     };
+    if let Some(dir) = &restore {
+        state.restore_from(std::path::Path::new(dir)).expect("failed to restore app state");
+    }
     let app = api::router(state).route("/healthz", get(|| async { "ok" }));
     let addr = std::net::SocketAddr::from(([0,0,0,0], 8090));
     println!("API listening on http://{}", addr);
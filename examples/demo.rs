@@ -2,7 +2,6 @@
 use afdb::semantic::pipeline::{DummyEmbedder, Embedder, HttpEmbedder, ReasoningClient};
 use afdb::vector::flat::FlatIndex;
 use afdb::vector::hnsw::HnswIndex;
-use afdb::types::{Vector};
 use afdb::Config;
 
 fn main() {
@@ -22,20 +21,20 @@ fn main() {
         (4u64, "subscription renewed for annual plan"),
     ];
     for (id, t) in &texts {
-        flat.add(*id, emb.embed(t));
+        flat.add(*id, emb.embed(t)).unwrap();
     }
 
     // 3) Run a top-K query with the flat index
     let q = "credit card failed during payment";
-    let flat_hits = flat.cosine_topk(&emb.embed(q), 3);
+    let flat_hits = flat.cosine_topk(&emb.embed(q), 3).unwrap();
     println!("FlatIndex hits: {:?}", flat_hits);
 
     // 4) Same corpus in a tiny HNSW index
-    let mut hnsw = HnswIndex::new(64, 8, 4);
+    let hnsw = HnswIndex::new(64, 8, 4);
     for (id, t) in &texts {
-        hnsw.add(*id, emb.embed(t));
+        hnsw.add(*id, emb.embed(t)).unwrap();
     }
-    let h_hits = hnsw.topk(&emb.embed(q), 3);
+    let h_hits = hnsw.topk(&emb.embed(q), 3).unwrap();
     println!("HNSW hits: {:?}", h_hits);
 
     // 5) Reasoning call (optional)
@@ -1,8 +1,9 @@
 use axum::{routing::post, Router, Json};
 use serde::{Serialize, Deserialize};
 use std::net::SocketAddr;
-use afdb::query::{SemanticQl};
-use afdb::semantic::pipeline::{HttpEmbedder, ReasoningClient, Embedder};
+use afdb::query::{SearchMode, SemanticQl};
+use afdb::query::operators::HybridOp;
+use afdb::semantic::pipeline::{HttpEmbedder, Embedder};
 use afdb::storage::Engine;
 use afdb::Config;
 
@@ -25,9 +26,9 @@ async fn main() {
     // Preload minimal data for demo
     {
         let row = afdb::types::Row { key: afdb::types::RowKey("1".into()), payload: serde_json::json!({"text": "payment failed on renewal"}) };
-        engine.insert(1, row);
+        engine.insert(row).unwrap();
         let row2 = afdb::types::Row { key: afdb::types::RowKey("2".into()), payload: serde_json::json!({"text": "refund processed successfully"}) };
-        engine.insert(1, row2);
+        engine.insert(row2).unwrap();
     }
 
     let app = Router::new().route("/semanticql", post({
@@ -36,7 +37,13 @@ async fn main() {
             let engine = engine.clone();
             async move {
                 if let Some(parsed) = SemanticQl::parse(&req.ql) {
-                    let hits = engine.flat_index.read().cosine_topk(&engine.embedder.embed(&parsed.query), parsed.k);
+                    let read_ts = *engine.now.read();
+                    let hits = match parsed.mode {
+                        SearchMode::Similar => engine.search_similar(&engine.embedder.embed(&parsed.query), parsed.k, read_ts).unwrap(),
+                        SearchMode::Matching => engine.search_text(&parsed.query, parsed.k, read_ts),
+                        SearchMode::Hybrid(fusion) => HybridOp { engine: &engine, embedder: &*engine.embedder, fusion, filter: None }
+                            .topk(&parsed.query, parsed.k, read_ts).unwrap().into_iter().map(|h| (h.id, h.score)).collect(),
+                    };
                     return Json(SimilarResp { hits });
                 }
                 Json(SimilarResp { hits: vec![] })
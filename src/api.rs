@@ -1,13 +1,17 @@
-use axum::{routing::{post, get}, Router, Json, extract::{State, FromRef}, http::HeaderMap};
+use axum::{routing::{post, get}, Router, Json, middleware, extract::{Request, State}, http::{HeaderMap, StatusCode}, response::Response};
 use tower_http::cors::{CorsLayer, Any};
 use serde::{Serialize, Deserialize};
 use std::sync::Arc;
 use crate::storage::Engine;
-use crate::persona::Persona;
+use crate::storage::txn::TxnError;
+use crate::persona::{Persona, ORG_UNIT_FIELD};
 use crate::org::{OrgGraph, OrgUnit};
 use roaring::RoaringBitmap;
+use subtle::ConstantTimeEq;
 use uuid::Uuid;
 use crate::query::planner::Planner;
+use crate::query::SearchMode;
+use crate::query::operators::HybridHit;
 use crate::util::{save_json, load_json};
 
 #[derive(Clone)]
@@ -19,6 +23,10 @@ pub struct AppState {
     pub contracts: Arc<parking_lot::RwLock<Vec<DataContract>>>,
     pub taxonomy: Arc<parking_lot::RwLock<Vec<String>>>,
     pub policies: Arc<parking_lot::RwLock<Vec<Policy>>>,
+    // bearer token for the /admin routes; without one they refuse every request
+    pub admin_token: Option<String>,
+    // /admin/backup writes only below this directory; unset disables it
+    pub backup_root: Option<std::path::PathBuf>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -48,14 +56,67 @@ pub struct UploadReq { pub manifest: IngestionManifest, pub artifacts: Vec<Artif
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Artifact { pub id: String, pub text: String }
 
+// Server state that lives outside the engine, saved as app_state.json next
+// to an engine backup.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AppSnapshot {
+    pub company: Option<String>,
+    pub org_units: Vec<OrgUnit>,
+    pub contracts: Vec<DataContract>,
+    pub taxonomy: Vec<String>,
+    pub policies: Vec<Policy>,
+}
+
+const APP_STATE_FILE: &str = "app_state.json";
+
+impl AppState {
+    pub fn snapshot(&self) -> AppSnapshot {
+        AppSnapshot {
+            company: self.company.read().clone(),
+            org_units: self.org.units.read().values().cloned().collect(),
+            contracts: self.contracts.read().clone(),
+            taxonomy: self.taxonomy.read().clone(),
+            policies: self.policies.read().clone(),
+        }
+    }
+
+    // Engine backup plus app_state.json in `dir`.
+    pub fn backup_to(&self, dir: &std::path::Path) -> anyhow::Result<crate::storage::backup::BackupInfo> {
+        let info = self.engine.checkpoint_to(dir)?;
+        save_json(dir.join(APP_STATE_FILE), &self.snapshot())?;
+        Ok(info)
+    }
+
+    // Loads the app_state.json of the backup in `dir`, if it has one. The
+    // engine itself is restored with `Engine::restore_from` before opening.
+    pub fn restore_from(&self, dir: &std::path::Path) -> anyhow::Result<()> {
+        let path = dir.join(APP_STATE_FILE);
+        if !path.exists() { return Ok(()); }
+        let snap: AppSnapshot = load_json(path)?;
+        *self.company.write() = snap.company;
+        for unit in snap.org_units { self.org.upsert_unit(unit); }
+        self.org.rebuild_closure();
+        if let Some(path) = contracts_path(&self.engine) { save_json(path, &snap.contracts)?; }
+        *self.contracts.write() = snap.contracts;
+        *self.taxonomy.write() = snap.taxonomy;
+        *self.policies.write() = snap.policies;
+        Ok(())
+    }
+}
+
 pub fn router(state: AppState) -> Router {
     let cors = CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any);
+    let admin = Router::new()
+        .route("/admin/backup", post(backup))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin));
     Router::new()
         .route("/ingest", post(upload))
         .route("/contracts", post(register_contract))
         .route("/contracts", get(list_contracts))
         .route("/assume_role", post(assume_role))
         .route("/semanticql", post(semanticql))
+        .route("/ddl", post(ddl))
+        .route("/indexes", get(list_indexes))
         .route("/onboarding", post(onboard))
         .route("/org/units", get(list_units))
         .route("/org/units/upsert", post(upsert_unit))
@@ -63,31 +124,48 @@ pub fn router(state: AppState) -> Router {
         .route("/taxonomy/paths", post(add_taxonomy))
         .route("/policies", get(list_policies))
         .route("/policies", post(add_policy))
+        .merge(admin)
         .with_state(state)
         .layer(cors)
 }
 
 async fn upload(State(st): State<AppState>, Json(req): Json<UploadReq>) -> Json<serde_json::Value> {
     // TODO: validate against DataContract registry (omitted)
-    for a in req.artifacts.iter() {
-        let row = crate::types::Row { key: crate::types::RowKey(a.id.clone()), payload: serde_json::json!({"text": a.text}) };
-        st.engine.insert(1, row);
+    // rows carry their org unit, so persona scopes can filter on it
+    let unit = req.manifest.org_unit_hint.as_deref().and_then(|h| st.org.resolve(h));
+    let rows = req.artifacts.iter()
+        .map(|a| {
+            let mut payload = serde_json::json!({"text": a.text});
+            if let Some(u) = unit { payload[ORG_UNIT_FIELD] = u.into(); }
+            crate::types::Row { key: crate::types::RowKey(a.id.clone()), payload }
+        })
+        .collect();
+    if let Err(e) = st.engine.insert_batch(rows) {
+        return Json(serde_json::json!({"status": "error", "error": e.to_string()}));
     }
     Json(serde_json::json!({"status": "ok", "ingested": req.artifacts.len()}))
 }
 
+// Contracts live next to the engine's segments; in-memory engines keep them
+// in memory only.
+fn contracts_path(engine: &Engine) -> Option<std::path::PathBuf> {
+    engine.data_dir().map(|d| d.join("contracts.json"))
+}
+
 #[derive(Deserialize)]
 struct ContractReq { contract: DataContract }
 async fn register_contract(State(st): State<AppState>, Json(req): Json<ContractReq>) -> Json<serde_json::Value> {
     st.contracts.write().push(req.contract.clone());
-    let _ = save_json(std::path::PathBuf::from("data/contracts.json"), &*st.contracts.read());
+    if let Some(path) = contracts_path(&st.engine) {
+        let _ = save_json(path, &*st.contracts.read());
+    }
     Json(serde_json::json!({"status": "registered"}))
 }
 
 async fn list_contracts(State(st): State<AppState>) -> Json<Vec<DataContract>> {
     // lazy load from disk if empty
     if st.contracts.read().is_empty() {
-        if let Ok(v) = load_json::<Vec<DataContract>>(std::path::PathBuf::from("data/contracts.json")) {
+        if let Some(Ok(v)) = contracts_path(&st.engine).map(load_json::<Vec<DataContract>>) {
             *st.contracts.write() = v;
         }
     }
@@ -100,8 +178,12 @@ struct AssumeReq { person_id: String, roles: Vec<String>, scope_ids: Vec<u32> }
 struct AssumeResp { session_id: String }
 
 async fn assume_role(State(st): State<AppState>, Json(req): Json<AssumeReq>) -> Json<AssumeResp> {
+    // each unit brings the units below it
     let mut scope = RoaringBitmap::new();
-    for id in req.scope_ids { scope.insert(id); }
+    for id in req.scope_ids {
+        scope.insert(id);
+        scope |= st.org.scope_bitmap(id);
+    }
     let persona = Persona { person_id: req.person_id, assumed_roles: req.roles, org_scope: scope, raci_allowed: vec![crate::raci::RaciRole::R] };
     let sid = Uuid::new_v4().to_string();
     st.sessions.write().insert(sid.clone(), persona);
@@ -111,8 +193,24 @@ async fn assume_role(State(st): State<AppState>, Json(req): Json<AssumeReq>) ->
 #[derive(Deserialize)]
 struct SemanticQlReq { ql: String }
 #[derive(Serialize)]
-struct SemanticQlResp { hits: Vec<(u64, f32)>, masked: bool, aggregate_only: bool, total: usize }
-async fn semanticql(State(st): State<AppState>, headers: HeaderMap, Json(req): Json<SemanticQlReq>) -> Json<SemanticQlResp> {
+struct SemanticQlResp {
+    hits: Vec<(u64, f32)>,
+    // per-signal ranks and scores behind each hit of a HYBRID query
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    signals: Vec<HybridHit>,
+    masked: bool,
+    aggregate_only: bool,
+    total: usize,
+    read_ts: Option<u64>,
+}
+// A failed request: the status and `{"error": reason}`.
+type ApiError = (StatusCode, Json<serde_json::Value>);
+
+fn api_error(status: StatusCode, reason: impl std::fmt::Display) -> ApiError {
+    (status, Json(serde_json::json!({"error": reason.to_string()})))
+}
+
+async fn semanticql(State(st): State<AppState>, headers: HeaderMap, Json(req): Json<SemanticQlReq>) -> Result<Json<SemanticQlResp>, ApiError> {
     let mut masked = false;
     let mut aggregate_only = false;
     if let Some(parsed) = crate::query::SemanticQl::parse(&req.ql) {
@@ -120,23 +218,65 @@ async fn semanticql(State(st): State<AppState>, headers: HeaderMap, Json(req): J
         let persona = headers.get("X-Session-Id").and_then(|h| h.to_str().ok()).and_then(|sid| st.sessions.read().get(sid).cloned());
         // Planner with optional persona shaping
         let planner = if let Some(ref p) = persona { Planner::new(&*st.engine.embedder).with_persona(p) } else { Planner::new(&*st.engine.embedder) };
-        let mut hits = planner.similar_flat(&st.engine.flat_index.read(), &parsed.query, parsed.k);
+        // AS OF reads the versions (and embeddings) visible at that time
+        // the snapshot is pinned for the duration of the search so GC cannot prune under it
+        // an AS OF that cannot be served is an error, not an empty result
+        let snapshot = match parsed.as_of {
+            None => st.engine.begin(),
+            Some(as_of) => {
+                let ts = st.engine.resolve_as_of(as_of)
+                    .map_err(|e| api_error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+                    // only AS OF TXN resolves to nothing: the id never committed
+                    .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "AS OF TXN names a transaction that has not committed"))?;
+                st.engine.begin_as_of(ts).map_err(|e| match e {
+                    TxnError::SnapshotTooOld { .. } => api_error(StatusCode::GONE, e),
+                    e => api_error(StatusCode::INTERNAL_SERVER_ERROR, e),
+                })?
+            }
+        };
+        let read_ts = snapshot.read_ts();
+        let mut signals = Vec::new();
+        let mut hits = match parsed.mode {
+            SearchMode::Similar => planner.similar_at(&st.engine, &parsed.query, parsed.k, read_ts)
+                .map_err(|e| api_error(StatusCode::INTERNAL_SERVER_ERROR, e))?,
+            SearchMode::Matching => planner.matching_at(&st.engine, &parsed.query, parsed.k, read_ts)
+                .map_err(|e| api_error(StatusCode::INTERNAL_SERVER_ERROR, e))?,
+            SearchMode::Hybrid(fusion) => {
+                signals = planner.hybrid_at(&st.engine, &parsed.query, parsed.k, read_ts, fusion)
+                    .map_err(|e| api_error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+                signals.iter().map(|h| (h.id, h.score)).collect()
+            }
+        };
+        drop(snapshot);
 
         // Trivial policy enforcement demo using in-memory policies list
         // If any policy named "aggregate_only" present, enforce aggregate only
         for pol in st.policies.read().iter() {
             match pol.effect.as_str() {
                 "aggregate_only" => { aggregate_only = true; },
-                "deny" => { hits.clear(); },
+                "deny" => { hits.clear(); signals.clear(); },
                 "mask" => { masked = true; },
                 _ => {}
             }
         }
         let total = hits.len();
-        if aggregate_only { hits.clear(); }
-        return Json(SemanticQlResp { hits, masked, aggregate_only, total });
+        if aggregate_only { hits.clear(); signals.clear(); }
+        return Ok(Json(SemanticQlResp { hits, signals, masked, aggregate_only, total, read_ts: Some(read_ts) }));
     }
-    Json(SemanticQlResp { hits: vec![], masked: false, aggregate_only: false, total: 0 })
+    Ok(Json(SemanticQlResp { hits: vec![], signals: vec![], masked: false, aggregate_only: false, total: 0, read_ts: None }))
+}
+
+async fn ddl(State(st): State<AppState>, Json(req): Json<SemanticQlReq>) -> Json<serde_json::Value> {
+    let result = match crate::query::IndexDdl::parse(&req.ql) {
+        Some(crate::query::IndexDdl::Create { name, path }) => st.engine.create_index(&name, &path).map(|_| serde_json::json!({"status": "created"})),
+        Some(crate::query::IndexDdl::Drop { name }) => st.engine.drop_index(&name).map(|existed| serde_json::json!({"status": if existed { "dropped" } else { "not_found" }})),
+        None => return Json(serde_json::json!({"status": "error", "error": "unrecognised statement"})),
+    };
+    Json(result.unwrap_or_else(|e| serde_json::json!({"status": "error", "error": e.to_string()})))
+}
+
+async fn list_indexes(State(st): State<AppState>) -> Json<Vec<crate::storage::manifest::FieldIndexDef>> {
+    Json(st.engine.field_indexes())
 }
 
 #[derive(Deserialize)]
@@ -180,3 +320,36 @@ async fn add_policy(State(st): State<AppState>, Json(req): Json<AddPolicyReq>) -
     st.policies.write().push(Policy { name: req.name, effect: req.effect, priority: req.priority });
     Json(serde_json::json!({"status":"ok"}))
 }
+
+// Lets a request through to the /admin routes only with
+// `Authorization: Bearer <admin_token>`.
+async fn require_admin(State(st): State<AppState>, headers: HeaderMap, req: Request, next: middleware::Next) -> Result<Response, ApiError> {
+    let Some(token) = &st.admin_token else {
+        return Err(api_error(StatusCode::FORBIDDEN, "admin routes are disabled: no admin token is configured"));
+    };
+    let given = headers.get("Authorization").and_then(|h| h.to_str().ok()).and_then(|h| h.strip_prefix("Bearer "));
+    // constant time, so response timing does not give the token away byte by byte
+    if !given.is_some_and(|g| bool::from(g.as_bytes().ct_eq(token.as_bytes()))) {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or wrong admin token"));
+    }
+    Ok(next.run(req).await)
+}
+
+#[derive(Deserialize)]
+struct BackupReq { dir: String }
+// `dir` is relative to the backup root and may not climb out of it.
+async fn backup(State(st): State<AppState>, Json(req): Json<BackupReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    let Some(root) = st.backup_root.clone() else {
+        return Err(api_error(StatusCode::FORBIDDEN, "backups are disabled: no backup root is configured"));
+    };
+    let rel = std::path::Path::new(&req.dir);
+    if rel.as_os_str().is_empty() || !rel.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+        return Err(api_error(StatusCode::BAD_REQUEST, format!("backup dir {:?} must be a relative path without \"..\"", req.dir)));
+    }
+    let dir = root.join(rel);
+    // the copy is blocking file I/O; keep it off the async workers
+    let info = tokio::task::spawn_blocking(move || st.backup_to(&dir)).await
+        .map_err(|e| api_error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .map_err(|e| api_error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(serde_json::json!({"status": "ok", "as_of": info.as_of, "versions": info.versions, "files": info.files.len()})))
+}
@@ -1,5 +1,7 @@
 
 use serde::{Serialize, Deserialize};
+use crate::storage::compression::Codec;
+use crate::vector::quant::{Quantization, DEFAULT_RERANK};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ModelEndpointConfig {
@@ -18,14 +20,59 @@ pub struct ModelEndpointConfig {
 }
 
 fn default_timeout_ms() -> u64 { 30_000 }
+fn default_wal_segment_size_mb() -> usize { 64 }
+fn default_memtable_flush_secs() -> u64 { 300 }
+fn default_train_after() -> usize { 100_000 }
+fn default_rerank() -> usize { DEFAULT_RERANK }
+
+// In-memory codes for the engine's vector index, e.g.
+//   {"kind": "scalar"}
+//   {"kind": {"product": {"subspaces": 48}}, "train_after": 50000, "rerank": 8}
+// The quantizer is trained once the index holds `train_after` vectors; full
+// vectors then live in data_dir/vectors and only re-rank the best
+// `rerank * k` candidates of a search.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VectorQuantization {
+    pub kind: Quantization,
+    #[serde(default = "default_train_after")]
+    pub train_after: usize,
+    #[serde(default = "default_rerank")]
+    pub rerank: usize,
+}
+
+// When a WAL append is considered durable.
+//   "always"            fsync before the write returns (concurrent writers share one fsync)
+//   {"every_n_ms": 10}  write through to the OS, fsync from a background thread
+//   "os"                write through to the OS, never fsync explicitly
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    #[default]
+    Always,
+    EveryNMs(u64),
+    Os,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
     pub data_dir: String,
     pub wal_dir: String,
+    // the memtable is flushed to a row segment once it reaches this size...
     pub segment_size_mb: usize,
+    // ...or once it has been open this long
+    #[serde(default = "default_memtable_flush_secs")]
+    pub memtable_flush_secs: u64,
     pub vector_dims: usize,
     #[serde(default)]
+    pub durability: Durability,
+    #[serde(default = "default_wal_segment_size_mb")]
+    pub wal_segment_size_mb: usize,
+    // block compression for row and column segments: "none", "lz4", "deflate"
+    #[serde(default)]
+    pub segment_codec: Codec,
+    #[serde(default)]
+    pub vector_quantization: Option<VectorQuantization>,
+    #[serde(default)]
     pub embedding: Option<ModelEndpointConfig>,
     #[serde(default)]
     pub reasoning: Option<ModelEndpointConfig>,
@@ -37,7 +84,12 @@ impl Default for Config {
             data_dir: "data".to_string(),
             wal_dir: "wal".to_string(),
             segment_size_mb: 128,
+            memtable_flush_secs: default_memtable_flush_secs(),
             vector_dims: 384,
+            durability: Durability::Always,
+            wal_segment_size_mb: default_wal_segment_size_mb(),
+            segment_codec: Codec::default(),
+            vector_quantization: None,
             embedding: Some(ModelEndpointConfig {
                 base_url: "http://localhost:8080".to_string(),
                 path: "/embed".to_string(),
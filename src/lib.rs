@@ -6,6 +6,7 @@ pub mod storage;
 pub mod semantic;
 pub mod vector;
 pub mod query;
+pub mod text;
 pub mod catalog;
 pub mod org;
 pub mod raci;
@@ -2,5 +2,10 @@
 use crate::types::{VersionedRow, Timestamp};
 
 pub fn visible_at(v: &VersionedRow, read_ts: Timestamp) -> bool {
-    v.begin_ts <= read_ts && v.end_ts.map(|e| read_ts < e).unwrap_or(true)
+    covers(v.begin_ts, v.end_ts, read_ts)
+}
+
+// Whether the validity interval [begin_ts, end_ts) contains `read_ts`.
+pub fn covers(begin_ts: Timestamp, end_ts: Option<Timestamp>, read_ts: Timestamp) -> bool {
+    begin_ts <= read_ts && end_ts.map(|e| read_ts < e).unwrap_or(true)
 }
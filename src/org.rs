@@ -55,4 +55,13 @@ impl OrgGraph {
     pub fn scope_bitmap(&self, unit_id: u32) -> RoaringBitmap {
         self.scopes.read().get(&unit_id).cloned().unwrap_or_default()
     }
+
+    // The unit an ingestion hint names, by id or by name.
+    pub fn resolve(&self, hint: &str) -> Option<u32> {
+        let units = self.units.read();
+        match hint.trim().parse::<u32>() {
+            Ok(id) => units.contains_key(&id).then_some(id),
+            Err(_) => units.values().find(|u| u.name == hint.trim()).map(|u| u.id),
+        }
+    }
 }
@@ -1,7 +1,21 @@
-use crate::raci::{RaciMask, RaciRole};
+use crate::raci::RaciRole;
+use crate::storage::Engine;
 use roaring::RoaringBitmap;
 use serde::{Serialize, Deserialize};
 
+// Payload field holding the id of the org unit a row belongs to.
+pub const ORG_UNIT_FIELD: &str = "org_unit";
+
+// Secondary index that scoped searches read the scope's rows through.
+pub const ORG_UNIT_INDEX: &str = "by_org_unit";
+
+// Creates `ORG_UNIT_INDEX` unless some index already covers the org unit.
+pub fn ensure_org_unit_index(engine: &Engine) -> anyhow::Result<()> {
+    let path = format!("$.{}", ORG_UNIT_FIELD);
+    if engine.field_indexes().iter().any(|d| d.path == path) { return Ok(()); }
+    engine.create_index(ORG_UNIT_INDEX, &path)
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Persona {
     pub person_id: String,
@@ -12,4 +26,17 @@ pub struct Persona {
 
 impl Persona {
     pub fn allows_role(&self, r: RaciRole) -> bool { self.raci_allowed.contains(&r) }
+
+    // An empty org scope leaves the persona unscoped.
+    pub fn is_scoped(&self) -> bool { !self.org_scope.is_empty() }
+
+    // Whether a row with this payload is inside the org scope. A row whose
+    // org unit is missing, or is not a u32 unit id (e.g. the unit's name as
+    // a string), belongs to no unit and so is outside every scope; only
+    // unscoped personas see it.
+    pub fn in_scope(&self, payload: &serde_json::Value) -> bool {
+        if !self.is_scoped() { return true; }
+        payload.get(ORG_UNIT_FIELD).and_then(|u| u.as_u64()).and_then(|u| u32::try_from(u).ok())
+            .is_some_and(|u| self.org_scope.contains(u))
+    }
 }
@@ -3,24 +3,89 @@ pub mod operators;
 pub mod planner;
 
 use regex::Regex;
+use crate::types::AsOf;
+use operators::Fusion;
 
-// A minimal SemanticQL parser for patterns like:
-// FIND SIMILAR "<query>" IN <space>
+// largest TOP a query may ask for; searches overfetch a multiple of it
+pub const MAX_TOP: usize = 10_000;
+
+// SIMILAR ranks by embedding similarity, MATCHING by BM25 over payload.text,
+// HYBRID by both, fused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchMode {
+    Similar,
+    Matching,
+    Hybrid(Fusion),
+}
+
+// A minimal SemanticQL parser for patterns like the following, with the
+// optional clauses in this order:
+// FIND SIMILAR "<query>" IN <space> [AS OF <ts> | AS OF TXN <id>] [TOP <k>]
+// FIND MATCHING "<terms>" IN <space> [AS OF ...] [TOP <k>]
+// FIND HYBRID "<query>" IN <space> [FUSION RRF [<k>] | FUSION LINEAR <lexical> <vector>] [AS OF ...] [TOP <k>]
+// TOP above MAX_TOP fails the parse.
 #[derive(Debug, Clone)]
 pub struct SemanticQl {
+    pub mode: SearchMode,
     pub query: String,
     pub space: String,
     pub k: usize,
+    pub as_of: Option<AsOf>,
 }
 
 impl SemanticQl {
     pub fn parse(input: &str) -> Option<Self> {
         // Raw string with escaped quotes around the query capture
-        let re = Regex::new(r#"\s*FIND\s+SIMILAR\s+\"(.+?)\"\s+IN\s+([a-zA-Z0-9_]+)(?:\s+TOP\s+(\d+))?\s*"#).ok()?;
+        // anchored, so a clause out of order or anything trailing fails the
+        // parse instead of being ignored
+        let re = Regex::new(r#"^\s*FIND\s+(SIMILAR|MATCHING|HYBRID)\s+\"(.+?)\"\s+IN\s+([a-zA-Z0-9_]+)(?:\s+(FUSION)\s+(?:RRF(?:\s+(\d+(?:\.\d+)?))?|(LINEAR)\s+(\d+(?:\.\d+)?)\s+(\d+(?:\.\d+)?)))?(?:\s+AS\s+OF\s+(TXN\s+)?(\d+))?(?:\s+TOP\s+(\d+))?\s*;?\s*$"#).ok()?;
         let caps = re.captures(input.trim())?;
-        let query = caps.get(1)?.as_str().to_string();
-        let space = caps.get(2)?.as_str().to_string();
-        let k = caps.get(3).map(|m| m.as_str().parse::<usize>().unwrap_or(10)).unwrap_or(10);
-        Some(Self { query, space, k })
+        let num = |i: usize| caps.get(i).and_then(|m| m.as_str().parse::<f32>().ok());
+        let fusion = match (caps.get(4), num(5), caps.get(6), num(7), num(8)) {
+            (None, ..) => None,
+            (_, _, Some(_), Some(lexical), Some(vector)) => Some(Fusion::Linear { lexical, vector }),
+            (_, Some(k), ..) => Some(Fusion::Rrf { k }),
+            _ => Some(Fusion::default()),
+        };
+        let mode = match &caps[1] {
+            "HYBRID" => SearchMode::Hybrid(fusion.unwrap_or_default()),
+            // FUSION only applies to HYBRID
+            _ if fusion.is_some() => return None,
+            "MATCHING" => SearchMode::Matching,
+            _ => SearchMode::Similar,
+        };
+        let query = caps.get(2)?.as_str().to_string();
+        let space = caps.get(3)?.as_str().to_string();
+        let as_of = match (caps.get(9), caps.get(10)) {
+            (Some(_), Some(id)) => Some(AsOf::Txn(id.as_str().parse().ok()?)),
+            (None, Some(ts)) => Some(AsOf::Ts(ts.as_str().parse().ok()?)),
+            _ => None,
+        };
+        let k = match caps.get(11) {
+            Some(m) => m.as_str().parse::<usize>().ok().filter(|k| *k <= MAX_TOP)?,
+            None => 10,
+        };
+        Some(Self { mode, query, space, k, as_of })
+    }
+}
+
+// Secondary index DDL:
+// CREATE INDEX <name> ON $.<path>
+// DROP INDEX <name>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexDdl {
+    Create { name: String, path: String },
+    Drop { name: String },
+}
+
+impl IndexDdl {
+    pub fn parse(input: &str) -> Option<Self> {
+        let create = Regex::new(r#"(?i)^\s*CREATE\s+INDEX\s+([a-zA-Z0-9_]+)\s+ON\s+(\$\.[a-zA-Z0-9_.\-]+)\s*;?\s*$"#).ok()?;
+        if let Some(caps) = create.captures(input) {
+            return Some(IndexDdl::Create { name: caps.get(1)?.as_str().to_string(), path: caps.get(2)?.as_str().to_string() });
+        }
+        let drop = Regex::new(r#"(?i)^\s*DROP\s+INDEX\s+([a-zA-Z0-9_]+)\s*;?\s*$"#).ok()?;
+        let caps = drop.captures(input)?;
+        Some(IndexDdl::Drop { name: caps.get(1)?.as_str().to_string() })
     }
 }
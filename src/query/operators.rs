@@ -1,8 +1,13 @@
 
+use std::collections::HashMap;
+use serde::Serialize;
+use roaring::RoaringTreemap;
 use crate::vector::flat::FlatIndex;
 use crate::vector::hnsw::HnswIndex;
 use crate::semantic::pipeline::Embedder;
-use crate::types::Vector;
+use crate::types::{RowKey, Timestamp, Vector, VersionedRow};
+use crate::storage::{key_id, Engine};
+use crate::storage::predicate::Predicate;
 
 pub struct SimilarityOp<'a> {
     pub index: &'a FlatIndex,
@@ -10,10 +15,15 @@ pub struct SimilarityOp<'a> {
 }
 
 impl<'a> SimilarityOp<'a> {
-    pub fn topk(&self, text: &str, k: usize) -> Vec<(u64, f32)> {
+    pub fn topk(&self, text: &str, k: usize) -> anyhow::Result<Vec<(u64, f32)>> {
         let v: Vector = self.embedder.embed(text);
         self.index.cosine_topk(&v, k)
     }
+
+    // Top-k among the ids in `allowed`.
+    pub fn topk_in(&self, text: &str, k: usize, allowed: &RoaringTreemap) -> anyhow::Result<Vec<(u64, f32)>> {
+        self.index.cosine_topk_in(&self.embedder.embed(text), k, allowed)
+    }
 }
 
 pub struct SimilarityOpHnsw<'a> {
@@ -22,8 +32,174 @@ pub struct SimilarityOpHnsw<'a> {
 }
 
 impl<'a> SimilarityOpHnsw<'a> {
-    pub fn topk(&self, text: &str, k: usize) -> Vec<(u64, f32)> {
+    pub fn topk(&self, text: &str, k: usize) -> anyhow::Result<Vec<(u64, f32)>> {
         let v: Vector = self.embedder.embed(text);
         self.index.topk(&v, k)
     }
+
+    // Top-k among the ids in `allowed`, checked during the graph walk.
+    pub fn topk_in(&self, text: &str, k: usize, allowed: &RoaringTreemap) -> anyhow::Result<Vec<(u64, f32)>> {
+        self.index.topk_in(&self.embedder.embed(text), k, allowed)
+    }
+}
+
+// Rows a search may return. `ids`, when secondary indexes can supply it, is
+// a superset of the allowed rows' key ids that the search checks as it
+// goes. Key ids are hashes two keys can share and index postings outlive
+// the values they were made for, so each hit's visible version is then
+// checked with `accept`. Without `ids` the search overfetches and filters
+// its hits afterwards.
+pub struct RowFilter<'a> {
+    pub ids: Option<RoaringTreemap>,
+    pub accept: Box<dyn Fn(&VersionedRow) -> bool + 'a>,
+}
+
+// hits fetched per hit wanted without `ids`, and at most while widening
+const FILTER_OVERFETCH: usize = 4;
+const FILTER_MAX_OVERFETCH: usize = 64;
+
+impl<'a> RowFilter<'a> {
+    // The best k hits of `search(n, ids)` that pass `accept`, as key ids.
+    // While hits are filtered out the search is widened, until it runs out
+    // of rows or reaches FILTER_MAX_OVERFETCH * k; a filter few rows pass can
+    // come up short of k without `ids`.
+    pub fn topk(&self, engine: &Engine, k: usize, read_ts: Timestamp,
+                search: impl Fn(usize, Option<&RoaringTreemap>) -> anyhow::Result<Vec<(RowKey, f32)>>) -> anyhow::Result<Vec<(u64, f32)>> {
+        let max = k.saturating_mul(FILTER_MAX_OVERFETCH);
+        let mut n = if self.ids.is_some() { k } else { k.saturating_mul(FILTER_OVERFETCH) };
+        loop {
+            let hits = search(n, self.ids.as_ref())?;
+            let fetched = hits.len();
+            let mut kept = Vec::new();
+            for (key, score) in hits {
+                if kept.len() == k { break; }
+                if engine.get_visible(&key, read_ts)?.is_some_and(|v| (self.accept)(&v)) { kept.push((key_id(&key), score)); }
+            }
+            if kept.len() == k || fetched < n || n >= max { return Ok(kept); }
+            n = n.saturating_mul(2).min(max);
+        }
+    }
+}
+
+// BM25 ranking over the engine's full-text index.
+pub struct TextMatchOp<'a> {
+    pub engine: &'a Engine,
+    // None allows every row
+    pub filter: Option<&'a RowFilter<'a>>,
+}
+
+impl<'a> TextMatchOp<'a> {
+    pub fn topk(&self, terms: &str, k: usize, read_ts: Timestamp) -> anyhow::Result<Vec<(u64, f32)>> {
+        match self.filter {
+            Some(f) => f.topk(self.engine, k, read_ts, |n, ids| Ok(self.engine.search_text_keys(terms, n, read_ts, ids))),
+            None => Ok(self.engine.search_text(terms, k, read_ts)),
+        }
+    }
+}
+
+// How HybridOp combines the lexical (BM25) and vector (cosine) rankings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fusion {
+    // sum of 1 / (k + rank) over the signals that returned the row
+    Rrf { k: f32 },
+    // weighted sum of each signal's score, min-max scaled to [0, 1] over
+    // its candidates; a signal that missed the row contributes 0
+    Linear { lexical: f32, vector: f32 },
+}
+
+impl Default for Fusion {
+    fn default() -> Self { Fusion::Rrf { k: 60.0 } }
+}
+
+// One signal's opinion of a hit; rank is 1-based.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct SignalScore {
+    pub rank: usize,
+    pub score: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HybridHit {
+    pub id: u64,
+    pub score: f32,
+    pub lexical: Option<SignalScore>,
+    pub vector: Option<SignalScore>,
+}
+
+// Keyword plus embedding retrieval over the engine as of a timestamp. Each
+// signal overfetches `CANDIDATES_PER_HIT * k` rows before fusion.
+pub struct HybridOp<'a> {
+    pub engine: &'a Engine,
+    pub embedder: &'a dyn Embedder,
+    pub fusion: Fusion,
+    // applied to both signals; None allows every row
+    pub filter: Option<&'a RowFilter<'a>>,
+}
+
+const CANDIDATES_PER_HIT: usize = 4;
+
+impl<'a> HybridOp<'a> {
+    pub fn topk(&self, text: &str, k: usize, read_ts: Timestamp) -> anyhow::Result<Vec<HybridHit>> {
+        let n = k.saturating_mul(CANDIDATES_PER_HIT);
+        let q = self.embedder.embed(text);
+        let lexical = TextMatchOp { engine: self.engine, filter: self.filter }.topk(text, n, read_ts)?;
+        let vector = match self.filter {
+            Some(f) => f.topk(self.engine, n, read_ts, |m, ids| self.engine.search_similar_keys(&q, m, read_ts, ids))?,
+            None => self.engine.search_similar(&q, n, read_ts)?,
+        };
+        let mut hits: HashMap<u64, HybridHit> = HashMap::new();
+        for (i, (id, score)) in lexical.iter().enumerate() {
+            hits.entry(*id).or_insert_with(|| HybridHit { id: *id, score: 0.0, lexical: None, vector: None })
+                .lexical = Some(SignalScore { rank: i + 1, score: *score });
+        }
+        for (i, (id, score)) in vector.iter().enumerate() {
+            hits.entry(*id).or_insert_with(|| HybridHit { id: *id, score: 0.0, lexical: None, vector: None })
+                .vector = Some(SignalScore { rank: i + 1, score: *score });
+        }
+        let scale = |list: &[(u64, f32)]| {
+            let (lo, hi) = list.iter().fold((f32::MAX, f32::MIN), |(lo, hi), (_, s)| (lo.min(*s), hi.max(*s)));
+            move |s: f32| if hi > lo { (s - lo) / (hi - lo) } else { 1.0 }
+        };
+        let (lex_scale, vec_scale) = (scale(&lexical), scale(&vector));
+        for hit in hits.values_mut() {
+            hit.score = match self.fusion {
+                Fusion::Rrf { k } => [hit.lexical, hit.vector].iter().flatten().map(|s| 1.0 / (k + s.rank as f32)).sum(),
+                Fusion::Linear { lexical, vector } => {
+                    lexical * hit.lexical.map_or(0.0, |s| lex_scale(s.score)) + vector * hit.vector.map_or(0.0, |s| vec_scale(s.score))
+                }
+            };
+        }
+        let mut hits: Vec<HybridHit> = hits.into_values().collect();
+        hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal).then(a.id.cmp(&b.id)));
+        hits.truncate(k);
+        Ok(hits)
+    }
+}
+
+// Filtered scan that only materialises the projected payload fields.
+pub struct ScanOp<'a> {
+    pub engine: &'a Engine,
+    pub predicate: &'a Predicate,
+    // "payload.<field>" names; None keeps the whole payload
+    pub projection: Option<&'a [String]>,
+}
+
+impl<'a> ScanOp<'a> {
+    pub fn rows(&self, read_ts: Timestamp) -> anyhow::Result<Vec<VersionedRow>> {
+        self.engine.scan_projected(read_ts, self.predicate, self.projection)
+    }
+}
+
+// Point lookups through the secondary indexes that `predicate` constrains;
+// each candidate's visible version is re-checked.
+pub struct IndexScanOp<'a> {
+    pub engine: &'a Engine,
+    pub predicate: &'a Predicate,
+    pub projection: Option<&'a [String]>,
+}
+
+impl<'a> IndexScanOp<'a> {
+    pub fn rows(&self, read_ts: Timestamp) -> anyhow::Result<Vec<VersionedRow>> {
+        self.engine.scan_indexed(read_ts, self.predicate, self.projection)
+    }
 }
@@ -1,10 +1,15 @@
 
-use crate::query::operators::{SimilarityOp, SimilarityOpHnsw};
+use crate::query::operators::{Fusion, HybridHit, HybridOp, IndexScanOp, RowFilter, ScanOp, SimilarityOp, SimilarityOpHnsw, TextMatchOp};
 use crate::vector::flat::FlatIndex;
 use crate::vector::hnsw::HnswIndex;
 use crate::semantic::pipeline::Embedder;
-use crate::persona::Persona;
+use crate::persona::{Persona, ORG_UNIT_FIELD};
 use crate::raci::RaciRole;
+use crate::storage::Engine;
+use crate::storage::columnsegment::Scalar;
+use crate::storage::predicate::Predicate;
+use crate::types::{Timestamp, VersionedRow};
+use roaring::RoaringTreemap;
 
 // Extremely simplified planner API for demo/testing
 pub struct Planner<'a> {
@@ -16,28 +21,111 @@ impl<'a> Planner<'a> {
     pub fn new(embedder: &'a dyn Embedder) -> Self { Self { embedder, persona: None } }
     pub fn with_persona(mut self, p: &'a Persona) -> Self { self.persona = Some(p); self }
 
-    pub fn similar_flat(&self, index: &'a FlatIndex, text: &str, k: usize) -> Vec<(u64, f32)> {
+    pub fn similar_flat(&self, index: &'a FlatIndex, text: &str, k: usize) -> anyhow::Result<Vec<(u64, f32)>> {
         let op = SimilarityOp { index, embedder: self.embedder };
-        let mut hits = op.topk(text, k * 2); // overfetch
+        let mut hits = op.topk(text, k)?;
         // persona shaping demo: if persona lacks R/A, drop results
         if let Some(p) = self.persona {
             if !(p.allows_role(RaciRole::R) || p.allows_role(RaciRole::A)) {
                 hits.clear();
             }
         }
-        hits.truncate(k);
-        hits
+        Ok(hits)
     }
 
-    pub fn similar_hnsw(&self, index: &'a HnswIndex, text: &str, k: usize) -> Vec<(u64, f32)> {
+    // Similarity over the engine's versioned vectors as of `read_ts`; only
+    // embeddings whose row version was visible then can match. A scoped
+    // persona only sees rows inside its org scope.
+    // Fails if the rows the hits are checked against cannot be read.
+    pub fn similar_at(&self, engine: &Engine, text: &str, k: usize, read_ts: Timestamp) -> anyhow::Result<Vec<(u64, f32)>> {
+        self.similar_where(engine, text, k, read_ts, None)
+    }
+
+    // Like `similar_at`, among the rows matching `predicate`. See `filter`
+    // for how the predicate and the persona's org scope are applied.
+    pub fn similar_where(&self, engine: &Engine, text: &str, k: usize, read_ts: Timestamp, predicate: Option<&Predicate>) -> anyhow::Result<Vec<(u64, f32)>> {
+        if !self.may_read() { return Ok(Vec::new()); }
+        let q = self.embedder.embed(text);
+        match self.filter(engine, predicate) {
+            Some(f) => f.topk(engine, k, read_ts, |n, ids| engine.search_similar_keys(&q, n, read_ts, ids)),
+            None => engine.search_similar(&q, k, read_ts),
+        }
+    }
+
+    // Full-text BM25 search over payload.text as of `read_ts`, within the
+    // persona's org scope.
+    pub fn matching_at(&self, engine: &Engine, terms: &str, k: usize, read_ts: Timestamp) -> anyhow::Result<Vec<(u64, f32)>> {
+        if !self.may_read() { return Ok(Vec::new()); }
+        let filter = self.filter(engine, None);
+        TextMatchOp { engine, filter: filter.as_ref() }.topk(terms, k, read_ts)
+    }
+
+    // BM25 and embedding similarity fused into one ranking as of `read_ts`;
+    // each hit carries both signals' rank and score. Both signals keep to
+    // the persona's org scope.
+    pub fn hybrid_at(&self, engine: &Engine, text: &str, k: usize, read_ts: Timestamp, fusion: Fusion) -> anyhow::Result<Vec<HybridHit>> {
+        if !self.may_read() { return Ok(Vec::new()); }
+        let filter = self.filter(engine, None);
+        HybridOp { engine, embedder: self.embedder, fusion, filter: filter.as_ref() }.topk(text, k, read_ts)
+    }
+
+    // persona shaping demo: without R or A nothing is returned
+    fn may_read(&self) -> bool {
+        self.persona.is_none_or(|p| p.allows_role(RaciRole::R) || p.allows_role(RaciRole::A))
+    }
+
+    // The rows matching `predicate` inside the persona's org scope, or None
+    // when neither restricts anything. The indexed restrictions become a
+    // bitmap from the index postings, without reading rows: the scope
+    // through an index on the org unit (see `persona::ensure_org_unit_index`),
+    // the predicate through any index on its paths. With neither indexed,
+    // hits are filtered after the search.
+    fn filter(&self, engine: &Engine, predicate: Option<&Predicate>) -> Option<RowFilter<'a>> {
+        let scope = self.persona.filter(|p| p.is_scoped());
+        if predicate.is_none() && scope.is_none() { return None; }
+        let unit_is = |u: u32| Predicate::Eq(format!("payload.{}", ORG_UNIT_FIELD), Scalar::I64(u as i64));
+        let scope_ids = scope.and_then(|p| p.org_scope.iter().map(|u| engine.index_candidate_ids(&unit_is(u)))
+            .collect::<Option<Vec<_>>>())
+            .map(|units| units.into_iter().fold(RoaringTreemap::new(), |a, b| a | b));
+        let predicate_ids = predicate.and_then(|p| engine.index_candidate_ids(p));
+        let ids = [scope_ids, predicate_ids].into_iter().flatten().reduce(|a, b| a & b);
+        let predicate = predicate.cloned();
+        Some(RowFilter {
+            ids,
+            accept: Box::new(move |v| predicate.as_ref().is_none_or(|p| p.matches(v)) && scope.is_none_or(|p| p.in_scope(&v.row.payload))),
+        })
+    }
+
+    pub fn similar_hnsw(&self, index: &'a HnswIndex, text: &str, k: usize) -> anyhow::Result<Vec<(u64, f32)>> {
         let op = SimilarityOpHnsw { index, embedder: self.embedder };
-        let mut hits = op.topk(text, k * 2);
+        let mut hits = op.topk(text, k)?;
         if let Some(p) = self.persona {
             if !(p.allows_role(RaciRole::R) || p.allows_role(RaciRole::A)) {
                 hits.clear();
             }
         }
-        hits.truncate(k);
-        hits
+        Ok(hits)
+    }
+
+    // Rows matching `predicate` as of `read_ts`, with payloads cut down to
+    // `projection`. Equality and range conditions on an indexed path go
+    // through the secondary index; otherwise column segments only read the
+    // columns involved.
+    pub fn scan(&self, engine: &Engine, read_ts: Timestamp, predicate: &Predicate, projection: Option<&[String]>) -> anyhow::Result<Vec<VersionedRow>> {
+        let mut rows = self.rows(engine, read_ts, predicate, projection)?;
+        if let Some(p) = self.persona {
+            if !(p.allows_role(RaciRole::R) || p.allows_role(RaciRole::A)) {
+                rows.clear();
+            }
+        }
+        Ok(rows)
+    }
+
+    fn rows(&self, engine: &Engine, read_ts: Timestamp, predicate: &Predicate, projection: Option<&[String]>) -> anyhow::Result<Vec<VersionedRow>> {
+        if engine.has_index_for(predicate) {
+            IndexScanOp { engine, predicate, projection }.rows(read_ts)
+        } else {
+            ScanOp { engine, predicate, projection }.rows(read_ts)
+        }
     }
 }
@@ -1,5 +1,4 @@
 use serde::{Serialize, Deserialize};
-use roaring::RoaringBitmap;
 use std::collections::{HashMap, HashSet};
 use parking_lot::RwLock;
 
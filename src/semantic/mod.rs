@@ -49,7 +49,7 @@ impl Olsp for HeuristicOlsp {
                 out.entities.push(EntityLink { surface: token.to_string(), entity_id: token.to_lowercase(), score: 0.5 });
             }
         }
-        out.summary = Some(Summary { text: format!("{}", text.chars().take(120).collect::<String>()) });
+        out.summary = Some(Summary { text: text.chars().take(120).collect::<String>() });
         out
     }
 }
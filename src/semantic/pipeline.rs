@@ -79,7 +79,7 @@ impl Embedder for HttpEmbedder {
         for (k,v) in &self.cfg.headers { req = req.header(k, v); }
         let resp = req.send();
         match resp.and_then(|r| r.error_for_status())
-                  .and_then(|mut r| r.json::<serde_json::Value>()) {
+                  .and_then(|r| r.json::<serde_json::Value>()) {
             Ok(val) => {
                 if let Some(arr) = val.get("embedding").and_then(|e| e.as_array()) {
                     let mut v = Vec::with_capacity(self.dims_);
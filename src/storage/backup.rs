@@ -0,0 +1,219 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{bail, Context, Result};
+use serde::{Serialize, Deserialize};
+use crate::config::Config;
+use crate::types::Timestamp;
+use crate::util::{load_json, save_json};
+use super::Engine;
+use super::columnsegment::ColumnSegment;
+use super::layers::SegmentReader;
+use super::memtable::MemTable;
+use super::manifest::{Manifest, ManifestLog, SegmentKind, SegmentMeta, VersionEdit};
+use super::rowsegment::RowSegmentEntry;
+
+// Layout version of BACKUP.json and the directory next to it.
+pub const BACKUP_FORMAT: u32 = 1;
+
+const INFO_FILE: &str = "BACKUP.json";
+// a complete data_dir, ready to be copied into place
+const DATA_DIR: &str = "data";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupFile {
+    // relative to the backup's data/ directory
+    pub path: String,
+    pub bytes: u64,
+    pub adler32: u32,
+}
+
+// BACKUP.json: what a backup holds and how to check it. Written last, so a
+// directory without it is an unfinished backup.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupInfo {
+    pub format_version: u32,
+    // on-disk format of the data/ directory (manifest::FORMAT_VERSION)
+    pub data_format: u32,
+    // every commit up to and including this ts, and nothing after it
+    pub as_of: Timestamp,
+    pub created_unix_ms: u64,
+    pub schema_version: u64,
+    // row versions kept, history included
+    pub versions: u64,
+    // index snapshots taken after `as_of`, not copied
+    #[serde(default)]
+    pub skipped_indexes: Vec<String>,
+    pub files: Vec<BackupFile>,
+}
+
+impl Engine {
+    // Writes a consistent copy of the database as of the latest commit to
+    // `dir`, which must be missing or empty, without blocking writers. Each
+    // segment and memtable becomes one column segment of the versions up to
+    // that ts, with their embeddings, so only one layer is in memory at a
+    // time. Index snapshots taken at or before it are copied and secondary
+    // index definitions carried over. Restore with `Engine::restore_from`.
+    pub fn checkpoint_to(&self, dir: impl AsRef<Path>) -> Result<BackupInfo> {
+        let dir = dir.as_ref();
+        if std::fs::read_dir(dir).is_ok_and(|mut d| d.next().is_some()) {
+            bail!("backup directory {} is not empty", dir.display());
+        }
+        let data = dir.join(DATA_DIR);
+        std::fs::create_dir_all(&data)?;
+        // the open snapshot keeps GC away from versions visible at `as_of`
+        let snapshot = self.begin();
+        let as_of = snapshot.read_ts();
+        let view = self.view();
+
+        // copied under the manifest lock, so neither a newer snapshot nor
+        // compaction removes them mid-copy. A snapshot taken after `as_of`
+        // may hold vectors the backup does not, so it is left out.
+        let mut skipped_indexes = Vec::new();
+        let source = match &self.store {
+            Some(store) => {
+                let manifest = store.manifest.lock();
+                let mut source = manifest.state().clone();
+                source.indexes.retain(|index| {
+                    if index.as_of > as_of { skipped_indexes.push(index.name.clone()); }
+                    index.as_of <= as_of
+                });
+                for file in source.indexes.iter().flat_map(|i| i.all_files()) {
+                    let to = data.join(file);
+                    if let Some(parent) = to.parent() { std::fs::create_dir_all(parent)?; }
+                    std::fs::copy(store.dir.join(file), to)?;
+                }
+                source
+            }
+            None => Manifest { field_indexes: self.field_indexes(), ..Manifest::default() },
+        };
+
+        let codec = self.store.as_ref().map(|s| s.codec).unwrap_or_default();
+        let mut next_id = source.next_segment;
+        let mut segments = Vec::new();
+        let mut versions = 0;
+        // one output segment per layer, oldest first, as the layers stack
+        let mut write_layer = |mut entries: Vec<RowSegmentEntry>| -> Result<()> {
+            entries.retain(|e| e.version.begin_ts <= as_of);
+            if entries.is_empty() { return Ok(()); }
+            // a successor that came after `as_of` has not ended the version yet
+            for e in &mut entries {
+                if e.version.end_ts.is_some_and(|end| end > as_of) { e.version.end_ts = None; }
+            }
+            let file = format!("cols/{:06}.cseg", next_id);
+            let path = data.join(&file);
+            std::fs::create_dir_all(path.parent().unwrap_or(&data))?;
+            let seg = ColumnSegment::write(path, &entries, codec)?;
+            let m = seg.meta();
+            segments.push((segments.len(), SegmentMeta {
+                id: next_id,
+                kind: SegmentKind::Column,
+                bytes: std::fs::metadata(seg.path())?.len(),
+                file,
+                rows: m.rows,
+                min_ts: m.min_ts,
+                max_ts: m.max_ts,
+                max_txn: m.max_txn,
+            }));
+            next_id += 1;
+            versions += entries.len() as u64;
+            Ok(())
+        };
+        for seg in view.segments.iter().rev() {
+            write_layer(seg.iter()?)?;
+        }
+        let ids = self.vectors.read().version_ids();
+        let index = self.vector_index();
+        let mut seen: Vec<&Arc<MemTable>> = Vec::new();
+        for mem in view.mems.iter().rev() {
+            // a memtable shows up twice if it was frozen while the view was taken
+            if seen.iter().any(|m| Arc::ptr_eq(m, mem)) { continue; }
+            seen.push(mem);
+            write_layer(mem.all_versions().into_iter().map(|version| {
+                let vector = match ids.get(&(version.row.key.clone(), version.begin_ts)) {
+                    Some(vid) => index.get(*vid)?,
+                    None => None,
+                };
+                Ok(RowSegmentEntry { version, vector })
+            }).collect::<Result<_>>()?)?;
+        }
+        drop(index);
+
+        let mut log = ManifestLog::open(&data)?;
+        log.apply(VersionEdit {
+            schema_version: Some(source.schema_version),
+            next_segment: Some(next_id),
+            add_segments: segments,
+            add_indexes: source.indexes.clone(),
+            add_field_indexes: source.field_indexes.clone(),
+            ..VersionEdit::default()
+        })?;
+        drop(log);
+        drop(snapshot);
+
+        let info = BackupInfo {
+            format_version: BACKUP_FORMAT,
+            data_format: super::manifest::FORMAT_VERSION,
+            as_of,
+            created_unix_ms: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+            schema_version: source.schema_version,
+            versions,
+            skipped_indexes,
+            files: list_files(&data)?,
+        };
+        save_json(dir.join(INFO_FILE), &info)?;
+        super::wal::sync_dir(dir)?;
+        Ok(info)
+    }
+
+    // Checks the backup in `dir` against its BACKUP.json and copies it into
+    // `cfg.data_dir`, which must not hold a database yet; `cfg.wal_dir` must
+    // not hold a log either. Open the engine with `cfg` afterwards.
+    pub fn restore_from(dir: impl AsRef<Path>, cfg: &Config) -> Result<BackupInfo> {
+        let dir = dir.as_ref();
+        let info: BackupInfo = load_json(dir.join(INFO_FILE)).with_context(|| format!("{} is not a complete backup", dir.display()))?;
+        if info.format_version > BACKUP_FORMAT {
+            bail!("backup format {} is newer than this build reads ({})", info.format_version, BACKUP_FORMAT);
+        }
+        let data = dir.join(DATA_DIR);
+        for f in &info.files {
+            let bytes = std::fs::read(data.join(&f.path)).with_context(|| format!("backup file {} is missing", f.path))?;
+            if bytes.len() as u64 != f.bytes || simd_adler32::adler32(&bytes.as_slice()) != f.adler32 {
+                bail!("backup file {} is corrupt", f.path);
+            }
+        }
+        let target = PathBuf::from(&cfg.data_dir);
+        if target.join("CURRENT").exists() || target.join("MANIFEST.json").exists() {
+            bail!("{} already holds a database", target.display());
+        }
+        if std::fs::read_dir(&cfg.wal_dir).is_ok_and(|mut d| d.next().is_some()) {
+            bail!("WAL directory {} is not empty", cfg.wal_dir);
+        }
+        // CURRENT goes last: until it is in place the target is not a database
+        let (current, rest): (Vec<&BackupFile>, Vec<&BackupFile>) = info.files.iter().partition(|f| f.path == "CURRENT");
+        for f in rest.into_iter().chain(current) {
+            let to = target.join(&f.path);
+            if let Some(parent) = to.parent() { std::fs::create_dir_all(parent)?; }
+            std::fs::copy(data.join(&f.path), &to)?;
+            std::fs::File::open(&to)?.sync_all()?;
+        }
+        super::wal::sync_dir(&target)?;
+        Ok(info)
+    }
+}
+
+// Every file under `root`, with paths relative to it, in a stable order.
+fn list_files(root: &Path) -> Result<Vec<BackupFile>> {
+    let mut out = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(d) = dirs.pop() {
+        for entry in std::fs::read_dir(&d)? {
+            let path = entry?.path();
+            if path.is_dir() { dirs.push(path); continue; }
+            let bytes = std::fs::read(&path)?;
+            let rel = path.strip_prefix(root)?.to_string_lossy().replace('\\', "/");
+            out.push(BackupFile { path: rel, bytes: bytes.len() as u64, adler32: simd_adler32::adler32(&bytes.as_slice()) });
+        }
+    }
+    out.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(out)
+}
@@ -1,82 +1,809 @@
-
 use serde::{Serialize, Deserialize};
-use anyhow::Result;
-use std::path::PathBuf;
-use std::fs::OpenOptions;
-use std::io::{Write, Read};
+use anyhow::{anyhow, bail, Result};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions};
+use std::io::{Write, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::types::{Row, RowKey, Timestamp, Vector, VersionedRow};
+use crate::mvcc::visible_at;
+use super::encoding::{self, Encoding};
+use super::compression::{decode_block, encode_block, Codec};
+use super::layers::SegmentReader;
+use super::predicate::{needs_column, Bloom, Predicate};
+use super::rowsegment::{RowSegmentEntry, RowSegmentMeta};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// File layout: page blocks | footer block | footer_len u32 | MAGIC. Pages
+// and the footer are compressed, checksummed blocks (see `compression`).
+// The footer holds every column's stats and page directory, so a reader
+// fetches only the pages it needs with range reads.
+const MAGIC: &[u8; 4] = b"AFC2";
+pub const PAGE_ROWS: usize = 4096;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    String,
+    I64,
+    F64,
+    Bool,
+    Timestamp,
+    // f32 embedding; every value in the column has the same dims
+    Vector,
+    // anything else, stored as JSON text
+    Json,
+}
+
+// A decoded cell.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    Ts(Timestamp),
+    Vector(Vec<f32>),
+    Json(serde_json::Value),
+}
+
+impl Value {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Str(s) => s.clone().into(),
+            Value::I64(v) => (*v).into(),
+            Value::F64(v) => serde_json::Number::from_f64(*v).map_or(serde_json::Value::Null, Into::into),
+            Value::Bool(b) => (*b).into(),
+            Value::Ts(t) => (*t).into(),
+            Value::Vector(v) => v.iter().map(|&x| serde_json::Value::from(x)).collect(),
+            Value::Json(j) => j.clone(),
+        }
+    }
+}
+
+// Orderable summary value kept in zonemaps.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, PartialOrd)]
+pub enum Scalar {
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    Ts(Timestamp),
+    Str(String),
+}
+
+// Min/max over the non-null values; None for vector and JSON columns, and
+// when there are no values.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ZoneMap {
-    pub min_len: u32,
-    pub max_len: u32,
-    #[serde(default)]
-    pub min_ts: Option<u64>,
-    #[serde(default)]
-    pub max_ts: Option<u64>,
+    pub min: Option<Scalar>,
+    pub max: Option<Scalar>,
+    pub null_count: u64,
+}
+
+impl ZoneMap {
+    fn of(values: &[Option<Value>]) -> (Self, Vec<Scalar>) {
+        let scalars: Vec<Scalar> = values.iter().flatten().filter_map(|v| match v {
+            Value::I64(x) => Some(Scalar::I64(*x)),
+            Value::F64(x) if !x.is_nan() => Some(Scalar::F64(*x)),
+            Value::Bool(x) => Some(Scalar::Bool(*x)),
+            Value::Ts(x) => Some(Scalar::Ts(*x)),
+            Value::Str(x) => Some(Scalar::Str(x.clone())),
+            _ => None,
+        }).collect();
+        let cmp = |a: &&Scalar, b: &&Scalar| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal);
+        let zm = Self {
+            min: scalars.iter().min_by(cmp).cloned(),
+            max: scalars.iter().max_by(cmp).cloned(),
+            null_count: values.iter().filter(|v| v.is_none()).count() as u64,
+        };
+        (zm, scalars)
+    }
+
+    // Widens this zonemap to cover `other`'s values as well.
+    fn merge(&mut self, other: &ZoneMap) {
+        let pick = |a: &mut Option<Scalar>, b: &Option<Scalar>, want: std::cmp::Ordering| {
+            if let Some(b) = b {
+                if a.as_ref().is_none_or(|a| b.partial_cmp(a) == Some(want)) { *a = Some(b.clone()); }
+            }
+        };
+        pick(&mut self.min, &other.min, std::cmp::Ordering::Less);
+        pick(&mut self.max, &other.max, std::cmp::Ordering::Greater);
+        self.null_count += other.null_count;
+    }
+
+    // False if no value summarised here can satisfy `pred` on `column`.
+    fn may_match(&self, column: &str, pred: &Predicate, rows: u64) -> bool {
+        if self.null_count == rows { return false; }
+        match (&self.min, &self.max) {
+            (Some(lo), Some(hi)) => pred.may_overlap(column, lo, hi),
+            _ => true,
+        }
+    }
+}
+
+// Encoded values of one column for a run of rows. On disk:
+// validity_len u32 | validity | data.
+#[derive(Clone, Debug)]
+pub struct Page {
+    pub encoding: Encoding,
+    // one bit per row, set when the row has a value; empty if all rows do
+    pub validity: Vec<u8>,
+    // encoded non-null values only
+    pub data: Vec<u8>,
+}
+
+impl Page {
+    // Fails if a value does not match `ctype`.
+    pub fn encode(ctype: ColumnType, values: &[Option<Value>]) -> Result<Self> {
+        let present: Vec<&Value> = values.iter().flatten().collect();
+        let validity = if present.len() == values.len() { Vec::new() } else {
+            let mut bits = vec![0u8; values.len().div_ceil(8)];
+            for (i, v) in values.iter().enumerate() {
+                if v.is_some() { bits[i / 8] |= 1 << (i % 8); }
+            }
+            bits
+        };
+        let mismatch = |v: &Value| anyhow!("{:?} column got {:?}", ctype, v);
+        let ints = |f: &dyn Fn(&Value) -> Option<i64>| -> Result<Vec<i64>> {
+            present.iter().map(|v| f(v).ok_or_else(|| mismatch(v))).collect()
+        };
+        let (encoding, data) = match ctype {
+            ColumnType::I64 => encoding::encode_ints(&ints(&|v| match v { Value::I64(x) => Some(*x), _ => None })?),
+            ColumnType::Timestamp => encoding::encode_ints(&ints(&|v| match v { Value::Ts(x) => Some(*x as i64), _ => None })?),
+            ColumnType::Bool => encoding::encode_ints(&ints(&|v| match v { Value::Bool(x) => Some(*x as i64), _ => None })?),
+            ColumnType::F64 => encoding::encode_ints(&ints(&|v| match v { Value::F64(x) => Some(x.to_bits() as i64), _ => None })?),
+            ColumnType::String | ColumnType::Json => {
+                let texts: Vec<String> = present.iter().map(|v| match (ctype, v) {
+                    (ColumnType::String, Value::Str(s)) => Ok(s.clone()),
+                    (ColumnType::Json, Value::Json(j)) => Ok(j.to_string()),
+                    _ => Err(mismatch(v)),
+                }).collect::<Result<_>>()?;
+                encoding::encode_strings(&texts.iter().map(String::as_str).collect::<Vec<_>>())
+            }
+            ColumnType::Vector => {
+                let dims = match present.first() { Some(Value::Vector(v)) => v.len(), _ => 0 };
+                let mut data = (dims as u32).to_le_bytes().to_vec();
+                for v in &present {
+                    match v {
+                        Value::Vector(x) if x.len() == dims => data.extend(x.iter().flat_map(|f| f.to_le_bytes())),
+                        _ => return Err(mismatch(v)),
+                    }
+                }
+                (Encoding::Plain, data)
+            }
+        };
+        Ok(Self { encoding, validity, data })
+    }
+
+    pub fn is_valid(&self, row: usize) -> bool {
+        self.validity.is_empty() || self.validity[row / 8] & (1 << (row % 8)) != 0
+    }
+
+    // All `rows` cells, None where the row has no value.
+    pub fn decode(&self, ctype: ColumnType, rows: usize) -> Result<Vec<Option<Value>>> {
+        let n = (0..rows).filter(|&i| self.is_valid(i)).count();
+        let values: Vec<Value> = match ctype {
+            ColumnType::I64 => encoding::decode_ints(self.encoding, &self.data, n)?.into_iter().map(Value::I64).collect(),
+            ColumnType::Timestamp => encoding::decode_ints(self.encoding, &self.data, n)?.into_iter().map(|x| Value::Ts(x as u64)).collect(),
+            ColumnType::Bool => encoding::decode_ints(self.encoding, &self.data, n)?.into_iter().map(|x| Value::Bool(x != 0)).collect(),
+            ColumnType::F64 => encoding::decode_ints(self.encoding, &self.data, n)?.into_iter().map(|x| Value::F64(f64::from_bits(x as u64))).collect(),
+            ColumnType::String => encoding::decode_strings(self.encoding, &self.data, n)?.into_iter().map(Value::Str).collect(),
+            ColumnType::Json => encoding::decode_strings(self.encoding, &self.data, n)?.into_iter()
+                .map(|s| serde_json::from_str(&s).map(Value::Json)).collect::<Result<_, _>>()?,
+            ColumnType::Vector => {
+                let Some(header) = self.data.get(..4) else { bail!("vector page truncated") };
+                let dims = u32::from_le_bytes(header.try_into()?) as usize;
+                let floats: Vec<f32> = self.data[4..].chunks_exact(4).map(|c| f32::from_le_bytes(c.try_into().unwrap())).collect();
+                if floats.len() != dims * n { bail!("vector page truncated"); }
+                if dims == 0 { vec![Value::Vector(Vec::new()); n] } else { floats.chunks(dims).map(|c| Value::Vector(c.to_vec())).collect() }
+            }
+        };
+        if values.len() != n { bail!("page decoded {} values, expected {}", values.len(), n); }
+        let mut it = values.into_iter();
+        Ok((0..rows).map(|i| if self.is_valid(i) { it.next() } else { None }).collect())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = (self.validity.len() as u32).to_le_bytes().to_vec();
+        out.extend_from_slice(&self.validity);
+        out.extend_from_slice(&self.data);
+        out
+    }
+
+    fn from_bytes(encoding: Encoding, bytes: &[u8]) -> Result<Self> {
+        let Some(len) = bytes.get(..4) else { bail!("page truncated") };
+        let len = u32::from_le_bytes(len.try_into()?) as usize;
+        let Some(validity) = bytes.get(4..4 + len) else { bail!("page truncated") };
+        Ok(Self { encoding, validity: validity.to_vec(), data: bytes[4 + len..].to_vec() })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PageMeta {
+    pub first_row: u64,
+    pub rows: u64,
+    // byte range of the page block in the file
+    pub offset: u64,
+    pub len: u64,
+    // page body size before compression
+    pub raw_len: u64,
+    pub encoding: Encoding,
+    pub zonemap: ZoneMap,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Column {
     pub name: String,
-    pub offsets: Vec<u64>,
-    pub blob: Vec<u8>,
+    pub ctype: ColumnType,
     pub zonemap: ZoneMap,
+    // string and integer columns, for equality pruning
+    pub bloom: Option<Bloom>,
+    pub pages: Vec<PageMeta>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Segment {
+impl Column {
+    // False if the column's stats prove no row can satisfy `pred`.
+    pub fn may_match(&self, pred: &Predicate, rows: u64) -> bool {
+        if !self.zonemap.may_match(&self.name, pred, rows) { return false; }
+        let Some(bloom) = &self.bloom else { return true };
+        // the filter hashes the column's own scalar kind
+        pred.equalities(&self.name).into_iter().all(|want| {
+            !matches!((self.ctype, want), (ColumnType::String, Scalar::Str(_)) | (ColumnType::I64, Scalar::I64(_)))
+                || bloom.may_contain(want)
+        })
+    }
+
+    // Same, for the rows of one page.
+    pub fn page_may_match(&self, page: usize, pred: &Predicate) -> bool {
+        let p = &self.pages[page];
+        p.zonemap.may_match(&self.name, pred, p.rows)
+    }
+
+    // page bytes before and after block compression
+    pub fn encoded_bytes(&self) -> u64 { self.pages.iter().map(|p| p.raw_len).sum() }
+    pub fn stored_bytes(&self) -> u64 { self.pages.iter().map(|p| p.len).sum() }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Footer {
     pub rows: u64,
+    // every column is split into pages at the same row boundaries
+    pub page_rows: u64,
+    pub codec: Codec,
     pub columns: Vec<Column>,
 }
 
+impl Footer {
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.iter().find(|c| c.name == name)
+    }
+}
+
+// Builds a column segment in memory; `write_to` lays it out on disk.
+pub struct Segment {
+    pub footer: Footer,
+    // page bodies in file order; `PageMeta::offset` points into this
+    body: Vec<u8>,
+}
+
+impl Default for Segment {
+    fn default() -> Self { Self::new() }
+}
+
 impl Segment {
-    pub fn new() -> Self {
-        Self { rows: 0, columns: Vec::new() }
-    }
-
-    pub fn add_string_column(&mut self, name: &str, values: &[String]) {
-        let mut offsets = Vec::with_capacity(values.len());
-        let mut blob = Vec::<u8>::new();
-        let mut min_len = u32::MAX;
-        let mut max_len = 0u32;
-
-        let mut off: u64 = 0;
-        for s in values {
-            let bytes = s.as_bytes();
-            let len = bytes.len() as u32;
-            min_len = min_len.min(len);
-            max_len = max_len.max(len);
-            offsets.push(off);
-            blob.extend_from_slice(&(len.to_le_bytes()));
-            blob.extend_from_slice(bytes);
-            off += 4 + bytes.len() as u64;
+    pub fn new() -> Self { Self::with_layout(PAGE_ROWS, Codec::default()) }
+
+    pub fn with_layout(page_rows: usize, codec: Codec) -> Self {
+        Self { footer: Footer { rows: 0, page_rows: page_rows.max(1) as u64, codec, columns: Vec::new() }, body: Vec::new() }
+    }
+
+    pub fn rows(&self) -> u64 { self.footer.rows }
+
+    // Every column must have the same number of rows.
+    pub fn add_column(&mut self, name: &str, ctype: ColumnType, values: &[Option<Value>]) -> Result<()> {
+        if !self.footer.columns.is_empty() && values.len() as u64 != self.footer.rows {
+            bail!("column {} has {} rows, segment has {}", name, values.len(), self.footer.rows);
+        }
+        let mut pages = Vec::new();
+        for (i, chunk) in values.chunks(self.footer.page_rows as usize).enumerate() {
+            let (meta, bytes) = encode_page(self.footer.codec, name, ctype, chunk, i as u64 * self.footer.page_rows, self.body.len() as u64)?;
+            pages.push(meta);
+            self.body.extend_from_slice(&bytes);
         }
-        self.rows = values.len() as u64;
-        self.columns.push(Column {
-            name: name.to_string(),
-            offsets,
-            blob,
-            zonemap: ZoneMap { min_len, max_len, min_ts: None, max_ts: None },
+        let (zonemap, scalars) = ZoneMap::of(values);
+        let bloom = has_bloom(ctype).then(|| {
+            let mut b = Bloom::new(scalars.len());
+            for s in &scalars { b.insert(s); }
+            b
         });
+        self.footer.columns.push(Column { name: name.to_string(), ctype, zonemap, bloom, pages });
+        self.footer.rows = values.len() as u64;
+        Ok(())
     }
 
+    pub fn column(&self, name: &str) -> Option<&Column> { self.footer.column(name) }
+
+    // Written via a temp file and renamed into place.
     pub fn write_to(&self, path: PathBuf) -> Result<()> {
-        std::fs::create_dir_all(path.parent().unwrap())?;
-        let mut f = OpenOptions::new().create(true).write(true).open(&path)?;
-        let bytes = bincode::serialize(self)?;
-        f.write_all(&(bytes.len() as u32).to_le_bytes())?;
-        f.write_all(&bytes)?;
+        let (tmp, mut f) = create_tmp(&path)?;
+        f.write_all(&self.body)?;
+        finish_file(f, &self.footer, &tmp, &path)
+    }
+}
+
+// The temp file a segment for `path` is written to.
+fn create_tmp(path: &Path) -> Result<(PathBuf, File)> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension("tmp");
+    let f = OpenOptions::new().create(true).write(true).truncate(true).open(&tmp)?;
+    Ok((tmp, f))
+}
+
+// Appends the footer and trailer to `f`, whose pages are written, and
+// renames it to `path`.
+fn finish_file(mut f: File, footer: &Footer, tmp: &Path, path: &Path) -> Result<()> {
+    let footer = encode_block(footer.codec, &bincode::serialize(footer)?);
+    f.write_all(&footer)?;
+    f.write_all(&(footer.len() as u32).to_le_bytes())?;
+    f.write_all(MAGIC)?;
+    f.sync_all()?;
+    std::fs::rename(tmp, path)?;
+    super::wal::sync_dir(path.parent().unwrap())?;
+    Ok(())
+}
+
+// One page of `values`, starting at row `first_row`, as the block to write
+// at `offset`.
+fn encode_page(codec: Codec, name: &str, ctype: ColumnType, values: &[Option<Value>], first_row: u64, offset: u64) -> Result<(PageMeta, Vec<u8>)> {
+    let page = Page::encode(ctype, values).map_err(|e| anyhow!("column {}: {}", name, e))?;
+    let raw = page.to_bytes();
+    let bytes = encode_block(codec, &raw);
+    let meta = PageMeta {
+        first_row,
+        rows: values.len() as u64,
+        offset,
+        len: bytes.len() as u64,
+        raw_len: raw.len() as u64,
+        encoding: page.encoding,
+        zonemap: ZoneMap::of(values).0,
+    };
+    Ok((meta, bytes))
+}
+
+fn has_bloom(ctype: ColumnType) -> bool { matches!(ctype, ColumnType::String | ColumnType::I64) }
+
+// Read side of a column segment file: only the footer is kept in memory,
+// pages are fetched with range reads on demand.
+pub struct SegmentFile {
+    path: PathBuf,
+    file: Mutex<File>,
+    footer: Footer,
+    // page bytes read so far
+    bytes_read: AtomicU64,
+}
+
+impl SegmentFile {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).open(&path)?;
+        let len = file.metadata()?.len();
+        if len < 8 { bail!("column segment {} is too short", path.display()); }
+        let mut tail = [0u8; 8];
+        file.seek(SeekFrom::Start(len - 8))?;
+        file.read_exact(&mut tail)?;
+        if &tail[4..] != MAGIC { bail!("column segment {} has a bad trailer", path.display()); }
+        let footer_len = u32::from_le_bytes(tail[..4].try_into()?) as u64;
+        if footer_len > len - 8 { bail!("column segment {} has a bad footer length", path.display()); }
+        let mut buf = vec![0u8; footer_len as usize];
+        file.seek(SeekFrom::Start(len - 8 - footer_len))?;
+        file.read_exact(&mut buf)?;
+        let footer: Footer = bincode::deserialize(&decode_block(&buf).map_err(|e| anyhow!("column segment {} footer: {}", path.display(), e))?)?;
+        Ok(Self { path, file: Mutex::new(file), footer, bytes_read: AtomicU64::new(0) })
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+    pub fn footer(&self) -> &Footer { &self.footer }
+    pub fn rows(&self) -> u64 { self.footer.rows }
+    pub fn bytes_read(&self) -> u64 { self.bytes_read.load(Ordering::Relaxed) }
+
+    pub fn read_page(&self, col: &Column, page: usize) -> Result<Vec<Option<Value>>> {
+        let pm = &col.pages[page];
+        let mut buf = vec![0u8; pm.len as usize];
+        {
+            let mut f = self.file.lock();
+            f.seek(SeekFrom::Start(pm.offset))?;
+            f.read_exact(&mut buf)?;
+        }
+        self.bytes_read.fetch_add(pm.len, Ordering::Relaxed);
+        let raw = decode_block(&buf).map_err(|e| anyhow!("column segment {} column {} page {}: {}", self.path.display(), col.name, page, e))?;
+        Page::from_bytes(pm.encoding, &raw)?.decode(col.ctype, pm.rows as usize)
+    }
+
+    // Cells of column `name` for `rows`, reading only the pages they span.
+    pub fn read_column(&self, name: &str, rows: Range<usize>) -> Result<Vec<Option<Value>>> {
+        let col = self.footer.column(name).ok_or_else(|| anyhow!("column segment {} lacks column {}", self.path.display(), name))?;
+        let page_rows = self.footer.page_rows as usize;
+        let mut out = Vec::with_capacity(rows.len());
+        if rows.is_empty() { return Ok(out); }
+        for page in rows.start / page_rows..=(rows.end - 1) / page_rows {
+            let first = page * page_rows;
+            let cells = self.read_page(col, page)?;
+            let lo = rows.start.max(first) - first;
+            let hi = rows.end.min(first + cells.len()) - first;
+            out.extend(cells.into_iter().skip(lo).take(hi - lo));
+        }
+        Ok(out)
+    }
+}
+
+// System columns; payload fields are pivoted into "payload.<field>" columns
+// typed from their values (see `TypeFit`).
+const KEY: &str = "key";
+const BEGIN_TS: &str = "begin_ts";
+const END_TS: &str = "end_ts";
+const TXN_ID: &str = "txn_id";
+const DELETED: &str = "deleted";
+const VECTOR: &str = "vector";
+// payloads that are not objects; null otherwise
+const PAYLOAD: &str = "payload";
+const FIELD_PREFIX: &str = "payload.";
+
+// Narrowest type that round-trips every value of a payload field seen so
+// far. A JSON null is kept apart from a missing field, which forces the
+// Json type.
+#[derive(Clone, Copy)]
+struct TypeFit {
+    bool: bool,
+    i64: bool,
+    f64: bool,
+    string: bool,
+}
+
+impl TypeFit {
+    const ANY: TypeFit = TypeFit { bool: true, i64: true, f64: true, string: true };
+
+    fn add(&mut self, v: &serde_json::Value) {
+        self.bool &= v.is_boolean();
+        self.i64 &= v.is_i64();
+        self.f64 &= v.is_f64();
+        self.string &= v.is_string();
+    }
+
+    fn ctype(&self) -> ColumnType {
+        if self.bool { ColumnType::Bool }
+        else if self.i64 { ColumnType::I64 }
+        else if self.f64 { ColumnType::F64 }
+        else if self.string { ColumnType::String }
+        else { ColumnType::Json }
+    }
+}
+
+// The columns a column segment gets for a set of entries: payload fields
+// with their types, and the embedding dims. Gathered in a first pass, so
+// the rows can then be written a page at a time.
+#[derive(Default)]
+pub struct SegmentSchema {
+    fields: BTreeMap<String, TypeFit>,
+    dims: BTreeSet<usize>,
+    rows: u64,
+}
+
+impl SegmentSchema {
+    pub fn of<'a>(entries: impl IntoIterator<Item = &'a RowSegmentEntry>) -> Self {
+        let mut schema = Self::default();
+        for e in entries { schema.add(e); }
+        schema
+    }
+
+    pub fn add(&mut self, e: &RowSegmentEntry) {
+        if let Some(obj) = e.version.row.payload.as_object() {
+            for (field, v) in obj { self.fields.entry(field.clone()).or_insert(TypeFit::ANY).add(v); }
+        }
+        if let Some(v) = &e.vector { self.dims.insert(v.0.len()); }
+        self.rows += 1;
+    }
+
+    pub fn rows(&self) -> u64 { self.rows }
+}
+
+fn typed(ctype: ColumnType, v: &serde_json::Value) -> Value {
+    match ctype {
+        ColumnType::Bool => Value::Bool(v.as_bool().unwrap()),
+        ColumnType::I64 => Value::I64(v.as_i64().unwrap()),
+        ColumnType::F64 => Value::F64(v.as_f64().unwrap()),
+        ColumnType::String => Value::Str(v.as_str().unwrap().to_string()),
+        _ => Value::Json(v.clone()),
+    }
+}
+
+// Cells read for a run of rows, by column name.
+type Cells = HashMap<String, Vec<Option<Value>>>;
+
+// What a column holds of an entry.
+enum Source {
+    Key,
+    BeginTs,
+    EndTs,
+    TxnId,
+    Deleted,
+    Vector,
+    Payload,
+    Field(String),
+}
+
+impl Source {
+    fn cell(&self, ctype: ColumnType, e: &RowSegmentEntry) -> Option<Value> {
+        let v = &e.version;
+        match self {
+            Source::Key => Some(Value::Str(v.row.key.0.clone())),
+            Source::BeginTs => Some(Value::Ts(v.begin_ts)),
+            Source::EndTs => v.end_ts.map(Value::Ts),
+            Source::TxnId => Some(Value::I64(v.txn_id as i64)),
+            Source::Deleted => Some(Value::Bool(v.deleted)),
+            Source::Vector => e.vector.as_ref().map(|x| match ctype {
+                ColumnType::Vector => Value::Vector(x.0.clone()),
+                _ => Value::Json(serde_json::to_value(&x.0).unwrap()),
+            }),
+            Source::Payload => (!v.row.payload.is_object() && !v.deleted).then(|| Value::Json(v.row.payload.clone())),
+            Source::Field(f) => v.row.payload.get(f.as_str()).map(|x| typed(ctype, x)),
+        }
+    }
+}
+
+// Writes a column segment a page at a time: entries (sorted by key, then
+// begin_ts) are buffered until a page is full, then every column's page is
+// encoded and appended to the file. Only the footer and one page of entries
+// stay in memory. Every entry must fit the schema it was created with.
+pub struct ColumnSegmentWriter {
+    path: PathBuf,
+    tmp: PathBuf,
+    file: std::io::BufWriter<File>,
+    offset: u64,
+    sources: Vec<Source>,
+    footer: Footer,
+    blooms: Vec<Option<Bloom>>,
+    pending: Vec<RowSegmentEntry>,
+    // rows the schema was gathered from
+    expected: u64,
+}
+
+impl ColumnSegmentWriter {
+    pub fn create(path: PathBuf, schema: &SegmentSchema, codec: Codec) -> Result<Self> {
+        // embeddings of mismatched dims (a model change) fall back to JSON
+        let vtype = if schema.dims.len() <= 1 { ColumnType::Vector } else { ColumnType::Json };
+        let mut columns = vec![
+            (KEY.to_string(), ColumnType::String, Source::Key),
+            (BEGIN_TS.to_string(), ColumnType::Timestamp, Source::BeginTs),
+            (END_TS.to_string(), ColumnType::Timestamp, Source::EndTs),
+            (TXN_ID.to_string(), ColumnType::I64, Source::TxnId),
+            (DELETED.to_string(), ColumnType::Bool, Source::Deleted),
+            (VECTOR.to_string(), vtype, Source::Vector),
+            (PAYLOAD.to_string(), ColumnType::Json, Source::Payload),
+        ];
+        for (field, fit) in &schema.fields {
+            columns.push((format!("{}{}", FIELD_PREFIX, field), fit.ctype(), Source::Field(field.clone())));
+        }
+        let (tmp, file) = create_tmp(&path)?;
+        let mut footer = Footer { rows: 0, page_rows: PAGE_ROWS as u64, codec, columns: Vec::new() };
+        let mut sources = Vec::new();
+        let mut blooms = Vec::new();
+        for (name, ctype, source) in columns {
+            blooms.push(has_bloom(ctype).then(|| Bloom::new(schema.rows as usize)));
+            footer.columns.push(Column { name, ctype, zonemap: ZoneMap::default(), bloom: None, pages: Vec::new() });
+            sources.push(source);
+        }
+        Ok(Self { path, tmp, file: std::io::BufWriter::new(file), offset: 0, sources, footer, blooms, pending: Vec::with_capacity(PAGE_ROWS), expected: schema.rows })
+    }
+
+    pub fn push(&mut self, e: RowSegmentEntry) -> Result<()> {
+        self.pending.push(e);
+        if self.pending.len() == PAGE_ROWS { self.write_page()?; }
+        Ok(())
+    }
+
+    fn write_page(&mut self) -> Result<()> {
+        if self.pending.is_empty() { return Ok(()); }
+        let first_row = self.footer.rows;
+        for ((col, source), bloom) in self.footer.columns.iter_mut().zip(&self.sources).zip(&mut self.blooms) {
+            let values: Vec<Option<Value>> = self.pending.iter().map(|e| source.cell(col.ctype, e)).collect();
+            let (meta, bytes) = encode_page(self.footer.codec, &col.name, col.ctype, &values, first_row, self.offset)?;
+            self.file.write_all(&bytes)?;
+            self.offset += bytes.len() as u64;
+            let (zonemap, scalars) = ZoneMap::of(&values);
+            col.zonemap.merge(&zonemap);
+            if let Some(b) = bloom { for s in &scalars { b.insert(s); } }
+            col.pages.push(meta);
+        }
+        self.footer.rows += self.pending.len() as u64;
+        self.pending.clear();
         Ok(())
     }
 
-    pub fn read_from(path: PathBuf) -> Result<Self> {
-        let mut f = OpenOptions::new().read(true).open(path)?;
-        let mut len_buf = [0u8;4];
-        f.read_exact(&mut len_buf)?;
-        let meta_len = u32::from_le_bytes(len_buf) as usize;
-        let mut buf = vec![0u8; meta_len];
-        f.read_exact(&mut buf)?;
-        let seg: Segment = bincode::deserialize(&buf)?;
-        Ok(seg)
+    // Writes the last page and the footer and opens the segment.
+    pub fn finish(mut self) -> Result<ColumnSegment> {
+        self.write_page()?;
+        if self.footer.rows != self.expected {
+            bail!("column segment {} got {} rows, its schema was gathered from {}", self.path.display(), self.footer.rows, self.expected);
+        }
+        for (col, bloom) in self.footer.columns.iter_mut().zip(self.blooms) { col.bloom = bloom; }
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        finish_file(file, &self.footer, &self.tmp, &self.path)?;
+        ColumnSegment::open(self.path)
+    }
+}
+
+// Row versions stored column-wise, as produced by the compactor. Only the
+// key index and the footer stay in memory.
+pub struct ColumnSegment {
+    file: SegmentFile,
+    meta: RowSegmentMeta,
+    // key -> (begin_ts, row) oldest first
+    index: BTreeMap<String, Vec<(Timestamp, usize)>>,
+}
+
+impl ColumnSegment {
+    // Pivots `entries` (sorted by key, then begin_ts) into columns.
+    pub fn write(path: PathBuf, entries: &[RowSegmentEntry], codec: Codec) -> Result<Self> {
+        let mut writer = ColumnSegmentWriter::create(path, &SegmentSchema::of(entries), codec)?;
+        for e in entries { writer.push(e.clone())?; }
+        writer.finish()
+    }
+
+    // Reads the footer plus the key and begin_ts columns for the index.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let file = SegmentFile::open(path)?;
+        let rows = file.rows() as usize;
+        let keys = file.read_column(KEY, 0..rows)?;
+        let begins = file.read_column(BEGIN_TS, 0..rows)?;
+        let mut index: BTreeMap<String, Vec<(Timestamp, usize)>> = BTreeMap::new();
+        for (i, (k, b)) in keys.into_iter().zip(begins).enumerate() {
+            let (Some(Value::Str(k)), Some(Value::Ts(b))) = (k, b) else { bail!("column segment {}: bad key at row {}", file.path().display(), i) };
+            index.entry(k).or_default().push((b, i));
+        }
+        let footer = file.footer();
+        let ts = |zm: Option<&Scalar>| match zm { Some(Scalar::Ts(t)) => *t, _ => 0 };
+        let begin_zm = &footer.column(BEGIN_TS).unwrap().zonemap;
+        let meta = RowSegmentMeta {
+            rows: rows as u64,
+            min_ts: ts(begin_zm.min.as_ref()),
+            max_ts: ts(begin_zm.max.as_ref()),
+            max_txn: match footer.column(TXN_ID).and_then(|c| c.zonemap.max.as_ref()) { Some(Scalar::I64(t)) => *t as u64, _ => 0 },
+        };
+        Ok(Self { file, meta, index })
+    }
+
+    pub fn path(&self) -> &Path { self.file.path() }
+    pub fn file(&self) -> &SegmentFile { &self.file }
+
+    fn read(&self, columns: &[&str], rows: Range<usize>) -> Result<Cells> {
+        columns.iter().map(|&c| Ok((c.to_string(), self.file.read_column(c, rows.clone())?))).collect()
+    }
+
+    // Reassembles the row at offset `j` of `cells`. The payload is built
+    // from whichever "payload.<field>" columns were read.
+    fn assemble(&self, cells: &Cells, j: usize) -> Result<RowSegmentEntry> {
+        let cell = |name: &str| cells.get(name).and_then(|c| c[j].as_ref());
+        let bad = |name: &str| anyhow!("column segment {}: bad {}", self.path().display(), name);
+        let deleted = matches!(cell(DELETED), Some(Value::Bool(true)));
+        let payload = match cell(PAYLOAD) {
+            Some(v) => v.to_json(),
+            None if deleted => serde_json::Value::Null,
+            None => {
+                let mut obj = serde_json::Map::new();
+                for (name, col) in cells.iter().filter(|(n, _)| n.starts_with(FIELD_PREFIX)) {
+                    if let Some(v) = &col[j] { obj.insert(name[FIELD_PREFIX.len()..].to_string(), v.to_json()); }
+                }
+                serde_json::Value::Object(obj)
+            }
+        };
+        let vector = match cell(VECTOR) {
+            Some(Value::Vector(v)) => Some(Vector(v.clone())),
+            Some(Value::Json(j)) => Some(Vector(serde_json::from_value(j.clone())?)),
+            _ => None,
+        };
+        let (Some(Value::Str(key)), Some(Value::Ts(begin_ts)), Some(Value::I64(txn_id))) = (cell(KEY), cell(BEGIN_TS), cell(TXN_ID)) else {
+            return Err(bad("system columns"));
+        };
+        let end_ts = match cell(END_TS) { Some(Value::Ts(t)) => Some(*t), None => None, _ => return Err(bad(END_TS)) };
+        let version = VersionedRow {
+            begin_ts: *begin_ts,
+            end_ts,
+            txn_id: *txn_id as u64,
+            row: Row { key: RowKey(key.clone()), payload },
+            deleted,
+        };
+        Ok(RowSegmentEntry { version, vector })
+    }
+
+    fn all_columns(&self) -> Vec<&str> {
+        self.file.footer().columns.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    fn entry(&self, i: usize) -> Result<RowSegmentEntry> {
+        self.assemble(&self.read(&self.all_columns(), i..i + 1)?, 0)
+    }
+}
+
+impl SegmentReader for ColumnSegment {
+    fn meta(&self) -> &RowSegmentMeta { &self.meta }
+
+    fn latest_ts(&self, key: &RowKey) -> Option<Timestamp> {
+        self.index.get(&key.0).and_then(|p| p.last()).map(|p| p.0)
+    }
+
+    fn visible_version(&self, key: &RowKey, ts: Timestamp) -> Result<Option<VersionedRow>> {
+        let Some(&(_, i)) = self.index.get(&key.0).and_then(|p| p.iter().rev().find(|p| p.0 <= ts)) else { return Ok(None) };
+        Ok(Some(self.entry(i)?.version).filter(|v| visible_at(v, ts)))
+    }
+
+    fn versions(&self, key: &RowKey) -> Result<Vec<VersionedRow>> {
+        let Some(rows) = self.index.get(&key.0) else { return Ok(Vec::new()) };
+        rows.iter().map(|&(_, i)| self.entry(i).map(|e| e.version)).collect()
+    }
+
+    fn iter(&self) -> Result<Vec<RowSegmentEntry>> {
+        let rows = self.file.rows() as usize;
+        let cells = self.read(&self.all_columns(), 0..rows)?;
+        (0..rows).map(|j| self.assemble(&cells, j)).collect()
+    }
+
+    fn stream(&self) -> Result<Box<dyn Iterator<Item = Result<RowSegmentEntry>> + '_>> {
+        let rows = self.file.rows() as usize;
+        let page_rows = self.file.footer().page_rows as usize;
+        let columns = self.all_columns();
+        let pages = (0..rows).step_by(page_rows.max(1)).map(move |first| {
+            let page = first..rows.min(first + page_rows);
+            let cells = self.read(&columns, page.clone())?;
+            (0..page.len()).map(|j| self.assemble(&cells, j)).collect::<Result<Vec<_>>>()
+        });
+        // stops after the first page that fails
+        let mut failed = false;
+        Ok(Box::new(pages.map_while(move |page| {
+            if failed { return None; }
+            failed = page.is_err();
+            Some(match page {
+                Ok(entries) => entries.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(e) => vec![Err(e)],
+            })
+        }).flatten()))
+    }
+
+    fn may_match(&self, pred: &Predicate) -> bool {
+        let footer = self.file.footer();
+        pred.columns().into_iter().all(|name| match footer.column(name) {
+            Some(col) => col.may_match(pred, footer.rows),
+            // a missing top-level field is null in every row; nested paths
+            // live inside a JSON column and cannot be judged
+            None => name.strip_prefix(FIELD_PREFIX).is_some_and(|f| f.contains('.')),
+        })
+    }
+
+    // Reads page by page, skipping pages whose zonemaps rule `pred` out, and
+    // only the payload columns that `pred` or `projection` need.
+    fn scan(&self, ts: Timestamp, pred: &Predicate, projection: Option<&[String]>) -> Result<Vec<VersionedRow>> {
+        let footer = self.file.footer();
+        let pred_cols = pred.columns();
+        let mut columns = vec![KEY, BEGIN_TS, END_TS, TXN_ID, DELETED, PAYLOAD];
+        // a nested path is read from the JSON column of its top-level field
+        columns.extend(footer.columns.iter().map(|c| c.name.as_str()).filter(|n| {
+            n.starts_with(FIELD_PREFIX)
+                && (projection.is_none_or(|p| p.iter().any(|c| needs_column(c, n))) || pred_cols.iter().any(|c| needs_column(c, n)))
+        }));
+        let (rows, page_rows) = (footer.rows as usize, footer.page_rows as usize);
+        let mut out = Vec::new();
+        for page in 0..rows.div_ceil(page_rows) {
+            let prunable = pred_cols.iter().filter_map(|n| footer.column(n));
+            if !prunable.into_iter().all(|c| c.page_may_match(page, pred)) { continue; }
+            let range = page * page_rows..rows.min((page + 1) * page_rows);
+            let cells = self.read(&columns, range.clone())?;
+            for j in 0..range.len() {
+                let v = self.assemble(&cells, j)?.version;
+                if visible_at(&v, ts) { out.push(v); }
+            }
+        }
+        Ok(out)
+    }
+
+    fn shadowed_keys(&self, ts: Timestamp) -> Vec<&str> {
+        self.index.iter().filter(|(_, p)| p[0].0 <= ts).map(|(k, _)| k.as_str()).collect()
     }
 }
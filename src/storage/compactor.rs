@@ -1,9 +1,217 @@
 
-// Placeholder compactor — in a real system, merges row segments to column segments
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use anyhow::Result;
+use serde::Serialize;
+use crate::types::Timestamp;
+use super::Engine;
+use super::columnsegment::{ColumnSegmentWriter, SegmentSchema};
+use super::layers::SegmentReader;
+use super::manifest::{SegmentKind, SegmentMeta, VersionEdit};
+use super::rowsegment::RowSegmentEntry;
+
+// What one compaction did.
+#[derive(Clone, Debug, Serialize)]
+pub struct CompactionReport {
+    pub segments_in: usize,
+    pub rows_in: u64,
+    pub rows_out: u64,
+    // versions no live snapshot could see any more
+    pub versions_dropped: u64,
+    // None when nothing survived
+    pub output: Option<SegmentMeta>,
+}
+
+type Stream<'a> = Box<dyn Iterator<Item = Result<RowSegmentEntry>> + 'a>;
+
+// The next entry of input `seg`. The heap pops the smallest key first, and
+// for equal keys the oldest segment, so each key's chain comes out in ts
+// order.
+struct Head(RowSegmentEntry, usize);
+
+impl Head {
+    fn order(&self) -> (&str, usize) { (&self.0.version.row.key.0, self.1) }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool { self.order() == other.order() }
+}
+
+impl Eq for Head {}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering { other.order().cmp(&self.order()) }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+// K-way merge of the input segments (oldest first), one key's version chain
+// at a time, yielding the versions that survive compaction. Holds one entry
+// per input plus the current chain.
+struct Survivors<'a> {
+    streams: Vec<Stream<'a>>,
+    heads: BinaryHeap<Head>,
+    horizon: Timestamp,
+    // entries read from the inputs so far
+    rows_in: u64,
+    // survivors of the last chain, oldest last
+    ready: Vec<RowSegmentEntry>,
+}
+
+impl<'a> Survivors<'a> {
+    fn new(readers: &'a [Arc<dyn SegmentReader>], horizon: Timestamp) -> Result<Self> {
+        let mut merge = Self { streams: Vec::new(), heads: BinaryHeap::new(), horizon, rows_in: 0, ready: Vec::new() };
+        for (i, seg) in readers.iter().enumerate() {
+            merge.streams.push(seg.stream()?);
+            merge.advance(i)?;
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        if let Some(e) = self.streams[i].next() {
+            self.heads.push(Head(e?, i));
+            self.rows_in += 1;
+        }
+        Ok(())
+    }
+
+    // Pops the next key's chain and keeps what survives in `ready`.
+    fn next_chain(&mut self) -> Result<()> {
+        let Some(Head(first, i)) = self.heads.pop() else { return Ok(()) };
+        self.advance(i)?;
+        let mut chain = vec![first];
+        while self.heads.peek().is_some_and(|h| h.0.version.row.key == chain[0].version.row.key) {
+            let Head(e, i) = self.heads.pop().unwrap();
+            self.advance(i)?;
+            chain.push(e);
+        }
+        // a flushed version's end_ts is stale if its successor was
+        // committed after the flush
+        for i in 1..chain.len() {
+            let next = chain[i].version.begin_ts;
+            chain[i - 1].version.end_ts = Some(next);
+        }
+        chain.retain(|e| e.version.end_ts.is_none_or(|t| t > self.horizon));
+        if let [only] = chain.as_slice() {
+            if only.version.deleted && only.version.begin_ts <= self.horizon { chain.clear(); }
+        }
+        chain.reverse();
+        self.ready = chain;
+        Ok(())
+    }
+}
+
+impl Iterator for Survivors<'_> {
+    type Item = Result<RowSegmentEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ready.is_empty() {
+            if self.heads.is_empty() { return None; }
+            if let Err(e) = self.next_chain() {
+                self.heads.clear();
+                return Some(Err(e));
+            }
+        }
+        self.ready.pop().map(Ok)
+    }
+}
+
+// Merges all current segments into one column segment. Flushes only append
+// segments, so the inputs stay the oldest layers while the merge runs and the
+// output can take their place; nothing older exists, so tombstones below the
+// GC horizon can go as well.
 pub struct Compactor;
 
 impl Compactor {
-    pub fn run() {
-        // TODO: implement merging logic, vector index rebuild, stats calc.
+    // Returns None when there is nothing to do: no on-disk store, or a single
+    // segment that is already columnar.
+    pub fn run(engine: &Engine) -> Result<Option<CompactionReport>> {
+        let Some(store) = &engine.store else { return Ok(None) };
+        let _compacting = store.compact_lock.lock();
+        let (inputs, readers) = {
+            let manifest = store.manifest.lock();
+            (manifest.state().segments.clone(), engine.layers.read().segments.clone())
+        };
+        if inputs.is_empty() || (inputs.len() == 1 && inputs[0].kind == SegmentKind::Column) { return Ok(None); }
+
+        let horizon = engine.advance_gc_horizon();
+        // the merge runs twice: once to learn the output's columns, once to
+        // write it page by page. The inputs are immutable, so both passes
+        // see the same versions.
+        let mut schema = SegmentSchema::default();
+        let mut merge = Survivors::new(&readers, horizon)?;
+        for e in &mut merge { schema.add(&e?); }
+        let (rows_in, rows_out) = (merge.rows_in, schema.rows());
+
+        let id = {
+            let mut manifest = store.manifest.lock();
+            let id = manifest.state().next_segment;
+            manifest.apply(VersionEdit { next_segment: Some(id + 1), ..VersionEdit::default() })?;
+            id
+        };
+        let output = if rows_out == 0 { None } else {
+            let file = format!("cols/{:06}.cseg", id);
+            let mut writer = ColumnSegmentWriter::create(store.dir.join(&file), &schema, store.codec)?;
+            for e in Survivors::new(&readers, horizon)? { writer.push(e?)?; }
+            let seg = writer.finish()?;
+            let m = seg.meta();
+            let meta = SegmentMeta {
+                id,
+                kind: SegmentKind::Column,
+                bytes: std::fs::metadata(seg.path())?.len(),
+                file,
+                rows: m.rows,
+                min_ts: m.min_ts,
+                max_ts: m.max_ts,
+                max_txn: m.max_txn,
+            };
+            Some((meta, seg))
+        };
+
+        let mut manifest = store.manifest.lock();
+        manifest.apply(VersionEdit {
+            remove_segments: inputs.iter().map(|s| s.id).collect(),
+            add_segments: output.iter().map(|(meta, _)| (0, meta.clone())).collect(),
+            ..VersionEdit::default()
+        })?;
+        let report = CompactionReport {
+            segments_in: inputs.len(),
+            rows_in,
+            rows_out,
+            versions_dropped: rows_in - rows_out,
+            output: output.as_ref().map(|(meta, _)| meta.clone()),
+        };
+        {
+            let mut layers = engine.layers.write();
+            let replacement: Option<Arc<dyn SegmentReader>> = output.map(|(_, seg)| Arc::new(seg) as Arc<dyn SegmentReader>);
+            layers.segments.splice(..inputs.len(), replacement);
+        }
+        drop(manifest);
+        engine.rebuild_field_indexes()?;
+        // readers still holding the old segments keep their open handles
+        for meta in &inputs {
+            match std::fs::remove_file(store.dir.join(&meta.file)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(Some(report))
+    }
+
+    // Runs a compaction every `every` until the engine is dropped.
+    pub fn spawn(engine: &Arc<Engine>, every: Duration) -> std::thread::JoinHandle<()> {
+        let weak: Weak<Engine> = Arc::downgrade(engine);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(every);
+            match weak.upgrade() {
+                Some(engine) => { let _ = Compactor::run(&engine); }
+                None => break,
+            }
+        })
     }
 }
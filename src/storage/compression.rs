@@ -0,0 +1,77 @@
+use serde::{Serialize, Deserialize};
+use anyhow::{anyhow, bail, Result};
+
+// Block codec for segment files. `Lz4` is fast with a modest ratio,
+// `Deflate` trades speed for a higher ratio on text-heavy payloads.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    None,
+    #[default]
+    Lz4,
+    Deflate,
+}
+
+impl Codec {
+    fn tag(self) -> u8 {
+        match self { Codec::None => 0, Codec::Lz4 => 1, Codec::Deflate => 2 }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag { 0 => Some(Codec::None), 1 => Some(Codec::Lz4), 2 => Some(Codec::Deflate), _ => None }
+    }
+}
+
+// Block layout (little endian):
+//   codec: u8 | stored_len: u32 | raw_len: u32 | checksum: u32 | stored
+// The adler32 checksum covers the stored bytes. A block that does not get
+// smaller is stored with `Codec::None`, whatever the segment's codec is.
+pub const BLOCK_HEADER_LEN: usize = 1 + 4 + 4 + 4;
+// Deflate level for `Codec::Deflate` (miniz scale, 0-10).
+const DEFLATE_LEVEL: u8 = 6;
+
+pub fn encode_block(codec: Codec, raw: &[u8]) -> Vec<u8> {
+    let compressed = match codec {
+        Codec::None => None,
+        Codec::Lz4 => Some(lz4_flex::block::compress(raw)),
+        Codec::Deflate => Some(miniz_oxide::deflate::compress_to_vec(raw, DEFLATE_LEVEL)),
+    };
+    let (codec, stored) = match compressed {
+        Some(c) if c.len() < raw.len() => (codec, c),
+        _ => (Codec::None, raw.to_vec()),
+    };
+    let mut out = Vec::with_capacity(BLOCK_HEADER_LEN + stored.len());
+    out.push(codec.tag());
+    out.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+    out.extend_from_slice(&simd_adler32::adler32(&stored.as_slice()).to_le_bytes());
+    out.extend_from_slice(&stored);
+    out
+}
+
+// Total length of the block starting at `header`, which must hold at least
+// `BLOCK_HEADER_LEN` bytes.
+pub fn block_len(header: &[u8]) -> Result<usize> {
+    let Some(len) = header.get(1..5) else { bail!("block header truncated") };
+    Ok(BLOCK_HEADER_LEN + u32::from_le_bytes(len.try_into()?) as usize)
+}
+
+// Verifies the checksum and decompresses one whole block.
+pub fn decode_block(block: &[u8]) -> Result<Vec<u8>> {
+    if block.len() < BLOCK_HEADER_LEN { bail!("block header truncated"); }
+    let codec = Codec::from_tag(block[0]).ok_or_else(|| anyhow!("unknown block codec {}", block[0]))?;
+    let stored_len = u32::from_le_bytes(block[1..5].try_into()?) as usize;
+    let raw_len = u32::from_le_bytes(block[5..9].try_into()?) as usize;
+    let sum = u32::from_le_bytes(block[9..13].try_into()?);
+    let stored = &block[BLOCK_HEADER_LEN..];
+    if stored.len() != stored_len { bail!("block holds {} bytes, header says {}", stored.len(), stored_len); }
+    if simd_adler32::adler32(&stored) != sum { bail!("block checksum mismatch"); }
+    let raw = match codec {
+        Codec::None => stored.to_vec(),
+        Codec::Lz4 => lz4_flex::block::decompress(stored, raw_len).map_err(|e| anyhow!("lz4: {}", e))?,
+        Codec::Deflate => miniz_oxide::inflate::decompress_to_vec_with_limit(stored, raw_len)
+            .map_err(|e| anyhow!("deflate: {:?}", e.status))?,
+    };
+    if raw.len() != raw_len { bail!("block decoded to {} bytes, header says {}", raw.len(), raw_len); }
+    Ok(raw)
+}
@@ -0,0 +1,198 @@
+
+use serde::{Serialize, Deserialize};
+use anyhow::{bail, Result};
+
+// Physical encodings for column values. Integer-like columns (i64,
+// timestamps, bools, f64 bit patterns) try every integer encoding and keep
+// the smallest; string-like columns choose between plain and dictionary.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    // fixed-width little-endian values, or length-prefixed strings
+    Plain,
+    // distinct strings once, then bit-packed codes
+    Dictionary,
+    // (value, run length) pairs
+    Rle,
+    // first value, then bit-packed zigzag deltas
+    Delta,
+    // frame of reference: min value, then bit-packed offsets from it
+    BitPacked,
+}
+
+pub fn bit_width(max: u64) -> u8 { (64 - max.leading_zeros()) as u8 }
+
+pub fn pack(values: &[u64], width: u8, out: &mut Vec<u8>) {
+    if width == 0 { return; }
+    let (mut acc, mut bits) = (0u128, 0u32);
+    for &v in values {
+        acc |= (v as u128) << bits;
+        bits += width as u32;
+        while bits >= 8 {
+            out.push(acc as u8);
+            acc >>= 8;
+            bits -= 8;
+        }
+    }
+    if bits > 0 { out.push(acc as u8); }
+}
+
+pub fn unpack(data: &[u8], width: u8, n: usize) -> Result<Vec<u64>> {
+    if width == 0 { return Ok(vec![0; n]); }
+    if width > 64 || data.len() < (n * width as usize).div_ceil(8) { bail!("bit-packed block too short"); }
+    let mask = if width == 64 { u64::MAX } else { (1u64 << width) - 1 };
+    let (mut acc, mut bits, mut pos) = (0u128, 0u32, 0usize);
+    let mut out = Vec::with_capacity(n);
+    for _ in 0..n {
+        while bits < width as u32 {
+            acc |= (data[pos] as u128) << bits;
+            pos += 1;
+            bits += 8;
+        }
+        out.push(acc as u64 & mask);
+        acc >>= width;
+        bits -= width as u32;
+    }
+    Ok(out)
+}
+
+fn zigzag(v: i64) -> u64 { ((v << 1) ^ (v >> 63)) as u64 }
+fn unzigzag(v: u64) -> i64 { ((v >> 1) as i64) ^ -((v & 1) as i64) }
+
+// Bounds-checked cursor over an encoded block.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self { Self { data, pos: 0 } }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < n { bail!("encoded block truncated"); }
+        self.pos += n;
+        Ok(&self.data[self.pos - n..self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8> { Ok(self.take(1)?[0]) }
+    fn u32(&mut self) -> Result<u32> { Ok(u32::from_le_bytes(self.take(4)?.try_into()?)) }
+    fn i64(&mut self) -> Result<i64> { Ok(i64::from_le_bytes(self.take(8)?.try_into()?)) }
+    fn rest(&mut self) -> &'a [u8] { let r = &self.data[self.pos..]; self.pos = self.data.len(); r }
+}
+
+pub fn encode_ints(values: &[i64]) -> (Encoding, Vec<u8>) {
+    let candidates = [
+        (Encoding::BitPacked, ints_bitpacked(values)),
+        (Encoding::Delta, ints_delta(values)),
+        (Encoding::Rle, ints_rle(values)),
+        (Encoding::Plain, values.iter().flat_map(|v| v.to_le_bytes()).collect()),
+    ];
+    candidates.into_iter().min_by_key(|(_, b)| b.len()).unwrap()
+}
+
+fn ints_bitpacked(values: &[i64]) -> Vec<u8> {
+    let min = values.iter().copied().min().unwrap_or(0);
+    let offsets: Vec<u64> = values.iter().map(|v| v.wrapping_sub(min) as u64).collect();
+    let width = bit_width(offsets.iter().copied().max().unwrap_or(0));
+    let mut out = min.to_le_bytes().to_vec();
+    out.push(width);
+    pack(&offsets, width, &mut out);
+    out
+}
+
+fn ints_delta(values: &[i64]) -> Vec<u8> {
+    let first = values.first().copied().unwrap_or(0);
+    let deltas: Vec<u64> = values.windows(2).map(|w| zigzag(w[1].wrapping_sub(w[0]))).collect();
+    let width = bit_width(deltas.iter().copied().max().unwrap_or(0));
+    let mut out = first.to_le_bytes().to_vec();
+    out.push(width);
+    pack(&deltas, width, &mut out);
+    out
+}
+
+fn ints_rle(values: &[i64]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < values.len() {
+        let run = values[i..].iter().take_while(|&&v| v == values[i]).count().min(u32::MAX as usize);
+        out.extend_from_slice(&values[i].to_le_bytes());
+        out.extend_from_slice(&(run as u32).to_le_bytes());
+        i += run;
+    }
+    out
+}
+
+pub fn decode_ints(enc: Encoding, data: &[u8], n: usize) -> Result<Vec<i64>> {
+    let mut r = Reader::new(data);
+    let out = match enc {
+        Encoding::Plain => (0..n).map(|_| r.i64()).collect::<Result<Vec<_>>>()?,
+        Encoding::BitPacked => {
+            let min = r.i64()?;
+            let width = r.u8()?;
+            unpack(r.rest(), width, n)?.into_iter().map(|o| min.wrapping_add(o as i64)).collect()
+        }
+        Encoding::Delta => {
+            let first = r.i64()?;
+            let width = r.u8()?;
+            let deltas = unpack(r.rest(), width, n.saturating_sub(1))?;
+            let mut out = Vec::with_capacity(n);
+            if n > 0 { out.push(first); }
+            for d in deltas { out.push(out.last().unwrap().wrapping_add(unzigzag(d))); }
+            out
+        }
+        Encoding::Rle => {
+            let mut out = Vec::with_capacity(n);
+            while out.len() < n {
+                let v = r.i64()?;
+                let run = r.u32()? as usize;
+                if run == 0 || out.len() + run > n { bail!("bad run length"); }
+                out.extend(std::iter::repeat_n(v, run));
+            }
+            out
+        }
+        Encoding::Dictionary => bail!("dictionary encoding is for strings"),
+    };
+    Ok(out)
+}
+
+pub fn encode_strings(values: &[&str]) -> (Encoding, Vec<u8>) {
+    let mut plain = Vec::new();
+    for s in values { put_str(&mut plain, s); }
+    let mut dict: Vec<&str> = Vec::new();
+    let mut codes_by_value = std::collections::HashMap::new();
+    let codes: Vec<u64> = values.iter().map(|s| *codes_by_value.entry(*s).or_insert_with(|| {
+        dict.push(s);
+        dict.len() as u64 - 1
+    })).collect();
+    let mut dictionary = (dict.len() as u32).to_le_bytes().to_vec();
+    for s in &dict { put_str(&mut dictionary, s); }
+    let width = bit_width(dict.len().saturating_sub(1) as u64);
+    dictionary.push(width);
+    pack(&codes, width, &mut dictionary);
+    if dictionary.len() < plain.len() { (Encoding::Dictionary, dictionary) } else { (Encoding::Plain, plain) }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn get_str(r: &mut Reader) -> Result<String> {
+    let len = r.u32()? as usize;
+    Ok(std::str::from_utf8(r.take(len)?)?.to_string())
+}
+
+pub fn decode_strings(enc: Encoding, data: &[u8], n: usize) -> Result<Vec<String>> {
+    let mut r = Reader::new(data);
+    match enc {
+        Encoding::Plain => (0..n).map(|_| get_str(&mut r)).collect(),
+        Encoding::Dictionary => {
+            let len = r.u32()? as usize;
+            let dict = (0..len).map(|_| get_str(&mut r)).collect::<Result<Vec<_>>>()?;
+            let width = r.u8()?;
+            unpack(r.rest(), width, n)?.into_iter()
+                .map(|c| dict.get(c as usize).cloned().ok_or_else(|| anyhow::anyhow!("dictionary code out of range")))
+                .collect()
+        }
+        other => bail!("{:?} encoding is for integers", other),
+    }
+}
@@ -0,0 +1,201 @@
+
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use anyhow::Result;
+use parking_lot::{Condvar, Mutex};
+use crate::config::Config;
+use super::Engine;
+use super::layers::Frozen;
+use super::manifest::{ManifestLog, SegmentKind, SegmentMeta, VersionEdit};
+use super::memtable::MemTable;
+use super::rowsegment::{RowSegment, RowSegmentEntry};
+use super::layers::SegmentReader;
+use super::wal::Lsn;
+use super::compression::Codec;
+
+// Where and when memtables are flushed. Only durable engines have one.
+pub(crate) struct Store {
+    pub dir: PathBuf,
+    pub manifest: Mutex<ManifestLog>,
+    flush_bytes: u64,
+    flush_age: Duration,
+    // block codec for new row and column segments
+    pub codec: Codec,
+    // one flusher at a time
+    flush_lock: Mutex<()>,
+    pub compact_lock: Mutex<()>,
+    // set by commits that leave the memtable due; see `spawn_flusher`
+    signal: Arc<FlushSignal>,
+    // why the last background flush failed, until one succeeds
+    last_error: Mutex<Option<String>>,
+}
+
+#[derive(Default)]
+struct FlushSignal {
+    due: Mutex<bool>,
+    cv: Condvar,
+}
+
+impl FlushSignal {
+    fn notify(&self) {
+        *self.due.lock() = true;
+        self.cv.notify_all();
+    }
+
+    // Returns once notified or after `timeout`, whichever comes first.
+    fn wait(&self, timeout: Duration) {
+        let mut due = self.due.lock();
+        if !*due { self.cv.wait_for(&mut due, timeout); }
+        *due = false;
+    }
+}
+
+impl Store {
+    pub fn new(cfg: &Config, manifest: ManifestLog) -> Self {
+        Self {
+            dir: PathBuf::from(&cfg.data_dir),
+            manifest: Mutex::new(manifest),
+            flush_bytes: (cfg.segment_size_mb as u64) << 20,
+            flush_age: Duration::from_secs(cfg.memtable_flush_secs),
+            codec: cfg.segment_codec,
+            flush_lock: Mutex::new(()),
+            compact_lock: Mutex::new(()),
+            signal: Arc::new(FlushSignal::default()),
+            last_error: Mutex::new(None),
+        }
+    }
+}
+
+impl Engine {
+    // Freezes the active memtable and writes every frozen memtable to a new
+    // row segment, oldest first. Returns the segments written.
+    pub fn flush(&self) -> Result<Vec<SegmentMeta>> {
+        let Some(store) = &self.store else { return Ok(Vec::new()) };
+        let _flushing = store.flush_lock.lock();
+        self.flush_locked(store)
+    }
+
+    // Flushes if the active memtable has outgrown `segment_size_mb` or is
+    // older than `memtable_flush_secs`, or an earlier flush failed. Returns
+    // immediately if another flush is running.
+    pub fn maybe_flush(&self) -> Result<Vec<SegmentMeta>> {
+        let Some(store) = &self.store else { return Ok(Vec::new()) };
+        if !self.flush_due(store) { return Ok(Vec::new()); }
+        let Some(_flushing) = store.flush_lock.try_lock() else { return Ok(Vec::new()) };
+        self.flush_locked(store)
+    }
+
+    // Why the last flush by `spawn_flusher` failed; None once one succeeds.
+    pub fn flush_error(&self) -> Option<String> {
+        self.store.as_ref().and_then(|s| s.last_error.lock().clone())
+    }
+
+    // Called after every commit. The flush, and quantizing the vector index
+    // once it is due, run on the background flusher, never on the committing
+    // thread.
+    pub(crate) fn signal_flush(&self) {
+        if let Some(store) = &self.store {
+            if self.flush_due(store) || self.quantize_due() { store.signal.notify(); }
+        }
+    }
+
+    fn flush_due(&self, store: &Store) -> bool {
+        let mem = self.mem.read().clone();
+        let due = !mem.is_empty() && (mem.approx_bytes() >= store.flush_bytes || mem.age() >= store.flush_age);
+        due || !self.layers.read().frozen.is_empty()
+    }
+
+    fn flush_locked(&self, store: &Store) -> Result<Vec<SegmentMeta>> {
+        self.freeze();
+        let mut written = Vec::new();
+        loop {
+            let Some((mem, lsn)) = self.layers.read().frozen.first().map(|f| (f.mem.clone(), f.lsn)) else { break };
+            written.push(self.flush_frozen(store, &mem, lsn)?);
+        }
+        Ok(written)
+    }
+
+    // Swaps in an empty memtable. Commits are applied in log order under the
+    // commit lock, so the frozen table holds exactly the WAL up to `lsn`.
+    fn freeze(&self) {
+        let _commit = self.commit_lock.lock();
+        let mem = self.mem.read().clone();
+        if mem.is_empty() { return; }
+        let lsn = self.applied_lsn.load(std::sync::atomic::Ordering::Acquire);
+        // published before the swap, so readers never miss the frozen table
+        self.layers.write().frozen.push(Frozen { mem, lsn });
+        *self.mem.write() = Arc::new(MemTable::new());
+    }
+
+    fn flush_frozen(&self, store: &Store, mem: &Arc<MemTable>, lsn: Lsn) -> Result<SegmentMeta> {
+        // the segment must not get ahead of the log it replaces
+        if let Some(wal) = &self.wal { wal.wait_durable(lsn)?; }
+        let ids = self.vectors.read().version_ids();
+        let entries: Vec<RowSegmentEntry> = {
+            let index = self.vector_index();
+            mem.all_versions().into_iter().map(|version| {
+                let vector = match ids.get(&(version.row.key.clone(), version.begin_ts)) {
+                    Some(vid) => index.get(*vid)?,
+                    None => None,
+                };
+                Ok(RowSegmentEntry { version, vector })
+            }).collect::<Result<_>>()?
+        };
+        let mut manifest = store.manifest.lock();
+        let id = manifest.state().next_segment;
+        let file = format!("rows/{:06}.seg", id);
+        let seg = RowSegment::write(store.dir.join(&file), &entries, store.codec)?;
+        let m = seg.meta();
+        let meta = SegmentMeta {
+            id,
+            kind: SegmentKind::Row,
+            bytes: std::fs::metadata(seg.path())?.len(),
+            file,
+            rows: m.rows,
+            min_ts: m.min_ts,
+            max_ts: m.max_ts,
+            max_txn: m.max_txn,
+        };
+        let newest = manifest.state().segments.len();
+        manifest.apply(VersionEdit {
+            next_segment: Some(id + 1),
+            flushed_lsn: Some(lsn),
+            add_segments: vec![(newest, meta.clone())],
+            ..VersionEdit::default()
+        })?;
+        // layers change under the manifest lock, so the two always agree
+        {
+            let mut layers = self.layers.write();
+            layers.segments.push(Arc::new(seg));
+            layers.frozen.retain(|f| !Arc::ptr_eq(&f.mem, mem));
+        }
+        drop(manifest);
+        if let Some(wal) = &self.wal { wal.checkpoint(lsn)?; }
+        Ok(meta)
+    }
+}
+
+// Runs `Engine::maybe_flush` whenever a commit leaves the memtable over its
+// size budget, and every `every` so idle memtables still get flushed once
+// they reach `memtable_flush_secs`, until the engine is dropped. Failures
+// are kept for `Engine::flush_error` and retried on the next round. Each
+// round also runs `Engine::maybe_quantize_vectors` and
+// `maybe_save_vector_snapshot`, whose failures go to `Engine::vector_error`.
+pub fn spawn_flusher(engine: &Arc<Engine>, every: Duration) -> std::thread::JoinHandle<()> {
+    let weak: Weak<Engine> = Arc::downgrade(engine);
+    let signal = engine.store.as_ref().map(|s| s.signal.clone());
+    std::thread::spawn(move || loop {
+        match &signal {
+            Some(signal) => signal.wait(every),
+            None => std::thread::sleep(every),
+        }
+        let Some(engine) = weak.upgrade() else { break };
+        let res = engine.maybe_flush();
+        if let Some(store) = &engine.store {
+            *store.last_error.lock() = res.err().map(|e| e.to_string());
+        }
+        let saved = engine.maybe_quantize_vectors().and_then(|_| engine.maybe_save_vector_snapshot());
+        if let Err(e) = saved { *engine.vector_error.lock() = Some(e.to_string()); }
+    })
+}
@@ -0,0 +1,120 @@
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use serde::Serialize;
+use crate::types::Timestamp;
+use super::Engine;
+
+// GC rebuilds the vector index once more than this share of its nodes are
+// tombstones of reclaimed embeddings.
+const VACUUM_TOMBSTONE_RATIO: f64 = 0.25;
+
+// Reclaimed by one GC run, or in total (`Engine::gc_stats`).
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct GcStats {
+    pub runs: u64,
+    // versions that ended at or before this ts were eligible
+    pub horizon: Timestamp,
+    pub versions_reclaimed: u64,
+    pub bytes_reclaimed: u64,
+    pub vectors_reclaimed: u64,
+}
+
+// Read timestamps of open transactions. `watermark` is the highest GC horizon
+// so far; snapshots older than it can no longer be served.
+#[derive(Default)]
+pub(crate) struct Snapshots {
+    active: BTreeMap<Timestamp, usize>,
+    pub(crate) watermark: Timestamp,
+}
+
+impl Snapshots {
+    pub(crate) fn register(&mut self, ts: Timestamp) {
+        *self.active.entry(ts).or_default() += 1;
+    }
+
+    pub(crate) fn release(&mut self, ts: Timestamp) {
+        if let Some(n) = self.active.get_mut(&ts) {
+            *n -= 1;
+            if *n == 0 { self.active.remove(&ts); }
+        }
+    }
+
+    pub(crate) fn oldest(&self) -> Option<Timestamp> { self.active.keys().next().copied() }
+}
+
+impl Engine {
+    // Oldest read timestamp any open transaction may still use.
+    pub fn oldest_active_snapshot(&self) -> Timestamp {
+        let now = *self.now.read();
+        self.snapshots.lock().oldest().map_or(now, |ts| ts.min(now))
+    }
+
+    // Prunes row versions and embeddings that ended at or before the oldest
+    // active snapshot; no current or future reader can see them.
+    pub fn gc(&self) -> GcStats {
+        let horizon = self.advance_gc_horizon();
+        // tombstones must keep shadowing older versions in frozen or flushed layers
+        let keep_tombstones = {
+            let layers = self.layers.read();
+            !layers.frozen.is_empty() || !layers.segments.is_empty()
+        };
+        let (versions, mut bytes) = self.mem.read().clone().gc(horizon, keep_tombstones);
+        let vids = self.vectors.write().gc(horizon);
+        self.text_index.write().gc(horizon);
+        let index = self.vector_index();
+        // what a node holds in memory
+        let vector_bytes = index.quantizer().map_or(index.dims * std::mem::size_of::<f32>(), |q| q.code_len());
+        for vid in &vids {
+            if index.remove(*vid) { bytes += vector_bytes as u64; }
+        }
+        // a failed quantization or vacuum leaves the index as it was, is
+        // kept for `vector_error` and retried by the next run; quantizing
+        // drops the tombstones too
+        let quantized = self.maybe_quantize_vectors().unwrap_or_else(|e| {
+            *self.vector_error.lock() = Some(e.to_string());
+            false
+        });
+        if !quantized && index.tombstone_ratio() > VACUUM_TOMBSTONE_RATIO {
+            // rebuilt without blocking searches or commits
+            let seen = index.mutations();
+            let vacuumed = index.vacuumed()
+                .and_then(|fresh| self.replace_vector_index(&index, seen, fresh))
+                .and_then(|_| self.maybe_save_vector_snapshot());
+            if let Err(e) = vacuumed { *self.vector_error.lock() = Some(e.to_string()); }
+        }
+        let run = GcStats { runs: 1, horizon, versions_reclaimed: versions, bytes_reclaimed: bytes, vectors_reclaimed: vids.len() as u64 };
+        let mut total = self.gc_totals.lock();
+        total.runs += 1;
+        total.horizon = horizon;
+        total.versions_reclaimed += run.versions_reclaimed;
+        total.bytes_reclaimed += run.bytes_reclaimed;
+        total.vectors_reclaimed += run.vectors_reclaimed;
+        run
+    }
+
+    pub fn gc_stats(&self) -> GcStats { *self.gc_totals.lock() }
+
+    // Moves the watermark up to the oldest active snapshot and returns it.
+    // Versions that ended at or before the result may be discarded.
+    pub(crate) fn advance_gc_horizon(&self) -> Timestamp {
+        let mut snaps = self.snapshots.lock();
+        let now = *self.now.read();
+        let horizon = snaps.oldest().map_or(now, |ts| ts.min(now)).max(snaps.watermark);
+        snaps.watermark = horizon;
+        horizon
+    }
+}
+
+// Runs `Engine::gc` every `every` until the engine is dropped.
+pub fn spawn_gc(engine: &Arc<Engine>, every: Duration) -> std::thread::JoinHandle<()> {
+    let weak: Weak<Engine> = Arc::downgrade(engine);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(every);
+        match weak.upgrade() {
+            Some(engine) => { engine.gc(); }
+            None => break,
+        }
+    })
+}
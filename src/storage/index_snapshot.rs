@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::{bail, Result};
+use crate::types::{RowKey, Timestamp, Vector};
+use crate::vector::hnsw::{HnswIndex, Snapshot};
+use super::Engine;
+use super::manifest::{IndexMeta, Manifest, VersionEdit};
+
+// The engine's own vector index, saved once it is quantized so a restart
+// loads its codes instead of rebuilding the graph and training again. Not a
+// name `save_index` takes.
+pub const VECTOR_SNAPSHOT: &str = "_vectors";
+// share of the vector index that has to change before the background
+// flusher saves it again
+const VECTOR_SNAPSHOT_CHURN: f64 = 0.1;
+
+// The engine's vector snapshot while `Engine::open` rebuilds the catalog.
+pub(crate) struct VectorRestore {
+    // snapshot id of each row version's vector
+    by_version: HashMap<(RowKey, Timestamp), u64>,
+    // catalog id of each snapshot id, for the versions seen so far
+    renamed: HashMap<u64, u64>,
+    // vectors of versions the snapshot does not hold, by catalog id
+    newer: Vec<(u64, Vector)>,
+}
+
+impl VectorRestore {
+    // Takes the vector of the version `key`@`ts`, now catalog id `vid`.
+    pub fn take(&mut self, vid: u64, key: &RowKey, ts: Timestamp, vec: Vector) {
+        match self.by_version.remove(&(key.clone(), ts)) {
+            Some(id) => { self.renamed.insert(id, vid); }
+            None => self.newer.push((vid, vec)),
+        }
+    }
+}
+
+impl Engine {
+    // Saves `index` as the snapshot called `name` under data_dir/indexes/ and
+    // records it in the manifest, replacing the previous one. Names are
+    // [A-Za-z0-9_-]+. Fails on an in-memory engine.
+    pub fn save_index(&self, name: &str, index: &HnswIndex) -> Result<IndexMeta> {
+        if name == VECTOR_SNAPSHOT { bail!("index name {} is reserved for the engine's vector index", name); }
+        self.write_index(name, index, false)
+    }
+
+    // With `versions`, the row version of each saved vector goes to an .ids
+    // file next to the snapshot.
+    fn write_index(&self, name: &str, index: &HnswIndex, versions: bool) -> Result<IndexMeta> {
+        let Some(store) = &self.store else { bail!("in-memory engine has no data_dir to save index {} to", name) };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            bail!("invalid index name {:?}", name);
+        }
+        let mut manifest = store.manifest.lock();
+        let id = manifest.state().next_segment;
+        let stem = format!("indexes/{}-{:06}", name, id);
+        let file = format!("{}.hnsw", stem);
+        let path = store.dir.join(&file);
+        let live = index.save_snapshot(&path)?;
+        let mut files = Vec::new();
+        if index.is_quantized() { files.push(format!("{}.f32", stem)); }
+        if versions {
+            let ids = format!("{}.ids", stem);
+            let listed: Vec<(u64, RowKey, Timestamp)> = {
+                let catalog = self.vectors.read();
+                live.into_iter().filter_map(|id| catalog.get(id).map(|v| (id, v.key.clone(), v.begin_ts))).collect()
+            };
+            let mut f = std::fs::File::create(store.dir.join(&ids))?;
+            f.write_all(&bincode::serialize(&listed)?)?;
+            f.sync_all()?;
+            super::wal::sync_dir(path.parent().unwrap_or(&store.dir))?;
+            files.push(ids);
+        }
+        let meta = IndexMeta { name: name.to_string(), bytes: std::fs::metadata(&path)?.len(), file, vectors: index.len(), as_of: *self.now.read(), files };
+        let old = manifest.state().indexes.iter().find(|i| i.name == name).cloned();
+        manifest.apply(VersionEdit { next_segment: Some(id + 1), add_indexes: vec![meta.clone()], ..VersionEdit::default() })?;
+        drop(manifest);
+        for old in old.iter().flat_map(|o| o.all_files()) {
+            match std::fs::remove_file(store.dir.join(old)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(meta)
+    }
+
+    // The snapshot saved as `name`, if the manifest lists one.
+    pub fn load_index(&self, name: &str) -> Result<Option<HnswIndex>> {
+        let Some(store) = &self.store else { return Ok(None) };
+        let file = store.manifest.lock().state().indexes.iter().find(|i| i.name == name).map(|i| i.file.clone());
+        file.map(|f| HnswIndex::load_from(store.dir.join(f))).transpose()
+    }
+
+    pub fn index_snapshots(&self) -> Vec<IndexMeta> {
+        self.store.as_ref().map_or_else(Vec::new, |s| s.manifest.lock().state().indexes.clone())
+    }
+
+    // Saves the engine's vector index as VECTOR_SNAPSHOT. Fails on an
+    // in-memory engine.
+    pub fn save_vector_snapshot(&self) -> Result<IndexMeta> {
+        let index = self.vector_index();
+        let seen = index.mutations();
+        let meta = self.write_index(VECTOR_SNAPSHOT, &index, true)?;
+        *self.vector_snapshot.lock() = (Arc::downgrade(&index), seen);
+        Ok(meta)
+    }
+
+    // Saves a quantized vector index of a durable engine if it has no
+    // snapshot yet, or VECTOR_SNAPSHOT_CHURN of it changed since the last
+    // one; true if it did. Run after quantizing, by `gc` after a vacuum and
+    // by the background flusher.
+    pub fn maybe_save_vector_snapshot(&self) -> Result<bool> {
+        let index = self.vector_index();
+        if self.store.is_none() || !index.is_quantized() { return Ok(false); }
+        let (last, seen) = self.vector_snapshot.lock().clone();
+        let changed = index.mutations().saturating_sub(seen) as f64;
+        if last.ptr_eq(&Arc::downgrade(&index)) && changed < VECTOR_SNAPSHOT_CHURN * index.len() as f64 { return Ok(false); }
+        self.save_vector_snapshot()?;
+        Ok(true)
+    }
+
+    // Reads the vector snapshot `manifest` lists, if any. Until
+    // `finish_vector_restore`, `index_vector` hands vectors to the restore
+    // instead of indexing them.
+    pub(crate) fn start_vector_restore(&self, data_dir: &Path, manifest: &Manifest) -> Result<Option<Snapshot>> {
+        let Some(meta) = manifest.indexes.iter().find(|i| i.name == VECTOR_SNAPSHOT) else { return Ok(None) };
+        let Some(ids) = meta.files.iter().find(|f| f.ends_with(".ids")) else { return Ok(None) };
+        let snapshot = Snapshot::read(&data_dir.join(&meta.file))?;
+        let listed: Vec<(u64, RowKey, Timestamp)> = bincode::deserialize(&std::fs::read(data_dir.join(ids))?)?;
+        *self.restoring.lock() = Some(VectorRestore {
+            by_version: listed.into_iter().map(|(id, key, ts)| ((key, ts), id)).collect(),
+            renamed: HashMap::new(),
+            newer: Vec::new(),
+        });
+        Ok(Some(snapshot))
+    }
+
+    // Builds `snapshot` with catalog ids, leaving out vectors of versions
+    // that are gone, adds the vectors newer than it and swaps it in.
+    pub(crate) fn finish_vector_restore(&self, snapshot: Snapshot) -> Result<()> {
+        let Some(restore) = self.restoring.lock().take() else { return Ok(()) };
+        let index = snapshot.build(&self.raw_vector_dir(), |id| restore.renamed.get(&id).copied())?;
+        let index = Arc::new(index);
+        *self.vector_snapshot.lock() = (Arc::downgrade(&index), index.mutations());
+        *self.vector_index.write() = index;
+        for (vid, vec) in restore.newer { self.store_vector(vid, vec); }
+        Ok(())
+    }
+}
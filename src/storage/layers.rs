@@ -0,0 +1,155 @@
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use anyhow::Result;
+use crate::types::{RowKey, Timestamp, TxnId, VersionedRow};
+use crate::mvcc::visible_at;
+use super::memtable::MemTable;
+use super::rowsegment::{RowSegmentEntry, RowSegmentMeta};
+use super::wal::Lsn;
+use super::predicate::{project_payload, Predicate};
+
+// Read access to an immutable on-disk segment, row- or column-oriented. A
+// segment holds a contiguous version chain per key, sorted by key and ts.
+pub trait SegmentReader: Send + Sync {
+    fn meta(&self) -> &RowSegmentMeta;
+    // begin_ts of the newest version of `key` in this segment
+    fn latest_ts(&self, key: &RowKey) -> Option<Timestamp>;
+    // The version of `key` visible at `ts`, tombstones included. Only the
+    // newest version that began at or before `ts` can qualify.
+    fn visible_version(&self, key: &RowKey, ts: Timestamp) -> Result<Option<VersionedRow>>;
+    // every version of `key`, oldest first
+    fn versions(&self, key: &RowKey) -> Result<Vec<VersionedRow>>;
+    fn iter(&self) -> Result<Vec<RowSegmentEntry>>;
+    // Same entries as `iter`, read a block or page at a time.
+    fn stream(&self) -> Result<Box<dyn Iterator<Item = Result<RowSegmentEntry>> + '_>>;
+    // False if the segment's stats prove no version in it matches `pred`.
+    fn may_match(&self, _pred: &Predicate) -> bool { true }
+    // Versions visible at `ts`, tombstones included. Implementations may
+    // skip versions that cannot match `pred` and leave out payload fields
+    // not named in `projection` ("payload.<field>"); callers re-check `pred`.
+    fn scan(&self, ts: Timestamp, _pred: &Predicate, _projection: Option<&[String]>) -> Result<Vec<VersionedRow>> {
+        Ok(self.iter()?.into_iter().map(|e| e.version).filter(|v| visible_at(v, ts)).collect())
+    }
+    // Keys with a version visible at `ts`; they shadow older layers even
+    // when the segment itself is skipped.
+    fn shadowed_keys(&self, ts: Timestamp) -> Vec<&str>;
+}
+
+// Segments read and skipped by predicate scans since the engine started.
+#[derive(Clone, Copy, Debug, Default, serde::Serialize)]
+pub struct ScanStats {
+    pub segments_scanned: u64,
+    pub segments_pruned: u64,
+}
+
+// A memtable that no longer takes writes and is waiting to be flushed.
+pub(crate) struct Frozen {
+    pub mem: Arc<MemTable>,
+    // last WAL record applied to `mem`
+    pub lsn: Lsn,
+}
+
+// Everything older than the active memtable, oldest first.
+#[derive(Default)]
+pub(crate) struct Layers {
+    pub frozen: Vec<Frozen>,
+    pub segments: Vec<Arc<dyn SegmentReader>>,
+}
+
+// A consistent set of layers to read through, newest first. Every version in
+// a layer began after every version in the layers below it, so the first
+// layer with a visible version of a key decides what a reader sees.
+pub(crate) struct View {
+    pub mems: Vec<Arc<MemTable>>,
+    pub segments: Vec<Arc<dyn SegmentReader>>,
+}
+
+impl View {
+    pub fn visible_version(&self, key: &RowKey, ts: Timestamp) -> Result<Option<VersionedRow>> {
+        for m in &self.mems {
+            if let Some(v) = m.visible_version(key, ts) { return Ok(Some(v)); }
+        }
+        for s in &self.segments {
+            if let Some(v) = s.visible_version(key, ts)? { return Ok(Some(v)); }
+        }
+        Ok(None)
+    }
+
+    // Visible version of every key, tombstones included, in key order.
+    pub fn visible_versions(&self, ts: Timestamp) -> Result<Vec<VersionedRow>> {
+        let mut out: BTreeMap<String, VersionedRow> = BTreeMap::new();
+        for s in self.segments.iter().rev() {
+            for e in s.iter()? {
+                if visible_at(&e.version, ts) { out.insert(e.version.row.key.0.clone(), e.version); }
+            }
+        }
+        for m in self.mems.iter().rev() {
+            for v in m.visible_versions(ts) { out.insert(v.row.key.0.clone(), v); }
+        }
+        Ok(out.into_values().collect())
+    }
+
+    // Visible live versions matching `pred`, in key order, with the payload
+    // cut down to `projection` when given. Segments whose stats rule the
+    // predicate out are not read; their keys only hide the older versions
+    // they shadow.
+    pub fn scan_where(&self, ts: Timestamp, pred: &Predicate, projection: Option<&[String]>, stats: &mut ScanStats) -> Result<Vec<VersionedRow>> {
+        let mut out: BTreeMap<String, VersionedRow> = BTreeMap::new();
+        for s in self.segments.iter().rev() {
+            for key in s.shadowed_keys(ts) { out.remove(key); }
+            if s.may_match(pred) {
+                stats.segments_scanned += 1;
+                for v in s.scan(ts, pred, projection)? { out.insert(v.row.key.0.clone(), v); }
+            } else {
+                stats.segments_pruned += 1;
+            }
+        }
+        for m in self.mems.iter().rev() {
+            for v in m.visible_versions(ts) { out.insert(v.row.key.0.clone(), v); }
+        }
+        Ok(out.into_values().filter(|v| !v.deleted && pred.matches(v)).map(|mut v| {
+            if let Some(cols) = projection { project_payload(&mut v.row.payload, cols); }
+            v
+        }).collect())
+    }
+
+    // Every retained version in every layer, tombstones included.
+    pub fn all_versions(&self) -> Result<Vec<VersionedRow>> {
+        let mut out = Vec::new();
+        for s in &self.segments { out.extend(s.iter()?.into_iter().map(|e| e.version)); }
+        for m in &self.mems { out.extend(m.all_versions()); }
+        Ok(out)
+    }
+
+    // Every retained version that began after `ts`. Layers are newest
+    // first, so only the segments above `ts` are read.
+    pub fn versions_since(&self, ts: Timestamp) -> Result<Vec<VersionedRow>> {
+        let mut out: Vec<VersionedRow> = self.mems.iter().flat_map(|m| m.all_versions()).filter(|v| v.begin_ts > ts).collect();
+        for s in self.segments.iter().take_while(|s| s.meta().max_ts > ts) {
+            out.extend(s.iter()?.into_iter().map(|e| e.version).filter(|v| v.begin_ts > ts));
+        }
+        Ok(out)
+    }
+
+    pub fn latest_ts(&self, key: &RowKey) -> Option<Timestamp> {
+        self.mems.iter().find_map(|m| m.latest_ts(key))
+            .or_else(|| self.segments.iter().find_map(|s| s.latest_ts(key)))
+    }
+
+    pub fn commit_ts_of(&self, txn_id: TxnId) -> Result<Option<Timestamp>> {
+        if let Some(ts) = self.mems.iter().find_map(|m| m.commit_ts_of(txn_id)) { return Ok(Some(ts)); }
+        for s in self.segments.iter().filter(|s| s.meta().max_txn >= txn_id) {
+            if let Some(e) = s.iter()?.into_iter().find(|e| e.version.txn_id == txn_id) { return Ok(Some(e.version.begin_ts)); }
+        }
+        Ok(None)
+    }
+
+    // Every retained version of `key`, oldest first.
+    pub fn versions(&self, key: &RowKey) -> Result<Vec<VersionedRow>> {
+        let mut out = Vec::new();
+        for s in self.segments.iter().rev() { out.extend(s.versions(key)?); }
+        for m in self.mems.iter().rev() { out.extend(m.versions(key)); }
+        Ok(out)
+    }
+}
@@ -0,0 +1,331 @@
+use serde::{Serialize, Deserialize};
+use anyhow::{anyhow, bail, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crate::types::{Timestamp, TxnId};
+use super::wal::Lsn;
+
+// On-disk layout this build reads and writes. 1 was a single MANIFEST.json
+// with uncompressed segments; 2 adds the edit log and compressed blocks.
+pub const FORMAT_VERSION: u32 = 2;
+
+const CURRENT_FILE: &str = "CURRENT";
+const MANIFEST_PREFIX: &str = "MANIFEST-";
+const LEGACY_FILE: &str = "MANIFEST.json";
+// Edits appended to one manifest file before it is rewritten as a snapshot.
+const ROLL_EDITS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    // written by a memtable flush
+    #[default]
+    Row,
+    // written by the compactor
+    Column,
+}
+
+// One live segment, oldest first in `Manifest::segments`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SegmentMeta {
+    pub id: u64,
+    #[serde(default)]
+    pub kind: SegmentKind,
+    // relative to data_dir
+    pub file: String,
+    pub rows: u64,
+    pub bytes: u64,
+    pub min_ts: Timestamp,
+    pub max_ts: Timestamp,
+    pub max_txn: TxnId,
+}
+
+// A saved vector index, at most one live snapshot per name.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexMeta {
+    pub name: String,
+    // relative to data_dir
+    pub file: String,
+    pub bytes: u64,
+    pub vectors: usize,
+    // engine `now` when the snapshot was taken
+    pub as_of: Timestamp,
+    // other files the snapshot reads, relative to data_dir: the raw vectors
+    // of a quantized index, and for the engine's own vector index the row
+    // version of each vector
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+impl IndexMeta {
+    pub fn all_files(&self) -> impl Iterator<Item = &str> + '_ {
+        std::iter::once(self.file.as_str()).chain(self.files.iter().map(String::as_str))
+    }
+}
+
+// A secondary index over a JSON path of the payload (`CREATE INDEX`).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FieldIndexDef {
+    pub name: String,
+    // "$.customer_id", "$.ticket.status"
+    pub path: String,
+}
+
+fn legacy_format() -> u32 { 1 }
+
+// What makes up the database under data_dir: live segments, index
+// snapshots, and the WAL position they cover. Files under rows/, cols/ and
+// indexes/ that are not listed here are garbage from a crash.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    #[serde(default = "legacy_format")]
+    pub format_version: u32,
+    // bumped whenever catalog definitions change
+    #[serde(default)]
+    pub schema_version: u64,
+    // next file number for segments and index snapshots
+    pub next_segment: u64,
+    // every WAL record up to here is contained in `segments`
+    pub flushed_lsn: Lsn,
+    pub segments: Vec<SegmentMeta>,
+    #[serde(default)]
+    pub indexes: Vec<IndexMeta>,
+    #[serde(default)]
+    pub field_indexes: Vec<FieldIndexDef>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            schema_version: 0,
+            next_segment: 0,
+            flushed_lsn: 0,
+            segments: Vec::new(),
+            indexes: Vec::new(),
+            field_indexes: Vec::new(),
+        }
+    }
+}
+
+// One change to the manifest. Removals apply before additions; each added
+// segment is inserted at its position in the (oldest first) segment list.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VersionEdit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format_version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_segment: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flushed_lsn: Option<Lsn>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove_segments: Vec<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add_segments: Vec<(usize, SegmentMeta)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove_indexes: Vec<String>,
+    // replaces any snapshot with the same name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add_indexes: Vec<IndexMeta>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drop_field_indexes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add_field_indexes: Vec<FieldIndexDef>,
+}
+
+impl Manifest {
+    // The state recorded under `dir`: the manifest named by CURRENT, a
+    // format-1 MANIFEST.json, or an empty manifest for a fresh directory.
+    pub fn load(dir: &Path) -> Result<Self> {
+        match std::fs::read_to_string(dir.join(CURRENT_FILE)) {
+            Ok(name) => {
+                let name = name.trim();
+                if !name.starts_with(MANIFEST_PREFIX) { bail!("CURRENT names {:?}, not a manifest", name); }
+                let data = std::fs::read(dir.join(name))?;
+                let mut m = Self { format_version: 0, ..Self::default() };
+                for edit in read_edits(&data)? { m.apply(&edit); }
+                if m.format_version == 0 { bail!("manifest {} has no snapshot record", name); }
+                Ok(m)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => match std::fs::read(dir.join(LEGACY_FILE)) {
+                Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn apply(&mut self, edit: &VersionEdit) {
+        if let Some(v) = edit.format_version { self.format_version = v; }
+        if let Some(v) = edit.schema_version { self.schema_version = v; }
+        if let Some(v) = edit.next_segment { self.next_segment = v; }
+        if let Some(v) = edit.flushed_lsn { self.flushed_lsn = v; }
+        self.segments.retain(|s| !edit.remove_segments.contains(&s.id));
+        for (pos, meta) in &edit.add_segments {
+            self.segments.insert((*pos).min(self.segments.len()), meta.clone());
+        }
+        self.indexes.retain(|i| !edit.remove_indexes.contains(&i.name) && !edit.add_indexes.iter().any(|a| a.name == i.name));
+        self.indexes.extend(edit.add_indexes.iter().cloned());
+        self.field_indexes.retain(|d| !edit.drop_field_indexes.contains(&d.name));
+        self.field_indexes.extend(edit.add_field_indexes.iter().cloned());
+    }
+
+    // A single edit that recreates this state from nothing.
+    fn snapshot(&self) -> VersionEdit {
+        VersionEdit {
+            format_version: Some(self.format_version),
+            schema_version: Some(self.schema_version),
+            next_segment: Some(self.next_segment),
+            flushed_lsn: Some(self.flushed_lsn),
+            remove_segments: Vec::new(),
+            add_segments: self.segments.iter().cloned().enumerate().collect(),
+            remove_indexes: Vec::new(),
+            add_indexes: self.indexes.clone(),
+            drop_field_indexes: Vec::new(),
+            add_field_indexes: self.field_indexes.clone(),
+        }
+    }
+
+    // Every file under data_dir this manifest keeps alive.
+    pub fn is_listed(&self, file: &str) -> bool {
+        self.segments.iter().any(|s| s.file == file) || self.indexes.iter().any(|i| i.all_files().any(|f| f == file))
+    }
+}
+
+// Manifest files are a log of edits (LevelDB style), the first being a
+// snapshot of the whole state; CURRENT names the live file.
+//
+// Record layout (little endian):
+//   len: u32 | checksum: u32 | body: [u8; len]   (body = JSON VersionEdit)
+// A torn record at the tail is an edit that never completed and is ignored.
+pub struct ManifestLog {
+    dir: PathBuf,
+    state: Manifest,
+    number: u64,
+    file: File,
+    edits: usize,
+    // a failed append may have left a torn record, which would hide every
+    // later edit on load; nothing more is appended to `file` until a new
+    // manifest is started
+    poisoned: bool,
+}
+
+impl ManifestLog {
+    // Loads the state under `dir` and starts a fresh manifest file with it.
+    // Fails on a format this build cannot read.
+    pub fn open(dir: &Path) -> Result<Self> {
+        let mut state = Manifest::load(dir)?;
+        if state.format_version > FORMAT_VERSION {
+            bail!("{} uses on-disk format {}, this build reads up to {}", dir.display(), state.format_version, FORMAT_VERSION);
+        }
+        if state.format_version < FORMAT_VERSION && (!state.segments.is_empty() || !state.indexes.is_empty()) {
+            bail!("{} uses on-disk format {}; this build only reads format {}", dir.display(), state.format_version, FORMAT_VERSION);
+        }
+        state.format_version = FORMAT_VERSION;
+        let number = current_number(dir)?.map_or(1, |n| n + 1);
+        let file = Self::start(dir, number, &state)?;
+        let log = Self { dir: dir.to_path_buf(), state, number, file, edits: 0, poisoned: false };
+        log.remove_stale()?;
+        Ok(log)
+    }
+
+    pub fn state(&self) -> &Manifest { &self.state }
+    pub fn file_name(&self) -> String { manifest_name(self.number) }
+
+    // Durably appends `edit`, then applies it. On error the in-memory state
+    // is unchanged, and the edit is left out of the manifest files: the log
+    // moves on to a new file holding the state without it.
+    pub fn apply(&mut self, edit: VersionEdit) -> Result<()> {
+        if self.poisoned { self.roll()?; }
+        let record = encode_record(&edit)?;
+        if let Err(e) = self.file.write_all(&record).and_then(|_| self.file.sync_data()) {
+            self.poisoned = true;
+            // retried by the next edit if this fails too
+            let _ = self.roll();
+            return Err(e.into());
+        }
+        self.state.apply(&edit);
+        self.edits += 1;
+        if self.edits >= ROLL_EDITS { self.roll()?; }
+        Ok(())
+    }
+
+    // Continues in a new manifest file that starts with a snapshot of the
+    // current state.
+    fn roll(&mut self) -> Result<()> {
+        let file = Self::start(&self.dir, self.number + 1, &self.state)?;
+        self.file = file;
+        self.number += 1;
+        self.edits = 0;
+        self.poisoned = false;
+        self.remove_stale()
+    }
+
+    // Writes MANIFEST-<number> holding a snapshot of `state` and points
+    // CURRENT at it (temp file + rename).
+    fn start(dir: &Path, number: u64, state: &Manifest) -> Result<File> {
+        let path = dir.join(manifest_name(number));
+        let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(&path)?;
+        file.write_all(&encode_record(&state.snapshot())?)?;
+        file.sync_all()?;
+        let tmp = dir.join(format!("{}.tmp", CURRENT_FILE));
+        let mut f = File::create(&tmp)?;
+        writeln!(f, "{}", manifest_name(number))?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, dir.join(CURRENT_FILE))?;
+        super::wal::sync_dir(dir)?;
+        Ok(file)
+    }
+
+    // Older manifest files and the format-1 MANIFEST.json.
+    fn remove_stale(&self) -> Result<()> {
+        let live = self.file_name();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if (name.starts_with(MANIFEST_PREFIX) && name != live) || name == LEGACY_FILE {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn manifest_name(number: u64) -> String { format!("{}{:06}", MANIFEST_PREFIX, number) }
+
+fn current_number(dir: &Path) -> Result<Option<u64>> {
+    match std::fs::read_to_string(dir.join(CURRENT_FILE)) {
+        Ok(name) => name.trim().strip_prefix(MANIFEST_PREFIX).and_then(|n| n.parse().ok())
+            .map(Some).ok_or_else(|| anyhow!("CURRENT names {:?}, not a manifest", name.trim())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn encode_record(edit: &VersionEdit) -> Result<Vec<u8>> {
+    let body = serde_json::to_vec(edit)?;
+    let mut out = Vec::with_capacity(8 + body.len());
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&simd_adler32::adler32(&body.as_slice()).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+// Complete records up to the first torn or corrupt one.
+fn read_edits(data: &[u8]) -> Result<Vec<VersionEdit>> {
+    let mut edits = Vec::new();
+    let mut off = 0usize;
+    while data.len() - off >= 8 {
+        let len = u32::from_le_bytes(data[off..off + 4].try_into()?) as usize;
+        let sum = u32::from_le_bytes(data[off + 4..off + 8].try_into()?);
+        let Some(body) = data.get(off + 8..off + 8 + len) else { break };
+        if simd_adler32::adler32(&body) != sum { break; }
+        edits.push(serde_json::from_slice(body)?);
+        off += 8 + len;
+    }
+    Ok(edits)
+}
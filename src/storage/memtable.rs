@@ -1,35 +1,109 @@
 
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use parking_lot::RwLock;
-use crate::types::{RowKey, VersionedRow, Timestamp};
+use crate::types::{RowKey, VersionedRow, Timestamp, TxnId};
 use crate::mvcc::visible_at;
 
-#[derive(Default)]
 pub struct MemTable {
     // key -> versions (newest last)
     inner: RwLock<BTreeMap<String, Vec<VersionedRow>>>,
+    // serialized size of the retained versions, for the flush trigger
+    bytes: AtomicU64,
+    created: Instant,
+}
+
+impl Default for MemTable {
+    fn default() -> Self { Self::new() }
 }
 
 impl MemTable {
-    pub fn new() -> Self { Self { inner: RwLock::new(BTreeMap::new()) } }
+    pub fn new() -> Self { Self { inner: RwLock::new(BTreeMap::new()), bytes: AtomicU64::new(0), created: Instant::now() } }
+
+    pub fn is_empty(&self) -> bool { self.inner.read().is_empty() }
+    pub fn approx_bytes(&self) -> u64 { self.bytes.load(Ordering::Relaxed) }
+    pub fn age(&self) -> Duration { self.created.elapsed() }
 
+    // Appends a new version and closes the previous one at its begin_ts, so
+    // each key's versions form a contiguous [begin_ts, end_ts) chain.
     pub fn upsert(&self, v: VersionedRow) {
+        self.bytes.fetch_add(bincode::serialized_size(&v).unwrap_or(0), Ordering::Relaxed);
         let mut g = self.inner.write();
         let e = g.entry(v.row.key.0.clone()).or_default();
+        if let Some(prev) = e.last_mut() {
+            if prev.end_ts.is_none() { prev.end_ts = Some(v.begin_ts); }
+        }
         e.push(v);
     }
 
-    pub fn get_visible(&self, key: &RowKey, ts: Timestamp) -> Option<VersionedRow> {
+    // begin_ts of the newest version (live or tombstone) of `key`.
+    pub fn latest_ts(&self, key: &RowKey) -> Option<Timestamp> {
+        self.inner.read().get(&key.0).and_then(|vv| vv.last()).map(|v| v.begin_ts)
+    }
+
+    // Commit timestamp of transaction `txn_id`, if any of its versions is retained.
+    pub fn commit_ts_of(&self, txn_id: TxnId) -> Option<Timestamp> {
+        self.inner.read().values().flatten().find(|v| v.txn_id == txn_id).map(|v| v.begin_ts)
+    }
+
+    // Drops versions that ended at or before `horizon`, plus keys whose only
+    // remaining version is a tombstone at or before it, unless
+    // `keep_tombstones` (older layers may still hold the key). Returns the
+    // number of versions and the approximate bytes reclaimed.
+    pub fn gc(&self, horizon: Timestamp, keep_tombstones: bool) -> (u64, u64) {
+        let mut g = self.inner.write();
+        let (mut versions, mut bytes) = (0u64, 0u64);
+        let mut reclaim = |v: &VersionedRow| {
+            versions += 1;
+            bytes += bincode::serialized_size(v).unwrap_or(0);
+        };
+        g.retain(|_, vv| {
+            vv.retain(|v| {
+                let dead = v.end_ts.is_some_and(|e| e <= horizon);
+                if dead { reclaim(v); }
+                !dead
+            });
+            if let [only] = vv.as_slice() {
+                if only.deleted && only.begin_ts <= horizon && !keep_tombstones {
+                    reclaim(only);
+                    vv.clear();
+                }
+            }
+            !vv.is_empty()
+        });
+        self.bytes.fetch_sub(bytes.min(self.approx_bytes()), Ordering::Relaxed);
+        (versions, bytes)
+    }
+
+    // Every retained version of `key`, oldest first.
+    pub fn versions(&self, key: &RowKey) -> Vec<VersionedRow> {
+        self.inner.read().get(&key.0).cloned().unwrap_or_default()
+    }
+
+    // Every retained version, ordered by key and then begin_ts.
+    pub fn all_versions(&self) -> Vec<VersionedRow> {
+        self.inner.read().values().flatten().cloned().collect()
+    }
+
+    // The version of `key` visible at `ts`, tombstones included.
+    pub fn visible_version(&self, key: &RowKey, ts: Timestamp) -> Option<VersionedRow> {
         let g = self.inner.read();
-        g.get(&key.0).and_then(|versions| {
-            versions.iter().rev().find(|v| visible_at(v, ts)).cloned()
-        })
+        g.get(&key.0).and_then(|versions| versions.iter().rev().find(|v| visible_at(v, ts)).cloned())
     }
 
-    pub fn scan_visible(&self, ts: Timestamp) -> Vec<VersionedRow> {
+    // Visible version of every key, tombstones included, in key order.
+    pub fn visible_versions(&self, ts: Timestamp) -> Vec<VersionedRow> {
         let g = self.inner.read();
-        g.values().flat_map(|vv| {
-            vv.iter().rev().find(|v| visible_at(v, ts)).cloned()
-        }).collect()
+        g.values().flat_map(|vv| vv.iter().rev().find(|v| visible_at(v, ts)).cloned()).collect()
+    }
+
+    // Visible live version; a visible tombstone hides the key.
+    pub fn get_visible(&self, key: &RowKey, ts: Timestamp) -> Option<VersionedRow> {
+        self.visible_version(key, ts).filter(|v| !v.deleted)
+    }
+
+    pub fn scan_visible(&self, ts: Timestamp) -> Vec<VersionedRow> {
+        self.visible_versions(ts).into_iter().filter(|v| !v.deleted).collect()
     }
 }
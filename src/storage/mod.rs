@@ -3,55 +3,566 @@ pub mod wal;
 pub mod memtable;
 pub mod rowsegment;
 pub mod columnsegment;
+pub mod encoding;
 pub mod compactor;
+pub mod txn;
+pub mod vector_catalog;
+pub mod gc;
+pub mod manifest;
+pub mod layers;
+pub mod flush;
+pub mod predicate;
+pub mod compression;
+pub mod index_snapshot;
+pub mod secondary;
+pub mod backup;
+pub mod quantize;
 
-use crate::types::{Row, VersionedRow, Timestamp, TxnId, Vector};
-use crate::semantic::pipeline::Embedder;
+use crate::types::{AsOf, Row, RowKey, VersionedRow, Timestamp, TxnId, Vector};
+use crate::semantic::pipeline::{Embedder, HttpEmbedder, DummyEmbedder};
 use crate::semantic::{Olsp, HeuristicOlsp, OlspOutput};
-use crate::vector::flat::FlatIndex;
-use parking_lot::RwLock;
+use crate::vector::hnsw::HnswIndex;
+use crate::text::bm25::TextIndex;
+use crate::config::{Config, VectorQuantization};
+use wal::{Wal, WalOptions, WalRecord};
+use vector_catalog::VectorCatalog;
+use memtable::MemTable;
+use manifest::{Manifest, ManifestLog, SegmentKind};
+use rowsegment::RowSegment;
+use columnsegment::ColumnSegment;
+use layers::{Layers, ScanStats, SegmentReader, View};
+use predicate::Predicate;
+use flush::Store;
+use txn::{Transaction, PendingWrite, TxnError, TxnResult};
+use parking_lot::{RwLock, Mutex, Condvar};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use roaring::RoaringTreemap;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 pub struct Engine {
-    pub mem: memtable::MemTable,
-    pub flat_index: RwLock<FlatIndex>,
+    // active memtable; flushed ones move to `layers` until they are on disk
+    mem: RwLock<Arc<MemTable>>,
+    layers: RwLock<Layers>,
+    store: Option<Store>,
+    // one node per embedding version, keyed by the ids in `vectors`. Searches
+    // and inserts run concurrently; the lock only guards swapping in a
+    // vacuumed graph (see `gc`)
+    vector_index: RwLock<Arc<HnswIndex>>,
+    // when and how the vector index is quantized; see `quantize`
+    quantization: Option<VectorQuantization>,
+    // why the vector index last failed to store or rebuild; see `vector_error`
+    vector_error: Mutex<Option<String>>,
+    // the index the last vector snapshot was taken of, and its mutations
+    // then; see `index_snapshot`
+    vector_snapshot: Mutex<(Weak<HnswIndex>, u64)>,
+    // set while `open` replays onto a vector snapshot
+    restoring: Mutex<Option<index_snapshot::VectorRestore>>,
+    pub vectors: RwLock<VectorCatalog>,
     pub embedder: Box<dyn Embedder>,
+    // latest published commit timestamp; new snapshots read at this ts
     pub now: RwLock<Timestamp>,
     pub olsp: Box<dyn Olsp>,
+    wal: Option<Wal>,
+    recovery: RecoveryReport,
+    next_txn: AtomicU64,
+    // last assigned commit timestamp; held while a commit is ordered and applied
+    commit_lock: Mutex<Timestamp>,
+    // keys written by commits that are logged but not applied yet, with
+    // their commit timestamps; only touched under `commit_lock`
+    in_flight: Mutex<HashMap<RowKey, Timestamp>>,
+    // last WAL record applied to the memtables; see `flush::freeze`
+    applied_lsn: AtomicU64,
+    // commits are applied and publish `now` strictly in timestamp order
+    publish_lock: Mutex<()>,
+    publish_cv: Condvar,
+    snapshots: Mutex<gc::Snapshots>,
+    gc_totals: Mutex<gc::GcStats>,
+    scan_totals: Mutex<ScanStats>,
+    // secondary indexes by name; see `secondary`
+    field_indexes: RwLock<BTreeMap<String, secondary::FieldIndex>>,
+    // one doc per version of payload.text; see `text::bm25`
+    text_index: RwLock<TextIndex>,
+}
+
+// HNSW parameters of the engine's vector index.
+pub const VECTOR_M: usize = 16;
+pub const VECTOR_EF: usize = 64;
+
+// What `Engine::open` found in the WAL.
+#[derive(Clone, Debug, Default)]
+pub struct RecoveryReport {
+    pub records_replayed: usize,
+    pub discarded_bytes: u64,
 }
 
 impl Engine {
+    // In-memory engine without a WAL; nothing survives a restart.
     pub fn new(embedder: Box<dyn Embedder>, dims: usize) -> Self {
         Self {
-            mem: memtable::MemTable::new(),
-            flat_index: RwLock::new(FlatIndex::new(dims)),
+            mem: RwLock::new(Arc::new(MemTable::new())),
+            layers: RwLock::new(Layers::default()),
+            store: None,
+            vector_index: RwLock::new(Arc::new(HnswIndex::new(dims, VECTOR_M, VECTOR_EF))),
+            quantization: None,
+            vector_error: Mutex::new(None),
+            vector_snapshot: Mutex::new((Weak::new(), 0)),
+            restoring: Mutex::new(None),
+            vectors: RwLock::new(VectorCatalog::new()),
             embedder,
             now: RwLock::new(1),
             olsp: Box::new(HeuristicOlsp),
+            wal: None,
+            recovery: RecoveryReport::default(),
+            next_txn: AtomicU64::new(1),
+            commit_lock: Mutex::new(1),
+            in_flight: Mutex::new(HashMap::new()),
+            applied_lsn: AtomicU64::new(0),
+            publish_lock: Mutex::new(()),
+            publish_cv: Condvar::new(),
+            snapshots: Mutex::new(gc::Snapshots::default()),
+            gc_totals: Mutex::new(gc::GcStats::default()),
+            scan_totals: Mutex::new(ScanStats::default()),
+            field_indexes: RwLock::new(BTreeMap::new()),
+            text_index: RwLock::new(TextIndex::new()),
+        }
+    }
+
+    pub fn with_vector_quantization(mut self, quantization: VectorQuantization) -> Self {
+        self.quantization = Some(quantization);
+        self
+    }
+
+    // Durable engine: builds the embedder from `cfg.embedding` (dummy fallback)
+    // and recovers state from the row segments under `cfg.data_dir` plus the
+    // WAL under `cfg.wal_dir`.
+    pub fn open(cfg: Config) -> Result<Self> {
+        let embedder: Box<dyn Embedder> = match cfg.embedding.clone().map(|e| HttpEmbedder::new(e, cfg.vector_dims)) {
+            Some(Ok(e)) => Box::new(e),
+            _ => Box::new(DummyEmbedder::new("demo-mini", cfg.vector_dims)),
+        };
+        Self::open_with_embedder(cfg, embedder)
+    }
+
+    pub fn open_with_embedder(cfg: Config, embedder: Box<dyn Embedder>) -> Result<Self> {
+        let wal = Wal::open(PathBuf::from(&cfg.wal_dir), WalOptions::from_config(&cfg))?;
+        let mut engine = Self::new(embedder, cfg.vector_dims);
+        engine.quantization = cfg.vector_quantization;
+        let data_dir = PathBuf::from(&cfg.data_dir);
+        std::fs::create_dir_all(&data_dir)?;
+        let log = ManifestLog::open(&data_dir)?;
+        let manifest = log.state();
+        remove_unlisted_files(&data_dir, manifest)?;
+        // vectors the snapshot holds are not indexed again below
+        let vector_snapshot = engine.start_vector_restore(&data_dir, manifest)?;
+        let mut max_txn = 0;
+        let mut last_ts = 1;
+        for meta in &manifest.segments {
+            let path = data_dir.join(&meta.file);
+            let seg: Arc<dyn SegmentReader> = match meta.kind {
+                SegmentKind::Row => Arc::new(RowSegment::open(path)?),
+                SegmentKind::Column => Arc::new(ColumnSegment::open(path)?),
+            };
+            // segments are in commit order and sorted by key and ts inside,
+            // so this reproduces each key's embedding history
+            for e in seg.iter()? {
+                engine.index_vector(&e.version.row.key, e.version.begin_ts, e.vector);
+                engine.index_text(&e.version);
+            }
+            last_ts = last_ts.max(meta.max_ts);
+            max_txn = max_txn.max(meta.max_txn);
+            engine.layers.write().segments.push(seg);
+        }
+        let flushed_lsn = manifest.flushed_lsn;
+        let field_defs = manifest.field_indexes.clone();
+        engine.store = Some(Store::new(&cfg, log));
+        let replay = wal.replay::<WalRecord>()?;
+        engine.recovery = RecoveryReport { records_replayed: replay.entries.len(), discarded_bytes: replay.discarded_bytes };
+        // writes are buffered per transaction until their commit record shows
+        // up; transactions without one were torn off the tail and are dropped
+        let mut open_txns: HashMap<TxnId, Vec<WalRecord>> = HashMap::new();
+        // records already in a segment (a crash between manifest update and
+        // WAL checkpoint) are skipped
+        for entry in replay.entries.into_iter().filter(|e| e.lsn > flushed_lsn) {
+            match entry.record {
+                WalRecord::Commit { txn_id, commit_ts } => {
+                    engine.apply_commit(txn_id, commit_ts, open_txns.remove(&txn_id).unwrap_or_default());
+                    last_ts = last_ts.max(commit_ts);
+                    max_txn = max_txn.max(txn_id);
+                }
+                rec @ (WalRecord::Put { txn_id, .. } | WalRecord::Delete { txn_id, .. }) => {
+                    open_txns.entry(txn_id).or_default().push(rec);
+                    max_txn = max_txn.max(txn_id);
+                }
+            }
+        }
+        *engine.now.write() = last_ts;
+        *engine.commit_lock.lock() = last_ts;
+        engine.next_txn = AtomicU64::new(max_txn + 1);
+        engine.applied_lsn = AtomicU64::new(wal.next_lsn() - 1);
+        engine.wal = Some(wal);
+        if let Some(snapshot) = vector_snapshot { engine.finish_vector_restore(snapshot)?; }
+        {
+            let versions = engine.view().all_versions()?;
+            let mut indexes = engine.field_indexes.write();
+            for def in field_defs {
+                let mut index = secondary::FieldIndex::new(def)?;
+                for v in &versions { index.insert(v); }
+                indexes.insert(index.def.name.clone(), index);
+            }
+        }
+        engine.maybe_quantize_vectors()?;
+        Ok(engine)
+    }
+
+    pub fn recovery(&self) -> &RecoveryReport { &self.recovery }
+
+    // None for in-memory engines.
+    pub fn data_dir(&self) -> Option<&std::path::Path> { self.store.as_ref().map(|s| s.dir.as_path()) }
+
+    // The manifest as of now; in-memory engines have an empty one.
+    pub fn manifest(&self) -> Manifest {
+        self.store.as_ref().map_or_else(Manifest::default, |s| s.manifest.lock().state().clone())
+    }
+
+    pub fn begin(&self) -> Transaction<'_> {
+        let id = self.next_txn.fetch_add(1, Ordering::Relaxed);
+        // registered under the snapshot lock so GC cannot pass it meanwhile
+        let mut snaps = self.snapshots.lock();
+        let read_ts = *self.now.read();
+        snaps.register(read_ts);
+        Transaction::new(self, id, read_ts, false)
+    }
+
+    // Read-only transaction over the state as of `ts` (clamped to `now`).
+    // Fails with `SnapshotTooOld` once GC has pruned versions `ts` could see.
+    pub fn begin_as_of(&self, ts: Timestamp) -> TxnResult<Transaction<'_>> {
+        let id = self.next_txn.fetch_add(1, Ordering::Relaxed);
+        let mut snaps = self.snapshots.lock();
+        let read_ts = ts.min(*self.now.read());
+        if read_ts < snaps.watermark {
+            return Err(TxnError::SnapshotTooOld { ts: read_ts, horizon: snaps.watermark });
+        }
+        snaps.register(read_ts);
+        Ok(Transaction::new(self, id, read_ts, true))
+    }
+
+    pub(crate) fn release_snapshot(&self, read_ts: Timestamp) {
+        self.snapshots.lock().release(read_ts);
+    }
+
+    // Resolves a time-travel target to a read timestamp, clamped to `now`.
+    pub fn resolve_as_of(&self, as_of: AsOf) -> Result<Option<Timestamp>> {
+        let now = *self.now.read();
+        Ok(match as_of {
+            AsOf::Ts(ts) => Some(ts.min(now)),
+            AsOf::Txn(txn_id) => self.view().commit_ts_of(txn_id)?.filter(|&ts| ts <= now),
+        })
+    }
+
+    // The active memtable, frozen memtables and row segments, newest first.
+    pub(crate) fn view(&self) -> View {
+        let mut mems = vec![self.mem.read().clone()];
+        let layers = self.layers.read();
+        mems.extend(layers.frozen.iter().rev().map(|f| f.mem.clone()));
+        View { mems, segments: layers.segments.iter().rev().cloned().collect() }
+    }
+
+    // Visible live version of `key` at `ts`, from whichever layer holds it.
+    pub fn get_visible(&self, key: &RowKey, ts: Timestamp) -> Result<Option<VersionedRow>> {
+        Ok(self.view().visible_version(key, ts)?.filter(|v| !v.deleted))
+    }
+
+    pub fn scan_visible(&self, ts: Timestamp) -> Result<Vec<VersionedRow>> {
+        Ok(self.view().visible_versions(ts)?.into_iter().filter(|v| !v.deleted).collect())
+    }
+
+    // Like `scan_visible`, restricted to rows matching `pred`; segments whose
+    // zonemaps or bloom filters exclude it are skipped (see `scan_stats`).
+    pub fn scan_where(&self, ts: Timestamp, pred: &Predicate) -> Result<Vec<VersionedRow>> {
+        self.scan_projected(ts, pred, None)
+    }
+
+    // Like `scan_where`, keeping only the payload fields named in
+    // `projection` ("payload.<field>"). Column segments read just those
+    // columns plus the ones `pred` needs.
+    pub fn scan_projected(&self, ts: Timestamp, pred: &Predicate, projection: Option<&[String]>) -> Result<Vec<VersionedRow>> {
+        let mut stats = ScanStats::default();
+        let rows = self.view().scan_where(ts, pred, projection, &mut stats)?;
+        let mut total = self.scan_totals.lock();
+        total.segments_scanned += stats.segments_scanned;
+        total.segments_pruned += stats.segments_pruned;
+        Ok(rows)
+    }
+
+    pub fn scan_stats(&self) -> ScanStats { *self.scan_totals.lock() }
+
+    // Every retained version of `key` across all layers, oldest first.
+    pub fn versions(&self, key: &RowKey) -> Result<Vec<VersionedRow>> {
+        self.view().versions(key)
+    }
+
+    // Top-k rows by cosine similarity among the embeddings that were live at
+    // `read_ts`. Ids are `key_id(key)`, stable across versions and restarts.
+    // Approximate: the index walks past versions not visible at `read_ts`.
+    pub fn search_similar(&self, q: &Vector, k: usize, read_ts: Timestamp) -> Result<Vec<(u64, f32)>> {
+        self.search_similar_where(q, k, read_ts, |_| true)
+    }
+
+    // Like `search_similar`, among the rows whose `key_id` is in `allowed`.
+    pub fn search_similar_in(&self, q: &Vector, k: usize, read_ts: Timestamp, allowed: &RoaringTreemap) -> Result<Vec<(u64, f32)>> {
+        self.search_similar_where(q, k, read_ts, |id| allowed.contains(id))
+    }
+
+    // Like `search_similar`, among the rows whose `key_id` passes `keep`. The
+    // filter is checked while the index is walked, so up to k hits come back
+    // however few rows pass it.
+    pub fn search_similar_where(&self, q: &Vector, k: usize, read_ts: Timestamp, keep: impl Fn(u64) -> bool) -> Result<Vec<(u64, f32)>> {
+        Ok(self.similar_keys_where(q, k, read_ts, keep)?.into_iter().map(|(key, s)| (key_id(&key), s)).collect())
+    }
+
+    // Like `search_similar_in` (`search_similar` without `allowed`), with the
+    // hits' row keys. Key ids are 64-bit hashes that two keys can share, so
+    // a caller enforcing access checks the hits by key.
+    pub fn search_similar_keys(&self, q: &Vector, k: usize, read_ts: Timestamp, allowed: Option<&RoaringTreemap>) -> Result<Vec<(RowKey, f32)>> {
+        self.similar_keys_where(q, k, read_ts, |id| allowed.is_none_or(|a| a.contains(id)))
+    }
+
+    fn similar_keys_where(&self, q: &Vector, k: usize, read_ts: Timestamp, keep: impl Fn(u64) -> bool) -> Result<Vec<(RowKey, f32)>> {
+        let index = self.vector_index();
+        let hits = index.topk_filtered(q, k, index.ef, |vid| {
+            self.vectors.read().visible_at(vid, read_ts).is_some_and(|v| keep(key_id(&v.key)))
+        })?;
+        let vectors = self.vectors.read();
+        Ok(hits.into_iter()
+            .filter_map(|(vid, s)| vectors.get(vid).map(|v| (v.key.clone(), s)))
+            .collect())
+    }
+
+    // The index behind `search_similar`, ids as in `vectors`. Holding it
+    // does not block commits.
+    pub fn vector_index(&self) -> Arc<HnswIndex> { self.vector_index.read().clone() }
+
+    // Top-k rows by BM25 score for `query` against payload.text, among the
+    // versions visible at `read_ts`. Ids are `key_id(key)`.
+    pub fn search_text(&self, query: &str, k: usize, read_ts: Timestamp) -> Vec<(u64, f32)> {
+        self.text_index.read().search(query, k, read_ts).into_iter()
+            .map(|(key, s)| (key_id(&key), s))
+            .collect()
+    }
+
+    // Like `search_text`, among the rows whose `key_id` is in `allowed`.
+    pub fn search_text_in(&self, query: &str, k: usize, read_ts: Timestamp, allowed: &RoaringTreemap) -> Vec<(u64, f32)> {
+        self.search_text_keys(query, k, read_ts, Some(allowed)).into_iter()
+            .map(|(key, s)| (key_id(&key), s))
+            .collect()
+    }
+
+    // Like `search_text_in` (`search_text` without `allowed`), with the
+    // hits' row keys; see `search_similar_keys`.
+    pub fn search_text_keys(&self, query: &str, k: usize, read_ts: Timestamp, allowed: Option<&RoaringTreemap>) -> Vec<(RowKey, f32)> {
+        self.text_index.read().search_where(query, k, read_ts, |key| allowed.is_none_or(|a| a.contains(key_id(key))))
+    }
+
+    // Embedding of the key's latest version, if it has one.
+    pub fn current_vector(&self, key: &RowKey) -> Result<Option<Vector>> {
+        let Some(vid) = self.vectors.read().current(key) else { return Ok(None) };
+        self.vector_index().get(vid)
+    }
+
+    // Autocommit insert of a single row; returns its commit timestamp.
+    pub fn insert(&self, row: Row) -> Result<Timestamp> {
+        self.insert_batch(vec![row])
+    }
+
+    // Inserts several rows in one transaction, so bulk ingest pays one WAL
+    // group write (and fsync) per batch instead of one per row.
+    pub fn insert_batch(&self, rows: Vec<Row>) -> Result<Timestamp> {
+        let mut txn = self.begin();
+        for row in rows { txn.insert(row)?; }
+        Ok(txn.commit()?)
+    }
+
+    // Autocommit replacement of an existing row; fails with `NotFound` if
+    // `key` is not visible. Only re-embeds when `payload.text` changed.
+    pub fn update(&self, key: RowKey, mut row: Row) -> Result<Timestamp> {
+        row.key = key;
+        let mut txn = self.begin();
+        txn.update(row)?;
+        Ok(txn.commit()?)
+    }
+
+    // Autocommit delete; returns false if `key` was not visible.
+    pub fn delete(&self, key: &RowKey) -> Result<bool> {
+        let mut txn = self.begin();
+        if !txn.delete(key)? { return Ok(false); }
+        txn.commit()?;
+        Ok(true)
+    }
+
+    // in-kernel embedding for a demo column: payload["text"]
+    pub(crate) fn embed_row(&self, row: &Row) -> Option<Vector> {
+        let text = row.payload.get("text").and_then(|x| x.as_str())?;
+        let _olsp: OlspOutput = self.olsp.process(text);
+        Some(self.embedder.embed(text))
+    }
+
+    pub(crate) fn commit_txn(&self, txn_id: TxnId, read_ts: Timestamp, writes: BTreeMap<RowKey, PendingWrite>) -> TxnResult<Timestamp> {
+        if writes.is_empty() { return Ok(read_ts); }
+        let keys: Vec<RowKey> = writes.keys().cloned().collect();
+        let mut recs: Vec<WalRecord> = writes.into_iter().map(|(key, w)| match w {
+            PendingWrite::Put { row, vector } => WalRecord::Put { txn_id, row, vector },
+            PendingWrite::Delete => WalRecord::Delete { txn_id, key },
+        }).collect();
+        let (commit_ts, lsn) = {
+            // ordering and logging happen under the commit lock; the fsync
+            // does not, so concurrent commits share one group write
+            let mut last = self.commit_lock.lock();
+            // every commit is applied or in flight under this lock, so
+            // anything committed after our snapshot is in one or the other
+            let view = self.view();
+            let mut in_flight = self.in_flight.lock();
+            for key in &keys {
+                if let Some(committed_ts) = in_flight.get(key).copied().or_else(|| view.latest_ts(key)) {
+                    if committed_ts > read_ts {
+                        return Err(TxnError::WriteConflict { key: key.0.clone(), read_ts, committed_ts });
+                    }
+                }
+            }
+            let commit_ts = *last + 1;
+            recs.push(WalRecord::Commit { txn_id, commit_ts });
+            let lsn = match &self.wal {
+                Some(wal) => Some(wal.enqueue_all(recs.iter().map(|r| (r.record_type(), r)))?),
+                None => None,
+            };
+            *last = commit_ts;
+            for key in &keys { in_flight.insert(key.clone(), commit_ts); }
+            (commit_ts, lsn)
+        };
+        // nothing is applied before it is durable, so a failed WAL write
+        // leaves no version behind that recovery would not have
+        let durable = match (&self.wal, lsn) {
+            (Some(wal), Some(lsn)) => wal.wait_durable(lsn),
+            _ => Ok(()),
+        };
+        self.publish(commit_ts, || {
+            if durable.is_ok() {
+                self.apply_commit(txn_id, commit_ts, recs);
+                if let Some(lsn) = lsn { self.applied_lsn.store(lsn, Ordering::Release); }
+            }
+            let mut in_flight = self.in_flight.lock();
+            for key in &keys { in_flight.remove(key); }
+        });
+        durable?;
+        self.signal_flush();
+        Ok(commit_ts)
+    }
+
+    // Runs `apply` and publishes `commit_ts` once every earlier commit has
+    // been, so the memtables always hold a prefix of the log. A commit whose
+    // WAL write failed still publishes its timestamp, with nothing applied,
+    // so the ones behind it are not stuck.
+    fn publish(&self, commit_ts: Timestamp, apply: impl FnOnce()) {
+        let mut g = self.publish_lock.lock();
+        while *self.now.read() + 1 != commit_ts {
+            self.publish_cv.wait(&mut g);
         }
+        {
+            let _commits = self.commit_lock.lock();
+            apply();
+        }
+        *self.now.write() = commit_ts;
+        self.publish_cv.notify_all();
     }
 
-    fn next_ts(&self) -> Timestamp {
-        let mut g = self.now.write();
-        *g += 1;
-        *g
+    // Applies a committed transaction's logged writes to the in-memory
+    // structures. Shared by the commit path and WAL replay so both produce
+    // identical state.
+    fn apply_commit(&self, txn_id: TxnId, commit_ts: Timestamp, recs: Vec<WalRecord>) {
+        let mem = self.mem.read().clone();
+        for rec in recs {
+            match rec {
+                WalRecord::Put { row, vector, .. } => {
+                    self.index_vector(&row.key, commit_ts, vector);
+                    let version = VersionedRow { begin_ts: commit_ts, end_ts: None, txn_id, row, deleted: false };
+                    self.index_fields(&version);
+                    self.index_text(&version);
+                    mem.upsert(version);
+                }
+                WalRecord::Delete { key, .. } => {
+                    self.index_vector(&key, commit_ts, None);
+                    let row = Row { key, payload: serde_json::Value::Null };
+                    let version = VersionedRow { begin_ts: commit_ts, end_ts: None, txn_id, row, deleted: true };
+                    self.index_text(&version);
+                    mem.upsert(version);
+                }
+                WalRecord::Commit { .. } => {}
+            }
+        }
     }
 
-    pub fn insert(&self, txn: TxnId, row: Row) {
-        let ts = self.next_ts();
-        let vrow = VersionedRow { begin_ts: ts, end_ts: None, txn_id: txn, row: row.clone() };
-        self.mem.upsert(vrow);
-        // in-kernel embedding + vector insert for a demo column: payload["text"]
-        if let Some(text) = row.payload.get("text").and_then(|x| x.as_str()) {
-            let vec: Vector = self.embedder.embed(text);
-            self.flat_index.write().add(self.hash_key(&row.key.0), vec);
-            let _olsp: OlspOutput = self.olsp.process(text);
+    // Makes `vector` the key's embedding from `ts` on, or ends the current one
+    // if there is none. Superseded embeddings are closed, not removed, so
+    // AS OF searches can still see them.
+    fn index_vector(&self, key: &RowKey, ts: Timestamp, vector: Option<Vector>) {
+        match vector {
+            Some(vec) => {
+                let vid = self.vectors.write().open(key.clone(), ts);
+                if let Some(restore) = self.restoring.lock().as_mut() {
+                    restore.take(vid, key, ts, vec);
+                    return;
+                }
+                // after the catalog lock is released: searches check
+                // visibility while they walk the graph
+                self.store_vector(vid, vec);
+            }
+            None => self.vectors.write().close(key, ts),
         }
     }
 
-    fn hash_key(&self, k: &str) -> u64 {
-        use std::hash::{Hash, Hasher};
-        let mut h = ahash::AHasher::default();
-        k.hash(&mut h);
-        h.finish()
+    // The commit is already durable, so a vector the raw file cannot take is
+    // kept uncoded and the failure reported by `vector_error`.
+    fn store_vector(&self, vid: u64, vec: Vector) {
+        let index = self.vector_index();
+        if let Err(e) = index.add(vid, vec.clone()) {
+            *self.vector_error.lock() = Some(e.to_string());
+            index.add_uncoded(vid, vec);
+        }
+    }
+
+    // Same for the key's text: a version without payload.text, or a
+    // tombstone, ends the current doc.
+    fn index_text(&self, v: &VersionedRow) {
+        let mut text = self.text_index.write();
+        match v.row.payload.get("text").and_then(|t| t.as_str()).filter(|_| !v.deleted) {
+            Some(t) => text.add(v.row.key.clone(), v.begin_ts, t),
+            None => text.close(&v.row.key, v.begin_ts),
+        }
+    }
+}
+
+// Segment and index files a crash left behind before they made it into the
+// manifest, or after compaction or a newer snapshot took them out of it.
+fn remove_unlisted_files(data_dir: &std::path::Path, manifest: &Manifest) -> Result<()> {
+    // nothing in vectors/ is listed: the vector index's raw file is copied
+    // there from its snapshot, or rebuilt, on open
+    for sub in ["rows", "cols", "indexes", "vectors"] {
+        let Ok(entries) = std::fs::read_dir(data_dir.join(sub)) else { continue };
+        for entry in entries {
+            let path = entry?.path();
+            let listed = path.strip_prefix(data_dir).ok()
+                .and_then(|p| p.to_str())
+                .is_some_and(|p| manifest.is_listed(p));
+            if !listed { std::fs::remove_file(path)?; }
+        }
     }
+    Ok(())
+}
+
+// Stable 64-bit id for a row key, as returned in similarity hits. FNV-1a is
+// platform independent, so ids survive restarts, backups and other hardware.
+pub fn key_id(key: &RowKey) -> u64 {
+    key.0.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3))
 }
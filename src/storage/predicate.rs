@@ -0,0 +1,155 @@
+
+use std::cmp::Ordering;
+use serde::{Serialize, Deserialize};
+use crate::types::VersionedRow;
+use super::columnsegment::Scalar;
+
+// Filter over row versions. Columns are named as in column segments: "key",
+// "begin_ts", "end_ts", "txn_id", or "payload.<field>".
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Predicate {
+    Eq(String, Scalar),
+    // inclusive bounds; None is unbounded
+    Range { column: String, min: Option<Scalar>, max: Option<Scalar> },
+    And(Vec<Predicate>),
+}
+
+impl Predicate {
+    pub fn matches(&self, v: &VersionedRow) -> bool {
+        match self {
+            Predicate::Eq(col, want) => value_of(v, col).is_some_and(|x| compare(&x, want) == Some(Ordering::Equal)),
+            Predicate::Range { column, min, max } => value_of(v, column).is_some_and(|x| in_range(&x, min.as_ref(), max.as_ref())),
+            Predicate::And(ps) => ps.iter().all(|p| p.matches(v)),
+        }
+    }
+
+    // False only if no value between `lo` and `hi` (a column's zonemap) can
+    // satisfy the predicate on `column`. Other columns are not constrained.
+    pub fn may_overlap(&self, column: &str, lo: &Scalar, hi: &Scalar) -> bool {
+        match self {
+            Predicate::Eq(col, want) if col == column => in_range(want, Some(lo), Some(hi)),
+            Predicate::Range { column: col, min, max } if col == column => {
+                min.as_ref().is_none_or(|m| compare(hi, m).is_none_or(|o| o != Ordering::Less))
+                    && max.as_ref().is_none_or(|m| compare(lo, m).is_none_or(|o| o != Ordering::Greater))
+            }
+            Predicate::And(ps) => ps.iter().all(|p| p.may_overlap(column, lo, hi)),
+            _ => true,
+        }
+    }
+
+    // Columns the predicate requires a value in.
+    pub fn columns(&self) -> Vec<&str> {
+        match self {
+            Predicate::Eq(col, _) | Predicate::Range { column: col, .. } => vec![col.as_str()],
+            Predicate::And(ps) => ps.iter().flat_map(|p| p.columns()).collect(),
+        }
+    }
+
+    // Values the column must equal, for bloom filter probes.
+    pub fn equalities<'a>(&'a self, column: &str) -> Vec<&'a Scalar> {
+        match self {
+            Predicate::Eq(col, want) if col == column => vec![want],
+            Predicate::And(ps) => ps.iter().flat_map(|p| p.equalities(column)).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn in_range(x: &Scalar, min: Option<&Scalar>, max: Option<&Scalar>) -> bool {
+    min.is_none_or(|m| matches!(compare(x, m), Some(Ordering::Greater | Ordering::Equal)))
+        && max.is_none_or(|m| matches!(compare(x, m), Some(Ordering::Less | Ordering::Equal)))
+}
+
+// Numeric scalars compare across I64/F64/Ts; other kinds only with themselves.
+pub fn compare(a: &Scalar, b: &Scalar) -> Option<Ordering> {
+    let num = |s: &Scalar| match s {
+        Scalar::I64(x) => Some(*x as f64),
+        Scalar::F64(x) => Some(*x),
+        Scalar::Ts(x) => Some(*x as f64),
+        _ => None,
+    };
+    match (a, b) {
+        (Scalar::I64(x), Scalar::I64(y)) => x.partial_cmp(y),
+        (Scalar::Ts(x), Scalar::Ts(y)) => x.partial_cmp(y),
+        (Scalar::Str(x), Scalar::Str(y)) => x.partial_cmp(y),
+        (Scalar::Bool(x), Scalar::Bool(y)) => x.partial_cmp(y),
+        _ => num(a)?.partial_cmp(&num(b)?),
+    }
+}
+
+// The scalar a version holds in `column`, if any.
+pub fn value_of(v: &VersionedRow, column: &str) -> Option<Scalar> {
+    match column {
+        "key" => Some(Scalar::Str(v.row.key.0.clone())),
+        "begin_ts" => Some(Scalar::Ts(v.begin_ts)),
+        "end_ts" => v.end_ts.map(Scalar::Ts),
+        "txn_id" => Some(Scalar::I64(v.txn_id as i64)),
+        _ => {
+            let field = column.strip_prefix("payload.")?;
+            let value = v.row.payload.get(field).or_else(|| {
+                field.split('.').try_fold(&v.row.payload, |cur, part| cur.get(part))
+            })?;
+            json_scalar(value)
+        }
+    }
+}
+
+// Keeps only the payload fields named in `projection` ("payload.<field>"),
+// or holding a nested path named there ("payload.<field>.<sub>").
+pub fn project_payload(payload: &mut serde_json::Value, projection: &[String]) {
+    if let Some(obj) = payload.as_object_mut() {
+        obj.retain(|f, _| projection.iter().any(|c| needs_column(c, &format!("payload.{}", f))));
+    }
+}
+
+// Whether reading `path` needs `column`: the two are equal, or `path` is
+// nested inside it ("payload.a.b" needs "payload.a").
+pub fn needs_column(path: &str, column: &str) -> bool {
+    path.strip_prefix(column).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+pub fn json_scalar(v: &serde_json::Value) -> Option<Scalar> {
+    match v {
+        serde_json::Value::Bool(b) => Some(Scalar::Bool(*b)),
+        serde_json::Value::String(s) => Some(Scalar::Str(s.clone())),
+        serde_json::Value::Number(n) => n.as_i64().map(Scalar::I64).or_else(|| n.as_f64().map(Scalar::F64)),
+        _ => None,
+    }
+}
+
+// Fixed-seed bloom filter over scalars (10 bits per value, 7 probes), so
+// filters written by one process can be probed by another.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bloom {
+    bits: Vec<u64>,
+    probes: u32,
+}
+
+impl Bloom {
+    pub fn new(items: usize) -> Self {
+        Self { bits: vec![0; (items * 10).div_ceil(64).max(1)], probes: 7 }
+    }
+
+    fn positions(&self, s: &Scalar) -> impl Iterator<Item = usize> + '_ {
+        let bytes = match s {
+            Scalar::Str(x) => [&[0u8][..], x.as_bytes()].concat(),
+            Scalar::I64(x) => [&[1u8][..], &x.to_le_bytes()].concat(),
+            Scalar::Ts(x) => [&[2u8][..], &x.to_le_bytes()].concat(),
+            Scalar::F64(x) => [&[3u8][..], &x.to_bits().to_le_bytes()].concat(),
+            Scalar::Bool(x) => vec![4, *x as u8],
+        };
+        let h = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |h, &b| (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3));
+        let (h1, h2) = (h as u32 as u64, (h >> 32) | 1);
+        let m = self.bits.len() as u64 * 64;
+        (0..self.probes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+
+    pub fn insert(&mut self, s: &Scalar) {
+        let pos: Vec<usize> = self.positions(s).collect();
+        for p in pos { self.bits[p / 64] |= 1 << (p % 64); }
+    }
+
+    pub fn may_contain(&self, s: &Scalar) -> bool {
+        self.positions(s).all(|p| self.bits[p / 64] & (1 << (p % 64)) != 0)
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use anyhow::Result;
+use crate::vector::hnsw::HnswIndex;
+use crate::vector::quant::{Quantization, Quantizer, RawVectors, TRAIN_SAMPLE};
+use super::Engine;
+
+impl Engine {
+    // Rebuilds the vector index with its vectors coded by a `kind` quantizer
+    // trained on up to TRAIN_SAMPLE of them. Full vectors move to a file
+    // under data_dir/vectors (the temp dir for in-memory engines) and
+    // re-rank the best `rerank * k` candidates of each search. Commits go on
+    // meanwhile and are carried over before the new index is swapped in. A
+    // durable engine then saves it; see `maybe_save_vector_snapshot`.
+    pub fn quantize_vectors(&self, kind: Quantization, rerank: usize) -> Result<()> {
+        let index = self.vector_index();
+        let seen = index.mutations();
+        let quantizer = Quantizer::train(kind, &index.sample(TRAIN_SAMPLE)?)?;
+        let raw = RawVectors::create_in(self.raw_vector_dir(), index.dims)?;
+        let fresh = index.quantized(quantizer, raw)?.with_rerank(rerank);
+        self.replace_vector_index(&index, seen, fresh)?;
+        self.maybe_save_vector_snapshot()?;
+        Ok(())
+    }
+
+    // Quantizes the vector index as configured once it holds `train_after`
+    // vectors; true if it did. Run on open, by `gc` and by the background
+    // flusher, which commits wake once the index is due.
+    pub fn maybe_quantize_vectors(&self) -> Result<bool> {
+        let Some(q) = self.quantization.filter(|_| self.quantize_due()) else { return Ok(false) };
+        self.quantize_vectors(q.kind, q.rerank)?;
+        Ok(true)
+    }
+
+    pub(crate) fn quantize_due(&self) -> bool {
+        let Some(q) = self.quantization else { return false };
+        let index = self.vector_index();
+        !index.is_quantized() && !index.is_empty() && index.len() >= q.train_after
+    }
+
+    // Swaps in `fresh`, a rebuild of `old` made while `old` was at
+    // `seen` mutations. Vectors added to or removed from `old` since are
+    // applied to `fresh` first, under the commit lock so none slip by. If
+    // one cannot be, `old` stays in place. So does a rebuild that another
+    // one (a concurrent `gc` or flusher round) beat to replacing `old`.
+    pub(crate) fn replace_vector_index(&self, old: &HnswIndex, seen: u64, fresh: HnswIndex) -> Result<()> {
+        let _commits = self.commit_lock.lock();
+        if !std::ptr::eq(&**self.vector_index.read(), old) { return Ok(()); }
+        if old.mutations() != seen {
+            let live: HashSet<u64> = old.ids().into_iter().collect();
+            let copied: HashSet<u64> = fresh.ids().into_iter().collect();
+            for id in copied.difference(&live) { fresh.remove(*id); }
+            for id in live.difference(&copied) {
+                if let Some(v) = old.get(*id)? { fresh.add(*id, v)?; }
+            }
+        }
+        *self.vector_index.write() = Arc::new(fresh);
+        Ok(())
+    }
+
+    // Why the vector index last failed to store a vector in its raw file, to
+    // be rebuilt by `gc`, or to be quantized or saved in the background; None
+    // if it never did. Vectors a commit could not store are kept uncoded, so
+    // no search loses them.
+    pub fn vector_error(&self) -> Option<String> { self.vector_error.lock().clone() }
+
+    pub(crate) fn raw_vector_dir(&self) -> PathBuf {
+        match self.data_dir() {
+            Some(dir) => dir.join("vectors"),
+            None => std::env::temp_dir().join("afdb-vectors"),
+        }
+    }
+}
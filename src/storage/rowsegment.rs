@@ -1,64 +1,238 @@
-
 use serde::{Serialize, Deserialize};
-use std::path::PathBuf;
-use anyhow::Result;
-use std::fs::OpenOptions;
-use std::io::{Write, Read};
-use crate::types::VersionedRow;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Result};
+use parking_lot::Mutex;
+use std::fs::{File, OpenOptions};
+use std::io::{Write, Read, Seek, SeekFrom, BufWriter};
+use std::sync::Arc;
+use crate::types::{RowKey, Timestamp, TxnId, Vector, VersionedRow};
+use crate::mvcc::visible_at;
+use super::layers::SegmentReader;
+use super::columnsegment::Scalar;
+use super::predicate::Predicate;
+use super::compression::{block_len, decode_block, encode_block, Codec};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// File layout: MAGIC | header_len u32 | header | block*. Each block (see
+// `compression`) holds (len u32 | entry)* for a run of entries sorted by key
+// and then begin_ts. Segments are immutable once written.
+const MAGIC: &[u8; 4] = b"AFR2";
+// Raw bytes of entries gathered into one block before it is compressed.
+const BLOCK_BYTES: usize = 64 << 10;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct RowSegmentMeta {
     pub rows: u64,
+    pub min_ts: Timestamp,
+    pub max_ts: Timestamp,
+    pub max_txn: TxnId,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct RowSegmentHeader {
+    meta: RowSegmentMeta,
+    codec: Codec,
 }
 
+// A row version plus the embedding it was committed with, so the vector
+// index can be rebuilt without the WAL.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RowSegmentEntry {
+    pub version: VersionedRow,
+    pub vector: Option<Vector>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct EntryPos {
+    begin_ts: Timestamp,
+    block: u32,
+    // within the decompressed block
+    offset: u32,
+    len: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BlockPos {
+    offset: u64,
+    len: u32,
+}
+
+struct Reader {
+    file: File,
+    // the last block decompressed, since version chains share blocks
+    cached: Option<(u32, Arc<Vec<u8>>)>,
+}
+
+// Only the key index lives in memory; versions are read from disk on demand.
 pub struct RowSegment {
     path: PathBuf,
+    meta: RowSegmentMeta,
+    codec: Codec,
+    index: BTreeMap<String, Vec<EntryPos>>,
+    blocks: Vec<BlockPos>,
+    reader: Mutex<Reader>,
 }
 
 impl RowSegment {
-    pub fn create(path: PathBuf) -> Result<Self> {
-        std::fs::create_dir_all(path.parent().unwrap())?;
-        let mut f = OpenOptions::new().create(true).write(true).open(&path)?;
-        // placeholder meta
-        let meta = RowSegmentMeta { rows: 0 };
-        let bytes = bincode::serialize(&meta)?;
-        f.write_all(&(bytes.len() as u32).to_le_bytes())?;
-        f.write_all(&bytes)?;
-        Ok(Self { path })
+    // Writes `entries` (sorted by key, then begin_ts) to `path` via a temp
+    // file, so a crash never leaves a partial segment under the final name.
+    pub fn write(path: PathBuf, entries: &[RowSegmentEntry], codec: Codec) -> Result<Self> {
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir)?;
+        let meta = RowSegmentMeta {
+            rows: entries.len() as u64,
+            min_ts: entries.iter().map(|e| e.version.begin_ts).min().unwrap_or(0),
+            max_ts: entries.iter().map(|e| e.version.begin_ts).max().unwrap_or(0),
+            max_txn: entries.iter().map(|e| e.version.txn_id).max().unwrap_or(0),
+        };
+        let tmp = path.with_extension("tmp");
+        let mut f = BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(&tmp)?);
+        let header = bincode::serialize(&RowSegmentHeader { meta, codec })?;
+        f.write_all(MAGIC)?;
+        f.write_all(&(header.len() as u32).to_le_bytes())?;
+        f.write_all(&header)?;
+        let mut block = Vec::new();
+        for e in entries {
+            let bytes = bincode::serialize(e)?;
+            block.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            block.extend_from_slice(&bytes);
+            if block.len() >= BLOCK_BYTES {
+                f.write_all(&encode_block(codec, &block))?;
+                block.clear();
+            }
+        }
+        if !block.is_empty() { f.write_all(&encode_block(codec, &block))?; }
+        f.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        super::wal::sync_dir(dir)?;
+        Self::open(path)
     }
 
+    // Opens a segment and builds its key index, validating every block.
     pub fn open(path: PathBuf) -> Result<Self> {
-        Ok(Self { path })
+        let mut data = Vec::new();
+        let mut file = OpenOptions::new().read(true).open(&path)?;
+        file.read_to_end(&mut data)?;
+        if data.get(..4) != Some(MAGIC.as_slice()) { bail!("row segment {} has an unknown format", path.display()); }
+        let header_len = u32::from_le_bytes(data.get(4..8).ok_or_else(|| anyhow!("row segment {} is truncated", path.display()))?.try_into()?) as usize;
+        let Some(header) = data.get(8..8 + header_len) else { bail!("row segment {} is truncated", path.display()) };
+        let RowSegmentHeader { meta, codec } = bincode::deserialize(header)?;
+        let mut index: BTreeMap<String, Vec<EntryPos>> = BTreeMap::new();
+        let mut blocks = Vec::new();
+        let (mut off, mut rows) = (8 + header_len, 0u64);
+        while off < data.len() {
+            let len = block_len(&data[off..])?;
+            let Some(stored) = data.get(off..off + len) else { bail!("row segment {} has a truncated block", path.display()) };
+            let raw = decode_block(stored).map_err(|e| anyhow!("row segment {}: {}", path.display(), e))?;
+            let block = blocks.len() as u32;
+            let mut pos = 0usize;
+            while pos < raw.len() {
+                let buf = frame_at(&raw, pos)?;
+                let e: RowSegmentEntry = bincode::deserialize(buf)?;
+                index.entry(e.version.row.key.0)
+                    .or_default()
+                    .push(EntryPos { begin_ts: e.version.begin_ts, block, offset: pos as u32, len: buf.len() as u32 });
+                pos += 4 + buf.len();
+                rows += 1;
+            }
+            blocks.push(BlockPos { offset: off as u64, len: len as u32 });
+            off += len;
+        }
+        if rows != meta.rows { bail!("row segment {} holds {} rows, header says {}", path.display(), rows, meta.rows); }
+        Ok(Self { path, meta, codec, index, blocks, reader: Mutex::new(Reader { file, cached: None }) })
     }
 
-    pub fn append(&self, row: &VersionedRow) -> Result<()> {
-        let mut f = OpenOptions::new().append(true).open(&self.path)?;
-        let bytes = bincode::serialize(row)?;
-        let len = bytes.len() as u32;
-        f.write_all(&len.to_le_bytes())?;
-        f.write_all(&bytes)?;
-        Ok(())
-    }
+    pub fn path(&self) -> &Path { &self.path }
+    pub fn codec(&self) -> Codec { self.codec }
 
-    pub fn iter(&self) -> Result<Vec<VersionedRow>> {
-        let mut f = OpenOptions::new().read(true).open(&self.path)?;
-        // read meta
-        let mut len_buf = [0u8;4];
-        f.read_exact(&mut len_buf)?;
-        let meta_len = u32::from_le_bytes(len_buf) as usize;
-        let mut meta_buf = vec![0u8; meta_len];
-        f.read_exact(&mut meta_buf)?;
-        // rows
-        let mut out = Vec::new();
-        loop {
-            let mut len_buf = [0u8;4];
-            if f.read_exact(&mut len_buf).is_err() { break; }
-            let len = u32::from_le_bytes(len_buf) as usize;
-            let mut buf = vec![0u8; len];
-            f.read_exact(&mut buf)?;
-            let rec: VersionedRow = bincode::deserialize(&buf)?;
-            out.push(rec);
+    fn read_block(&self, block: u32) -> Result<Arc<Vec<u8>>> {
+        let mut r = self.reader.lock();
+        if let Some((b, raw)) = &r.cached {
+            if *b == block { return Ok(raw.clone()); }
         }
-        Ok(out)
+        let pos = self.blocks[block as usize];
+        let mut buf = vec![0u8; pos.len as usize];
+        r.file.seek(SeekFrom::Start(pos.offset))?;
+        r.file.read_exact(&mut buf)?;
+        let raw = Arc::new(decode_block(&buf).map_err(|e| anyhow!("row segment {}: {}", self.path.display(), e))?);
+        r.cached = Some((block, raw.clone()));
+        Ok(raw)
     }
+
+    fn read_entry(&self, pos: EntryPos) -> Result<RowSegmentEntry> {
+        let raw = self.read_block(pos.block)?;
+        let start = pos.offset as usize + 4;
+        Ok(bincode::deserialize(&raw[start..start + pos.len as usize])?)
+    }
+}
+
+impl SegmentReader for RowSegment {
+    fn meta(&self) -> &RowSegmentMeta { &self.meta }
+
+    // Row segments keep no per-field stats; they prune on commit time and,
+    // through the in-memory index, on key equality.
+    fn may_match(&self, pred: &Predicate) -> bool {
+        let (lo, hi) = (Scalar::Ts(self.meta.min_ts), Scalar::Ts(self.meta.max_ts));
+        self.meta.rows > 0
+            && pred.may_overlap("begin_ts", &lo, &hi)
+            && pred.equalities("key").into_iter().all(|k| matches!(k, Scalar::Str(k) if self.index.contains_key(k)))
+    }
+
+    fn shadowed_keys(&self, ts: Timestamp) -> Vec<&str> {
+        self.index.iter().filter(|(_, p)| p[0].begin_ts <= ts).map(|(k, _)| k.as_str()).collect()
+    }
+
+    fn latest_ts(&self, key: &RowKey) -> Option<Timestamp> {
+        self.index.get(&key.0).and_then(|p| p.last()).map(|p| p.begin_ts)
+    }
+
+    fn visible_version(&self, key: &RowKey, ts: Timestamp) -> Result<Option<VersionedRow>> {
+        let Some(pos) = self.index.get(&key.0).and_then(|p| p.iter().rev().find(|p| p.begin_ts <= ts)) else { return Ok(None) };
+        let e = self.read_entry(*pos)?;
+        Ok(Some(e.version).filter(|v| visible_at(v, ts)))
+    }
+
+    fn versions(&self, key: &RowKey) -> Result<Vec<VersionedRow>> {
+        let Some(positions) = self.index.get(&key.0) else { return Ok(Vec::new()) };
+        positions.iter().map(|p| self.read_entry(*p).map(|e| e.version)).collect()
+    }
+
+    // Reads through the open handle, so it keeps working after compaction
+    // has unlinked the file.
+    fn iter(&self) -> Result<Vec<RowSegmentEntry>> { self.stream()?.collect() }
+
+    fn stream(&self) -> Result<Box<dyn Iterator<Item = Result<RowSegmentEntry>> + '_>> {
+        let mut next_block = 0usize;
+        let mut raw: Arc<Vec<u8>> = Arc::default();
+        let mut pos = 0usize;
+        let mut failed = false;
+        Ok(Box::new(std::iter::from_fn(move || {
+            if failed { return None; }
+            while pos >= raw.len() {
+                if next_block == self.blocks.len() { return None; }
+                raw = match self.read_block(next_block as u32) {
+                    Ok(raw) => raw,
+                    Err(e) => {
+                        failed = true;
+                        return Some(Err(e));
+                    }
+                };
+                next_block += 1;
+                pos = 0;
+            }
+            let entry = frame_at(&raw, pos).and_then(|buf| {
+                pos += 4 + buf.len();
+                Ok(bincode::deserialize(buf)?)
+            });
+            failed = entry.is_err();
+            Some(entry)
+        })))
+    }
+}
+
+// The length-prefixed entry starting at `pos` of a decompressed block.
+fn frame_at(raw: &[u8], pos: usize) -> Result<&[u8]> {
+    let Some(len) = raw.get(pos..pos + 4) else { bail!("row segment block truncated") };
+    let len = u32::from_le_bytes(len.try_into()?) as usize;
+    raw.get(pos + 4..pos + 4 + len).ok_or_else(|| anyhow!("row segment block truncated"))
 }
@@ -0,0 +1,235 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use anyhow::{bail, Result};
+use roaring::RoaringTreemap;
+use crate::types::{RowKey, Timestamp, VersionedRow};
+use super::{key_id, Engine};
+use super::columnsegment::Scalar;
+use super::manifest::{FieldIndexDef, VersionEdit};
+use super::predicate::{compare, project_payload, value_of, Predicate};
+
+// Scalars in index order: booleans, then numbers (I64/F64/Ts compared by
+// value), then strings.
+#[derive(Clone, Debug)]
+struct IndexKey(Scalar);
+
+impl IndexKey {
+    fn rank(&self) -> u8 {
+        match self.0 { Scalar::Bool(_) => 0, Scalar::I64(_) | Scalar::F64(_) | Scalar::Ts(_) => 1, Scalar::Str(_) => 2 }
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank().cmp(&other.rank()).then_with(|| compare(&self.0, &other.0).unwrap_or(Ordering::Equal))
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool { self.cmp(other) == Ordering::Equal }
+}
+
+impl Eq for IndexKey {}
+
+// Ordered map from a payload path's value to the keys with a retained
+// version holding it. Entries are added as versions commit and are only
+// dropped when the index is rebuilt, so a lookup returns a superset of the
+// matching keys; callers re-check the visible version.
+pub struct FieldIndex {
+    pub def: FieldIndexDef,
+    // predicate column, "payload.<path>"
+    column: String,
+    entries: BTreeMap<IndexKey, Posting>,
+}
+
+// The keys holding one value, and their `key_id`s for vector and text
+// searches to filter on.
+#[derive(Default)]
+struct Posting {
+    keys: BTreeSet<RowKey>,
+    ids: RoaringTreemap,
+}
+
+impl FieldIndex {
+    // `def.path` must be "$." followed by dot-separated field names.
+    pub fn new(def: FieldIndexDef) -> Result<Self> {
+        let Some(path) = def.path.strip_prefix("$.") else { bail!("index path {:?} must start with \"$.\"", def.path) };
+        if path.split('.').any(|f| f.is_empty() || !f.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')) {
+            bail!("invalid index path {:?}", def.path);
+        }
+        let column = format!("payload.{}", path);
+        Ok(Self { def, column, entries: BTreeMap::new() })
+    }
+
+    pub fn column(&self) -> &str { &self.column }
+    pub fn len(&self) -> usize { self.entries.values().map(|p| p.keys.len()).sum() }
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    // Whether `pred` has an equality or range condition on this index.
+    pub fn covers(&self, pred: &Predicate) -> bool {
+        match pred {
+            Predicate::Eq(col, _) | Predicate::Range { column: col, .. } => *col == self.column,
+            Predicate::And(ps) => ps.iter().any(|p| self.covers(p)),
+        }
+    }
+
+    pub fn insert(&mut self, v: &VersionedRow) {
+        if v.deleted { return; }
+        let Some(value) = value_of(v, &self.column) else { return };
+        if matches!(value, Scalar::F64(x) if x.is_nan()) { return; }
+        let posting = self.entries.entry(IndexKey(value)).or_default();
+        if posting.keys.insert(v.row.key.clone()) { posting.ids.insert(key_id(&v.row.key)); }
+    }
+
+    // Keys that may hold a value satisfying `pred` on this index's column;
+    // None if `pred` does not constrain it.
+    pub fn lookup(&self, pred: &Predicate) -> Option<BTreeSet<RowKey>> {
+        if let Predicate::And(ps) = pred {
+            return ps.iter().filter_map(|p| self.lookup(p)).reduce(|a, b| a.intersection(&b).cloned().collect());
+        }
+        Some(self.postings(pred)?.flat_map(|p| p.keys.iter().cloned()).collect())
+    }
+
+    // Like `lookup`, as `key_id`s.
+    pub fn lookup_ids(&self, pred: &Predicate) -> Option<RoaringTreemap> {
+        if let Predicate::And(ps) = pred {
+            return ps.iter().filter_map(|p| self.lookup_ids(p)).reduce(|a, b| a & b);
+        }
+        Some(self.postings(pred)?.fold(RoaringTreemap::new(), |acc, p| acc | &p.ids))
+    }
+
+    // The postings an equality or range condition on this index's column
+    // selects; None for any other predicate.
+    fn postings(&self, pred: &Predicate) -> Option<Box<dyn Iterator<Item = &Posting> + '_>> {
+        match pred {
+            Predicate::Eq(col, want) if *col == self.column => {
+                Some(Box::new(self.entries.get(&IndexKey(want.clone())).into_iter()))
+            }
+            Predicate::Range { column, min, max } if *column == self.column => {
+                let bound = |b: &Option<Scalar>| b.clone().map_or(Bound::Unbounded, |s| Bound::Included(IndexKey(s)));
+                if let (Some(lo), Some(hi)) = (min, max) {
+                    if IndexKey(lo.clone()) > IndexKey(hi.clone()) { return Some(Box::new(std::iter::empty())); }
+                }
+                // an open side must not run into values of another kind
+                let kind = min.as_ref().or(max.as_ref()).map(|s| IndexKey(s.clone()).rank());
+                Some(Box::new(self.entries.range((bound(min), bound(max)))
+                    .filter(move |(k, _)| kind.is_none_or(|r| k.rank() == r))
+                    .map(|(_, p)| p)))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Engine {
+    // Creates a secondary index over `path` (e.g. "$.customer_id") and fills
+    // it from every layer. Durable engines record it in the manifest and
+    // bump the schema version.
+    pub fn create_index(&self, name: &str, path: &str) -> Result<()> {
+        let mut index = FieldIndex::new(FieldIndexDef { name: name.to_string(), path: path.to_string() })?;
+        if self.field_indexes.read().contains_key(name) { bail!("index {} already exists", name); }
+        // the backfill runs beside commits; see `catch_up`
+        let read_ts = *self.now.read();
+        for v in self.view().all_versions()? { index.insert(&v); }
+        let _commits = self.commit_lock.lock();
+        if self.field_indexes.read().contains_key(name) { bail!("index {} already exists", name); }
+        self.catch_up(&mut [&mut index], read_ts)?;
+        if let Some(store) = &self.store {
+            let mut manifest = store.manifest.lock();
+            let schema_version = Some(manifest.state().schema_version + 1);
+            manifest.apply(VersionEdit { schema_version, add_field_indexes: vec![index.def.clone()], ..VersionEdit::default() })?;
+        }
+        self.field_indexes.write().insert(name.to_string(), index);
+        Ok(())
+    }
+
+    // Adds the versions that began after `read_ts` to indexes filled from a
+    // view taken at or after it. Commits are applied under the commit lock,
+    // which the caller holds, so nothing is missed before they go live.
+    fn catch_up(&self, indexes: &mut [&mut FieldIndex], read_ts: Timestamp) -> Result<()> {
+        for v in self.view().versions_since(read_ts)? {
+            for index in indexes.iter_mut() { index.insert(&v); }
+        }
+        Ok(())
+    }
+
+    // Returns whether the index existed.
+    pub fn drop_index(&self, name: &str) -> Result<bool> {
+        let _commits = self.commit_lock.lock();
+        if !self.field_indexes.read().contains_key(name) { return Ok(false); }
+        if let Some(store) = &self.store {
+            let mut manifest = store.manifest.lock();
+            let schema_version = Some(manifest.state().schema_version + 1);
+            manifest.apply(VersionEdit { schema_version, drop_field_indexes: vec![name.to_string()], ..VersionEdit::default() })?;
+        }
+        self.field_indexes.write().remove(name);
+        Ok(true)
+    }
+
+    pub fn field_indexes(&self) -> Vec<FieldIndexDef> {
+        self.field_indexes.read().values().map(|i| i.def.clone()).collect()
+    }
+
+    pub fn has_index_for(&self, pred: &Predicate) -> bool {
+        self.field_indexes.read().values().any(|i| i.covers(pred))
+    }
+
+    // Candidate keys for `pred` from every index it constrains, or None if
+    // no index applies.
+    pub fn index_candidates(&self, pred: &Predicate) -> Option<BTreeSet<RowKey>> {
+        self.field_indexes.read().values().filter_map(|i| i.lookup(pred))
+            .reduce(|a, b| a.intersection(&b).cloned().collect())
+    }
+
+    // Like `index_candidates`, as `key_id`s: a superset of the ids of the
+    // rows matching `pred`, built from the index postings without reading
+    // any row.
+    pub fn index_candidate_ids(&self, pred: &Predicate) -> Option<RoaringTreemap> {
+        self.field_indexes.read().values().filter_map(|i| i.lookup_ids(pred)).reduce(|a, b| a & b)
+    }
+
+    // Like `scan_projected`, but reads only the candidate keys an index
+    // yields for `pred`. Falls back to a scan when no index applies.
+    pub fn scan_indexed(&self, ts: Timestamp, pred: &Predicate, projection: Option<&[String]>) -> Result<Vec<VersionedRow>> {
+        let Some(keys) = self.index_candidates(pred) else { return self.scan_projected(ts, pred, projection) };
+        let view = self.view();
+        let mut out = Vec::new();
+        for key in keys {
+            let Some(mut v) = view.visible_version(&key, ts)?.filter(|v| !v.deleted && pred.matches(v)) else { continue };
+            if let Some(cols) = projection { project_payload(&mut v.row.payload, cols); }
+            out.push(v);
+        }
+        Ok(out)
+    }
+
+    // Refills every index from the current layers, dropping entries for
+    // versions that compaction or GC removed. Like `create_index`, the
+    // refill runs beside commits and catches up under the commit lock.
+    pub(crate) fn rebuild_field_indexes(&self) -> Result<()> {
+        let defs = self.field_indexes();
+        if defs.is_empty() { return Ok(()); }
+        let read_ts = *self.now.read();
+        let mut fresh = defs.into_iter().map(FieldIndex::new).collect::<Result<Vec<_>>>()?;
+        for v in self.view().all_versions()? {
+            for index in &mut fresh { index.insert(&v); }
+        }
+        let _commits = self.commit_lock.lock();
+        self.catch_up(&mut fresh.iter_mut().collect::<Vec<_>>(), read_ts)?;
+        let mut indexes = self.field_indexes.write();
+        for index in fresh {
+            // one dropped (or dropped and recreated) meanwhile is left alone
+            if let Some(live) = indexes.get_mut(&index.def.name).filter(|l| l.def == index.def) { *live = index; }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn index_fields(&self, v: &VersionedRow) {
+        let mut indexes = self.field_indexes.write();
+        for index in indexes.values_mut() { index.insert(v); }
+    }
+}
@@ -0,0 +1,141 @@
+
+use std::collections::BTreeMap;
+use crate::types::{Row, RowKey, Timestamp, TxnId, Vector, VersionedRow};
+use super::Engine;
+use super::predicate::Predicate;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TxnError {
+    #[error("key not found: {0}")]
+    NotFound(String),
+    #[error("transaction is read-only")]
+    ReadOnly,
+    #[error("snapshot at ts {ts} is older than the gc horizon {horizon}")]
+    SnapshotTooOld { ts: Timestamp, horizon: Timestamp },
+    // another transaction committed a write to `key` after this one's snapshot
+    #[error("write-write conflict on key {key}: committed at ts {committed_ts} after snapshot ts {read_ts}")]
+    WriteConflict { key: String, read_ts: Timestamp, committed_ts: Timestamp },
+    #[error(transparent)]
+    Storage(#[from] anyhow::Error),
+}
+
+pub type TxnResult<T> = std::result::Result<T, TxnError>;
+
+// A buffered write. Nothing reaches the WAL or the memtable before commit.
+#[derive(Clone, Debug)]
+pub(crate) enum PendingWrite {
+    Put { row: Row, vector: Option<Vector> },
+    Delete,
+}
+
+// A multi-statement transaction. Reads see the snapshot taken at `begin`
+// plus the transaction's own writes; writes become visible to others only
+// once `commit` returns. Dropping the handle without committing aborts it.
+pub struct Transaction<'e> {
+    engine: &'e Engine,
+    id: TxnId,
+    read_ts: Timestamp,
+    // historical snapshots (`Engine::begin_as_of`) cannot write
+    read_only: bool,
+    writes: BTreeMap<RowKey, PendingWrite>,
+}
+
+impl<'e> Transaction<'e> {
+    pub(crate) fn new(engine: &'e Engine, id: TxnId, read_ts: Timestamp, read_only: bool) -> Self {
+        Self { engine, id, read_ts, read_only, writes: BTreeMap::new() }
+    }
+
+    pub fn id(&self) -> TxnId { self.id }
+    pub fn read_ts(&self) -> Timestamp { self.read_ts }
+
+    // Writes a new version of `row.key`, whether or not the key exists.
+    pub fn insert(&mut self, row: Row) -> TxnResult<()> {
+        if self.read_only { return Err(TxnError::ReadOnly); }
+        let vector = self.engine.embed_row(&row);
+        self.writes.insert(row.key.clone(), PendingWrite::Put { row, vector });
+        Ok(())
+    }
+
+    // Like `insert`, but the key must be visible to this transaction. The
+    // existing embedding is reused when `payload.text` is unchanged.
+    pub fn update(&mut self, row: Row) -> TxnResult<()> {
+        if self.read_only { return Err(TxnError::ReadOnly); }
+        let Some(old) = self.get(&row.key)? else { return Err(TxnError::NotFound(row.key.0)) };
+        let text = row.payload.get("text");
+        if text.is_some() && old.payload.get("text") == text {
+            let vector = match self.writes.get(&row.key) {
+                Some(PendingWrite::Put { vector, .. }) => vector.clone(),
+                _ => self.engine.current_vector(&row.key)?,
+            };
+            if vector.is_some() {
+                self.writes.insert(row.key.clone(), PendingWrite::Put { row, vector });
+                return Ok(());
+            }
+        }
+        self.insert(row)
+    }
+
+    // Returns whether the key was visible (and is now deleted).
+    pub fn delete(&mut self, key: &RowKey) -> TxnResult<bool> {
+        if self.read_only { return Err(TxnError::ReadOnly); }
+        if self.get(key)?.is_none() { return Ok(false); }
+        self.writes.insert(key.clone(), PendingWrite::Delete);
+        Ok(true)
+    }
+
+    // Fails only if a flushed row segment cannot be read.
+    pub fn get(&self, key: &RowKey) -> TxnResult<Option<Row>> {
+        Ok(match self.writes.get(key) {
+            Some(PendingWrite::Put { row, .. }) => Some(row.clone()),
+            Some(PendingWrite::Delete) => None,
+            None => self.engine.get_visible(key, self.read_ts)?.map(|v| v.row),
+        })
+    }
+
+    // All visible rows in key order.
+    pub fn scan(&self) -> TxnResult<Vec<Row>> {
+        let mut rows: BTreeMap<RowKey, Row> = self.engine.scan_visible(self.read_ts)?
+            .into_iter().map(|v| (v.row.key.clone(), v.row)).collect();
+        for (key, w) in &self.writes {
+            match w {
+                PendingWrite::Put { row, .. } => { rows.insert(key.clone(), row.clone()); }
+                PendingWrite::Delete => { rows.remove(key); }
+            }
+        }
+        Ok(rows.into_values().collect())
+    }
+
+    // Visible rows matching `pred`, own writes included.
+    pub fn scan_where(&self, pred: &Predicate) -> TxnResult<Vec<Row>> {
+        let mut rows: BTreeMap<RowKey, Row> = self.engine.scan_where(self.read_ts, pred)?
+            .into_iter().map(|v| (v.row.key.clone(), v.row)).collect();
+        for (key, w) in &self.writes {
+            rows.remove(key);
+            if let PendingWrite::Put { row, .. } = w {
+                let v = VersionedRow { begin_ts: self.read_ts, end_ts: None, txn_id: self.id, row: row.clone(), deleted: false };
+                if pred.matches(&v) { rows.insert(key.clone(), row.clone()); }
+            }
+        }
+        Ok(rows.into_values().collect())
+    }
+
+    // Logs the writes plus a commit record and makes them visible. Returns the
+    // commit timestamp (the snapshot timestamp for read-only transactions).
+    // Fails with `WriteConflict`, writing nothing, if any key in the write set
+    // was committed by someone else after this transaction's snapshot
+    // (first committer wins).
+    pub fn commit(mut self) -> TxnResult<Timestamp> {
+        let writes = std::mem::take(&mut self.writes);
+        self.engine.commit_txn(self.id, self.read_ts, writes)
+    }
+
+    // Buffered writes were never logged or applied, so there is nothing to undo.
+    pub fn abort(self) {}
+}
+
+impl Drop for Transaction<'_> {
+    // releases the snapshot so GC can move past it
+    fn drop(&mut self) {
+        self.engine.release_snapshot(self.read_ts);
+    }
+}
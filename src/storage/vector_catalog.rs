@@ -0,0 +1,71 @@
+
+use std::collections::HashMap;
+use crate::types::{RowKey, Timestamp};
+use crate::mvcc::covers;
+
+// Which row version each vector in the index belongs to. A key's embedding is
+// valid over [begin_ts, end_ts); superseded embeddings stay in the index so
+// that time-travel searches can still reach them, until GC drops them.
+#[derive(Clone, Debug)]
+pub struct VectorVersion {
+    pub key: RowKey,
+    pub begin_ts: Timestamp,
+    pub end_ts: Option<Timestamp>,
+}
+
+#[derive(Default)]
+pub struct VectorCatalog {
+    versions: HashMap<u64, VectorVersion>,
+    // vector id of each key's open (end_ts = None) embedding
+    current: HashMap<RowKey, u64>,
+    next_id: u64,
+}
+
+impl VectorCatalog {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn len(&self) -> usize { self.versions.len() }
+    pub fn is_empty(&self) -> bool { self.versions.is_empty() }
+
+    // Starts a new embedding for `key` at `ts`, closing the previous one.
+    // Returns the vector id to store the embedding under.
+    pub fn open(&mut self, key: RowKey, ts: Timestamp) -> u64 {
+        self.close(&key, ts);
+        self.next_id += 1;
+        let id = self.next_id;
+        self.versions.insert(id, VectorVersion { key: key.clone(), begin_ts: ts, end_ts: None });
+        self.current.insert(key, id);
+        id
+    }
+
+    // Ends the key's current embedding at `ts` (update without text, delete).
+    pub fn close(&mut self, key: &RowKey, ts: Timestamp) {
+        if let Some(id) = self.current.remove(key) {
+            if let Some(v) = self.versions.get_mut(&id) { v.end_ts = Some(ts); }
+        }
+    }
+
+    pub fn current(&self, key: &RowKey) -> Option<u64> { self.current.get(key).copied() }
+
+    pub fn get(&self, id: u64) -> Option<&VectorVersion> { self.versions.get(&id) }
+
+    // Vector id of every retained embedding, by the row version it belongs to.
+    pub fn version_ids(&self) -> HashMap<(RowKey, Timestamp), u64> {
+        self.versions.iter().map(|(id, v)| ((v.key.clone(), v.begin_ts), *id)).collect()
+    }
+
+    // Forgets embeddings that ended at or before `horizon`; returns their ids
+    // so the caller can drop them from the vector index.
+    pub fn gc(&mut self, horizon: Timestamp) -> Vec<u64> {
+        let dead: Vec<u64> = self.versions.iter()
+            .filter(|(_, v)| v.end_ts.is_some_and(|e| e <= horizon))
+            .map(|(id, _)| *id).collect();
+        for id in &dead { self.versions.remove(id); }
+        dead
+    }
+
+    // The version behind vector `id` if it was live at `ts`.
+    pub fn visible_at(&self, id: u64, ts: Timestamp) -> Option<&VectorVersion> {
+        self.versions.get(&id).filter(|v| covers(v.begin_ts, v.end_ts, ts))
+    }
+}
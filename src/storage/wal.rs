@@ -1,43 +1,474 @@
 
-use std::fs::{OpenOptions};
-use std::io::{Write, Read, Seek, SeekFrom};
-use std::path::PathBuf;
-use anyhow::Result;
-use serde::{Serialize, de::DeserializeOwned};
+use std::fs::{OpenOptions, File};
+use std::io::{Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Result, bail};
+use parking_lot::{Mutex, MutexGuard, Condvar};
+use crate::config::{Config, Durability};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use crate::types::{Row, RowKey, Timestamp, TxnId, Vector};
+
+pub type Lsn = u64;
+
+// Logical records written by the engine. A transaction's writes are logged at
+// commit time, immediately followed by its `Commit` record in the same group
+// write; replay drops writes whose commit record never made it to disk. The
+// embedding is logged alongside the row so recovery does not need to call the
+// embedding endpoint again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum WalRecord {
+    Put { txn_id: TxnId, row: Row, vector: Option<Vector> },
+    Delete { txn_id: TxnId, key: RowKey },
+    Commit { txn_id: TxnId, commit_ts: Timestamp },
+}
+
+impl WalRecord {
+    pub fn record_type(&self) -> RecordType {
+        match self {
+            WalRecord::Put { .. } | WalRecord::Delete { .. } => RecordType::Write,
+            WalRecord::Commit { .. } => RecordType::Commit,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordType {
+    Write = 1,
+    // written by `Wal::checkpoint`; consumed by replay, never returned as an entry
+    Checkpoint = 2,
+    Commit = 3,
+}
+
+impl RecordType {
+    fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(RecordType::Write),
+            2 => Some(RecordType::Checkpoint),
+            3 => Some(RecordType::Commit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct CheckpointRecord {
+    // every record with lsn <= flushed_lsn is persisted outside the WAL
+    flushed_lsn: Lsn,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WalOptions {
+    pub durability: Durability,
+    // a segment is sealed and a new one started once it grows past this size
+    pub segment_bytes: u64,
+}
+
+impl WalOptions {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self { durability: cfg.durability, segment_bytes: (cfg.wal_segment_size_mb as u64) << 20 }
+    }
+}
+
+// The WAL is a directory of segment files named after the first lsn they
+// hold (`00000000000000000001.wal`, ...). Lsns are contiguous across segments.
+//
+// Frame layout (little endian):
+//   len: u32 | checksum: u32 | type: u8 | lsn: u64 | body: [u8; len]
+// The adler32 checksum covers type, lsn and body, so a torn header or a
+// half-written body is detected the same way as bit-rot.
+const HEADER_LEN: usize = 4 + 4 + 1 + 8;
+// Upper bound on a single frame body; a larger length prefix is treated as garbage.
+const MAX_BODY_LEN: usize = 64 << 20;
+const SEGMENT_EXT: &str = "wal";
+
+#[derive(Clone, Debug)]
+pub struct WalEntry<T> {
+    pub lsn: Lsn,
+    pub rtype: RecordType,
+    pub record: T,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Replay<T> {
+    // records after the last checkpoint, in lsn order
+    pub entries: Vec<WalEntry<T>>,
+    // flushed lsn of the last checkpoint record, 0 if there is none
+    pub checkpoint_lsn: Lsn,
+    // bytes cut on open because of a torn or corrupt frame
+    pub discarded_bytes: u64,
+}
+
+struct Frame<'a> {
+    lsn: Lsn,
+    rtype: RecordType,
+    body: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WalStats {
+    pub appends: u64,
+    pub writes: u64,
+    pub syncs: u64,
+    pub rotations: u64,
+    pub segments_deleted: u64,
+}
+
+struct WalState {
+    // framed records queued for the next group write
+    pending: Vec<u8>,
+    next_lsn: Lsn,
+    // highest lsn handed to the OS / made durable with fsync
+    written_lsn: Lsn,
+    synced_lsn: Lsn,
+    // first lsn of every live segment, oldest first; the last one is active
+    segments: Vec<Lsn>,
+    // a leader is currently writing (and possibly syncing) a batch
+    flushing: bool,
+    // first I/O error; the log refuses further appends once it is set
+    failed: Option<String>,
+    shutdown: bool,
+    stats: WalStats,
+}
+
+struct ActiveSegment {
+    file: File,
+    len: u64,
+}
+
+struct WalShared {
+    dir: PathBuf,
+    opts: WalOptions,
+    // only touched by the current leader (or the background syncer), which
+    // the `flushing` flag makes exclusive
+    active: Mutex<ActiveSegment>,
+    state: Mutex<WalState>,
+    cond: Condvar,
+}
 
 pub struct Wal {
-    path: PathBuf,
+    shared: Arc<WalShared>,
+    syncer: Option<std::thread::JoinHandle<()>>,
+    discarded_bytes: u64,
 }
 
 impl Wal {
-    pub fn open(path: PathBuf) -> Result<Self> {
-        std::fs::create_dir_all(path.parent().unwrap())?;
-        Ok(Self { path })
+    // Opens the log directory and recovers it: the first frame that fails
+    // validation truncates its segment, and any later segments are removed,
+    // so later appends never land behind garbage.
+    pub fn open(dir: PathBuf, opts: WalOptions) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut segments = list_segments(&dir)?;
+        let mut discarded_bytes = 0u64;
+        let mut last_lsn = 0u64;
+        let mut active_len = 0u64;
+        let mut i = 0;
+        while i < segments.len() {
+            let path = segment_path(&dir, segments[i]);
+            let data = std::fs::read(&path)?;
+            let (frames, valid_len) = scan(&data, last_lsn);
+            if let Some(fr) = frames.last() { last_lsn = fr.lsn; }
+            active_len = valid_len as u64;
+            if valid_len < data.len() {
+                discarded_bytes += (data.len() - valid_len) as u64;
+                let f = OpenOptions::new().write(true).open(&path)?;
+                f.set_len(valid_len as u64)?;
+                f.sync_all()?;
+                for &later in &segments[i + 1..] {
+                    let p = segment_path(&dir, later);
+                    discarded_bytes += std::fs::metadata(&p)?.len();
+                    std::fs::remove_file(p)?;
+                }
+                segments.truncate(i + 1);
+            }
+            i += 1;
+        }
+        if segments.is_empty() { segments.push(last_lsn + 1); }
+        let active_first = *segments.last().unwrap();
+        let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(segment_path(&dir, active_first))?;
+        file.seek(SeekFrom::Start(active_len))?;
+        sync_dir(&dir)?;
+        let shared = Arc::new(WalShared {
+            dir,
+            opts,
+            active: Mutex::new(ActiveSegment { file, len: active_len }),
+            state: Mutex::new(WalState {
+                pending: Vec::new(),
+                next_lsn: last_lsn + 1,
+                written_lsn: last_lsn,
+                synced_lsn: last_lsn,
+                segments,
+                flushing: false,
+                failed: None,
+                shutdown: false,
+                stats: WalStats::default(),
+            }),
+            cond: Condvar::new(),
+        });
+        let syncer = match opts.durability {
+            Durability::EveryNMs(ms) => {
+                let shared = shared.clone();
+                Some(std::thread::spawn(move || shared.sync_loop(Duration::from_millis(ms.max(1)))))
+            }
+            _ => None,
+        };
+        Ok(Self { shared, syncer, discarded_bytes })
+    }
+
+    pub fn append<T: Serialize>(&self, rtype: RecordType, rec: &T) -> Result<Lsn> {
+        self.append_all(std::iter::once((rtype, rec)))
+    }
+
+    // Appends several records as one group and returns the last lsn. With
+    // `Durability::Always` the call returns once every record is fsynced; a
+    // single fsync covers the whole batch plus whatever other writers queued
+    // in the meantime.
+    pub fn append_all<'a, T: Serialize + 'a>(&self, recs: impl IntoIterator<Item = (RecordType, &'a T)>) -> Result<Lsn> {
+        let lsn = self.enqueue_all(recs)?;
+        self.wait_durable(lsn)?;
+        Ok(lsn)
+    }
+
+    // Queues records without waiting for them to be written; pair with
+    // `wait_durable`. Lsns are assigned in call order, so callers can fix an
+    // order under their own lock and wait for the I/O outside it.
+    pub fn enqueue_all<'a, T: Serialize + 'a>(&self, recs: impl IntoIterator<Item = (RecordType, &'a T)>) -> Result<Lsn> {
+        let mut bodies = Vec::new();
+        for (rtype, rec) in recs {
+            let body = bincode::serialize(rec)?;
+            if body.len() > MAX_BODY_LEN { bail!("wal record of {} bytes exceeds frame limit", body.len()); }
+            bodies.push((rtype, body));
+        }
+        let mut st = self.shared.state.lock();
+        if let Some(err) = &st.failed { bail!("wal is unusable after an earlier I/O error: {}", err); }
+        for (rtype, body) in &bodies {
+            let lsn = st.next_lsn;
+            st.pending.extend_from_slice(&encode_frame(lsn, *rtype, body));
+            st.next_lsn += 1;
+            st.stats.appends += 1;
+        }
+        Ok(st.next_lsn - 1)
     }
 
-    pub fn append<T: Serialize>(&self, rec: &T) -> Result<u64> {
-        let mut f = OpenOptions::new().create(true).append(true).open(&self.path)?;
-        let bytes = bincode::serialize(rec)?;
-        let len = bytes.len() as u32;
-        f.write_all(&len.to_le_bytes())?;
-        f.write_all(&bytes)?;
-        f.flush()?;
-        let pos = f.seek(SeekFrom::End(0))?;
-        Ok(pos)
+    // Blocks until `lsn` is durable according to the configured durability.
+    pub fn wait_durable(&self, lsn: Lsn) -> Result<()> {
+        let need_sync = self.shared.opts.durability == Durability::Always;
+        let st = self.shared.state.lock();
+        self.shared.wait_for(st, lsn, need_sync).map(drop)
     }
 
-    pub fn replay<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
-        let mut f = OpenOptions::new().read(true).create(true).open(&self.path)?;
-        let mut out = Vec::new();
+    // Forces everything appended so far to stable storage.
+    pub fn sync(&self) -> Result<()> {
+        let st = self.shared.state.lock();
+        let upto = st.next_lsn - 1;
+        self.shared.wait_for(st, upto, true).map(drop)
+    }
+
+    // Records that every lsn <= `flushed_lsn` is persisted elsewhere, then
+    // deletes the segments that only hold such records. The checkpoint record
+    // is always fsynced, whatever the durability setting.
+    pub fn checkpoint(&self, flushed_lsn: Lsn) -> Result<Lsn> {
+        let lsn = self.enqueue_all(std::iter::once((RecordType::Checkpoint, &CheckpointRecord { flushed_lsn })))?;
+        let mut st = self.shared.wait_for(self.shared.state.lock(), lsn, true)?;
+        // segment i is obsolete once the next segment starts at or before flushed_lsn + 1
+        let keep_from = st.segments.windows(2).take_while(|w| w[1] <= flushed_lsn + 1).count();
+        // oldest first; the list only forgets segments whose file is gone, so
+        // a failed delete is retried by the next checkpoint
+        let mut removed = 0;
+        let mut failed = None;
+        for &first in &st.segments[..keep_from] {
+            match std::fs::remove_file(segment_path(&self.shared.dir, first)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => { failed = Some(e); break; }
+                _ => removed += 1,
+            }
+        }
+        st.segments.drain(..removed);
+        st.stats.segments_deleted += removed as u64;
+        drop(st);
+        sync_dir(&self.shared.dir)?;
+        match failed {
+            Some(e) => Err(e.into()),
+            None => Ok(lsn),
+        }
+    }
+
+    pub fn replay<T: DeserializeOwned>(&self) -> Result<Replay<T>> {
+        let segments = self.shared.state.lock().segments.clone();
+        let mut raw = Vec::new();
+        let mut checkpoint_lsn = 0;
+        let mut last_lsn = 0;
+        for first in segments {
+            let data = std::fs::read(segment_path(&self.shared.dir, first))?;
+            let (frames, _) = scan(&data, last_lsn);
+            for fr in frames {
+                last_lsn = fr.lsn;
+                // the checksum matched, so a decode failure is a format bug, not a torn write
+                if fr.rtype == RecordType::Checkpoint {
+                    let cp: CheckpointRecord = bincode::deserialize(fr.body)?;
+                    checkpoint_lsn = checkpoint_lsn.max(cp.flushed_lsn);
+                } else {
+                    raw.push((fr.lsn, fr.rtype, fr.body.to_vec()));
+                }
+            }
+        }
+        let mut entries = Vec::new();
+        for (lsn, rtype, body) in raw.into_iter().filter(|(lsn, _, _)| *lsn > checkpoint_lsn) {
+            entries.push(WalEntry { lsn, rtype, record: bincode::deserialize(&body)? });
+        }
+        Ok(Replay { entries, checkpoint_lsn, discarded_bytes: self.discarded_bytes })
+    }
+
+    pub fn next_lsn(&self) -> Lsn { self.shared.state.lock().next_lsn }
+
+    pub fn stats(&self) -> WalStats { self.shared.state.lock().stats }
+
+    pub fn segment_paths(&self) -> Vec<PathBuf> {
+        self.shared.state.lock().segments.iter().map(|&f| segment_path(&self.shared.dir, f)).collect()
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        {
+            let mut st = self.shared.state.lock();
+            st.shutdown = true;
+            self.shared.cond.notify_all();
+        }
+        if let Some(h) = self.syncer.take() { let _ = h.join(); }
+        let _ = self.sync();
+    }
+}
+
+impl WalShared {
+    // Blocks until `lsn` is written (and synced if `need_sync`) and hands the
+    // state lock back. Whoever finds no flush in progress becomes the leader
+    // and writes the whole pending queue on behalf of everyone waiting; the
+    // rest sleep on the condvar.
+    fn wait_for<'a>(&'a self, mut st: MutexGuard<'a, WalState>, lsn: Lsn, need_sync: bool) -> Result<MutexGuard<'a, WalState>> {
         loop {
-            let mut len_buf = [0u8;4];
-            if f.read_exact(&mut len_buf).is_err() { break; }
-            let len = u32::from_le_bytes(len_buf) as usize;
-            let mut buf = vec![0u8; len];
-            f.read_exact(&mut buf)?;
-            let rec: T = bincode::deserialize(&buf)?;
-            out.push(rec);
+            if let Some(err) = &st.failed { bail!("wal write failed: {}", err); }
+            let done = if need_sync { st.synced_lsn >= lsn } else { st.written_lsn >= lsn };
+            if done { return Ok(st); }
+            if st.flushing {
+                self.cond.wait(&mut st);
+                continue;
+            }
+            st = self.lead_flush(st, need_sync);
+        }
+    }
+
+    fn lead_flush<'a>(&'a self, mut st: MutexGuard<'a, WalState>, sync: bool) -> MutexGuard<'a, WalState> {
+        st.flushing = true;
+        let buf = std::mem::take(&mut st.pending);
+        let upto = st.next_lsn - 1;
+        let res = MutexGuard::unlocked(&mut st, || -> std::io::Result<Option<Lsn>> {
+            let mut active = self.active.lock();
+            if !buf.is_empty() {
+                active.file.write_all(&buf)?;
+                active.len += buf.len() as u64;
+            }
+            if sync { active.file.sync_data()?; }
+            // seal the segment on a frame boundary; sealed segments are always fsynced
+            if active.len >= self.opts.segment_bytes {
+                if !sync { active.file.sync_data()?; }
+                let first = upto + 1;
+                let file = OpenOptions::new().write(true).create(true).truncate(true).open(segment_path(&self.dir, first))?;
+                sync_dir(&self.dir)?;
+                *active = ActiveSegment { file, len: 0 };
+                return Ok(Some(first));
+            }
+            Ok(None)
+        });
+        st.flushing = false;
+        match res {
+            Ok(rotated) => {
+                if !buf.is_empty() { st.stats.writes += 1; }
+                st.written_lsn = upto;
+                if sync || rotated.is_some() {
+                    st.synced_lsn = upto;
+                    st.stats.syncs += 1;
+                }
+                if let Some(first) = rotated {
+                    st.segments.push(first);
+                    st.stats.rotations += 1;
+                }
+            }
+            Err(e) => st.failed = Some(e.to_string()),
+        }
+        self.cond.notify_all();
+        st
+    }
+
+    fn sync_loop(&self, every: Duration) {
+        let mut st = self.state.lock();
+        while !st.shutdown {
+            self.cond.wait_for(&mut st, every);
+            if st.shutdown || st.failed.is_some() { break; }
+            if st.flushing || st.synced_lsn == st.next_lsn - 1 { continue; }
+            st = self.lead_flush(st, true);
+        }
+    }
+}
+
+fn segment_path(dir: &Path, first_lsn: Lsn) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_lsn, SEGMENT_EXT))
+}
+
+fn list_segments(dir: &Path) -> Result<Vec<Lsn>> {
+    let mut out = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) { continue; }
+        if let Some(first) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<Lsn>().ok()) {
+            out.push(first);
         }
-        Ok(out)
     }
+    out.sort_unstable();
+    Ok(out)
+}
+
+// Makes segment creation and deletion durable.
+pub(crate) fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn encode_frame(lsn: Lsn, rtype: RecordType, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&[0u8; 4]);
+    frame.push(rtype as u8);
+    frame.extend_from_slice(&lsn.to_le_bytes());
+    frame.extend_from_slice(body);
+    let sum = simd_adler32::adler32(&&frame[8..]);
+    frame[4..8].copy_from_slice(&sum.to_le_bytes());
+    frame
+}
+
+// Returns the valid frames and the length of the valid prefix. Scanning stops
+// at a short header, a short body, a checksum mismatch, an unknown record type
+// or an lsn that is not above `last_lsn`.
+fn scan(data: &[u8], mut last_lsn: Lsn) -> (Vec<Frame<'_>>, usize) {
+    let mut frames = Vec::new();
+    let mut off = 0usize;
+    while data.len() - off >= HEADER_LEN {
+        let h = &data[off..off + HEADER_LEN];
+        let len = u32::from_le_bytes(h[0..4].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(h[4..8].try_into().unwrap());
+        let lsn = u64::from_le_bytes(h[9..17].try_into().unwrap());
+        if len > MAX_BODY_LEN || data.len() - off - HEADER_LEN < len { break; }
+        let end = off + HEADER_LEN + len;
+        if simd_adler32::adler32(&&data[off + 8..end]) != sum { break; }
+        let Some(rtype) = RecordType::from_u8(h[8]) else { break };
+        if lsn <= last_lsn { break; }
+        frames.push(Frame { lsn, rtype, body: &data[off + HEADER_LEN..end] });
+        last_lsn = lsn;
+        off = end;
+    }
+    (frames, off)
 }
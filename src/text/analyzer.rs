@@ -0,0 +1,32 @@
+use super::porter::stem;
+
+const STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+// Characters that glue identifiers together ("INV-2024-0042", "E_CONN_RESET").
+fn is_joiner(c: char) -> bool { matches!(c, '-' | '_' | '.' | '/' | ':' | '#') }
+
+// Index terms for `text`, in order and with repeats. Plain words are
+// lowercased, stop-listed and stemmed. Identifiers (tokens with digits or
+// joiners) are kept whole, unstemmed, and also split into their parts, so
+// both "inv-2024-0042" and "0042" find the invoice.
+pub fn analyze(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    for raw in text.split(|c: char| !(c.is_alphanumeric() || is_joiner(c))) {
+        let token = raw.trim_matches(is_joiner).to_lowercase();
+        if token.is_empty() { continue; }
+        if token.chars().all(char::is_alphabetic) {
+            if !STOPWORDS.contains(&token.as_str()) { terms.push(stem(&token)); }
+            continue;
+        }
+        let parts: Vec<&str> = token.split(is_joiner).filter(|p| p.chars().count() > 1).collect();
+        terms.push(token.clone());
+        if parts.len() > 1 || parts.first().is_some_and(|p| *p != token) {
+            terms.extend(parts.into_iter().map(str::to_string));
+        }
+    }
+    terms
+}
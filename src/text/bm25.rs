@@ -0,0 +1,170 @@
+use std::collections::{HashMap, HashSet};
+use crate::mvcc::covers;
+use crate::types::{RowKey, Timestamp};
+use super::analyzer::analyze;
+
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+// One indexed version of a row's text, valid over [begin_ts, end_ts).
+#[derive(Clone, Debug)]
+struct Doc {
+    key: RowKey,
+    begin_ts: Timestamp,
+    end_ts: Option<Timestamp>,
+    // number of terms
+    len: u32,
+}
+
+// Doc ids of one term in ascending order, with their term frequencies, as
+// varint (doc id delta, tf) pairs.
+#[derive(Default)]
+struct Postings {
+    bytes: Vec<u8>,
+    last_doc: u32,
+    count: u32,
+}
+
+impl Postings {
+    fn push(&mut self, doc: u32, tf: u32) {
+        put_varint(&mut self.bytes, doc - self.last_doc);
+        put_varint(&mut self.bytes, tf);
+        self.last_doc = doc;
+        self.count += 1;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let mut pos = 0;
+        let mut doc = 0;
+        std::iter::from_fn(move || {
+            if pos >= self.bytes.len() { return None; }
+            doc += get_varint(&self.bytes, &mut pos);
+            Some((doc, get_varint(&self.bytes, &mut pos)))
+        })
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u32) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn get_varint(data: &[u8], pos: &mut usize) -> u32 {
+    let mut v = 0u32;
+    let mut shift = 0;
+    while let Some(&b) = data.get(*pos) {
+        *pos += 1;
+        v |= ((b & 0x7f) as u32) << shift;
+        if b & 0x80 == 0 { break; }
+        shift += 7;
+    }
+    v
+}
+
+// Inverted index over row text with BM25 ranking. Like the vector catalog it
+// keeps one doc per row version, so searches can run as of any retained
+// timestamp; `gc` drops docs no snapshot can see any more.
+#[derive(Default)]
+pub struct TextIndex {
+    // by doc id; None once collected
+    docs: Vec<Option<Doc>>,
+    // doc id of each key's open version
+    current: HashMap<RowKey, u32>,
+    postings: HashMap<String, Postings>,
+    // collected docs still referenced from postings
+    dead: usize,
+}
+
+impl TextIndex {
+    pub fn new() -> Self { Self::default() }
+
+    // Retained docs, open or closed.
+    pub fn len(&self) -> usize { self.docs.len() - self.docs.iter().filter(|d| d.is_none()).count() }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+    pub fn terms(&self) -> usize { self.postings.len() }
+    pub fn postings_bytes(&self) -> usize { self.postings.values().map(|p| p.bytes.len()).sum() }
+
+    // Indexes `text` as the key's version from `ts` on, closing the previous one.
+    pub fn add(&mut self, key: RowKey, ts: Timestamp, text: &str) {
+        self.close(&key, ts);
+        let terms = analyze(text);
+        let doc = self.docs.len() as u32;
+        let mut tfs: HashMap<String, u32> = HashMap::new();
+        for t in &terms { *tfs.entry(t.clone()).or_default() += 1; }
+        for (term, tf) in tfs { self.postings.entry(term).or_default().push(doc, tf); }
+        self.docs.push(Some(Doc { key: key.clone(), begin_ts: ts, end_ts: None, len: terms.len() as u32 }));
+        self.current.insert(key, doc);
+    }
+
+    // Ends the key's current doc at `ts` (update without text, delete).
+    pub fn close(&mut self, key: &RowKey, ts: Timestamp) {
+        if let Some(id) = self.current.remove(key) {
+            if let Some(Some(d)) = self.docs.get_mut(id as usize) { d.end_ts = Some(ts); }
+        }
+    }
+
+    // Forgets docs that ended at or before `horizon`; returns how many.
+    // Postings are rewritten once a quarter of their entries are dead.
+    pub fn gc(&mut self, horizon: Timestamp) -> usize {
+        let mut n = 0;
+        for slot in &mut self.docs {
+            if slot.as_ref().is_some_and(|d| d.end_ts.is_some_and(|e| e <= horizon)) {
+                *slot = None;
+                n += 1;
+            }
+        }
+        self.dead += n;
+        if self.dead * 4 > self.postings.values().map(|p| p.count as usize).sum::<usize>().max(1) {
+            let docs = &self.docs;
+            for p in self.postings.values_mut() {
+                let mut live = Postings::default();
+                for (doc, tf) in p.iter().filter(|(d, _)| docs[*d as usize].is_some()) { live.push(doc, tf); }
+                *p = live;
+            }
+            self.postings.retain(|_, p| p.count > 0);
+            self.dead = 0;
+        }
+        n
+    }
+
+    // Top-k keys by BM25 score for `query` among the docs live at `ts`.
+    // Document counts and lengths are taken at `ts` as well, so an AS OF
+    // search ranks exactly as it would have then.
+    pub fn search(&self, query: &str, k: usize, ts: Timestamp) -> Vec<(RowKey, f32)> {
+        self.search_where(query, k, ts, |_| true)
+    }
+
+    // Like `search`, among the keys `keep` accepts. Collection statistics
+    // still cover every live doc, so a kept doc scores as it would unfiltered.
+    pub fn search_where(&self, query: &str, k: usize, ts: Timestamp, keep: impl Fn(&RowKey) -> bool) -> Vec<(RowKey, f32)> {
+        let visible = |id: u32| self.docs[id as usize].as_ref().filter(|d| covers(d.begin_ts, d.end_ts, ts));
+        let (n, total_len) = self.docs.iter().flatten()
+            .filter(|d| covers(d.begin_ts, d.end_ts, ts))
+            .fold((0usize, 0u64), |(n, l), d| (n + 1, l + d.len as u64));
+        if n == 0 { return Vec::new(); }
+        let avgdl = total_len as f32 / n as f32;
+        let mut scores: HashMap<u32, f32> = HashMap::new();
+        let mut seen = HashSet::new();
+        for term in analyze(query).into_iter().filter(|t| seen.insert(t.clone())) {
+            let Some(p) = self.postings.get(&term) else { continue };
+            let hits: Vec<(u32, u32, u32)> = p.iter()
+                .filter_map(|(doc, tf)| visible(doc).map(|d| (doc, tf, d.len)))
+                .collect();
+            let df = hits.len() as f32;
+            let idf = (1.0 + (n as f32 - df + 0.5) / (df + 0.5)).ln();
+            for (doc, tf, len) in hits {
+                let tf = tf as f32;
+                *scores.entry(doc).or_default() += idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * len as f32 / avgdl));
+            }
+        }
+        let mut hits: Vec<(RowKey, f32)> = scores.into_iter()
+            .filter_map(|(doc, s)| visible(doc).filter(|d| keep(&d.key)).map(|d| (d.key.clone(), s)))
+            .collect();
+        hits.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.0.cmp(&b.0)));
+        hits.truncate(k);
+        hits
+    }
+}
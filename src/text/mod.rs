@@ -0,0 +1,5 @@
+pub mod porter;
+
+pub mod analyzer;
+
+pub mod bm25;
@@ -0,0 +1,115 @@
+// Porter (1980) stemmer for lowercase ASCII English words. Anything else is
+// returned unchanged.
+
+fn is_cons(w: &[u8], i: usize) -> bool {
+    match w[i] {
+        b'a' | b'e' | b'i' | b'o' | b'u' => false,
+        // y is a vowel after a consonant
+        b'y' => i == 0 || !is_cons(w, i - 1),
+        _ => true,
+    }
+}
+
+// Number of vowel-consonant sequences in `w`: [C](VC)^m[V].
+fn measure(w: &[u8]) -> usize {
+    let mut m = 0;
+    let mut prev_vowel = false;
+    for i in 0..w.len() {
+        let cons = is_cons(w, i);
+        if cons && prev_vowel { m += 1; }
+        prev_vowel = !cons;
+    }
+    m
+}
+
+fn has_vowel(w: &[u8]) -> bool { (0..w.len()).any(|i| !is_cons(w, i)) }
+
+fn ends_double_cons(w: &[u8]) -> bool {
+    let n = w.len();
+    n >= 2 && w[n - 1] == w[n - 2] && is_cons(w, n - 1)
+}
+
+// consonant-vowel-consonant, the last not w, x or y
+fn ends_cvc(w: &[u8]) -> bool {
+    let n = w.len();
+    n >= 3 && is_cons(w, n - 3) && !is_cons(w, n - 2) && is_cons(w, n - 1) && !matches!(w[n - 1], b'w' | b'x' | b'y')
+}
+
+// Replaces the first listed suffix `w` ends with, if the stem left in front
+// of it passes `cond`. Later suffixes are not tried either way, so rules are
+// listed longest first.
+fn replace_suffix(w: &mut Vec<u8>, rules: &[(&str, &str)], cond: impl Fn(&[u8], &str) -> bool) {
+    for (suffix, repl) in rules {
+        if w.ends_with(suffix.as_bytes()) {
+            let stem = w.len() - suffix.len();
+            if cond(&w[..stem], suffix) {
+                w.truncate(stem);
+                w.extend_from_slice(repl.as_bytes());
+            }
+            return;
+        }
+    }
+}
+
+pub fn stem(word: &str) -> String {
+    if word.len() <= 2 || !word.bytes().all(|b| b.is_ascii_lowercase()) { return word.to_string(); }
+    let mut w = word.as_bytes().to_vec();
+
+    // step 1a: plurals
+    replace_suffix(&mut w, &[("sses", "ss"), ("ies", "i"), ("ss", "ss"), ("s", "")], |_, _| true);
+
+    // step 1b: -eed, -ed, -ing
+    if w.ends_with(b"eed") {
+        if measure(&w[..w.len() - 3]) > 0 { w.pop(); }
+    } else {
+        let cut = [&b"ed"[..], &b"ing"[..]].into_iter()
+            .find(|s| w.ends_with(s) && has_vowel(&w[..w.len() - s.len()]));
+        if let Some(s) = cut {
+            w.truncate(w.len() - s.len());
+            if w.ends_with(b"at") || w.ends_with(b"bl") || w.ends_with(b"iz") {
+                w.push(b'e');
+            } else if ends_double_cons(&w) && !matches!(w[w.len() - 1], b'l' | b's' | b'z') {
+                w.pop();
+            } else if measure(&w) == 1 && ends_cvc(&w) {
+                w.push(b'e');
+            }
+        }
+    }
+
+    // step 1c: y -> i
+    if w.ends_with(b"y") && has_vowel(&w[..w.len() - 1]) {
+        let n = w.len();
+        w[n - 1] = b'i';
+    }
+
+    // step 2: double suffixes
+    replace_suffix(&mut w, &[
+        ("ational", "ate"), ("iveness", "ive"), ("fulness", "ful"), ("ousness", "ous"), ("ization", "ize"),
+        ("tional", "tion"), ("biliti", "ble"), ("entli", "ent"), ("ousli", "ous"), ("ation", "ate"),
+        ("alism", "al"), ("aliti", "al"), ("iviti", "ive"), ("enci", "ence"), ("anci", "ance"),
+        ("izer", "ize"), ("alli", "al"), ("ator", "ate"), ("bli", "ble"), ("eli", "e"),
+    ], |s, _| measure(s) > 0);
+
+    // step 3: -ic-, -full, -ness
+    replace_suffix(&mut w, &[
+        ("icate", "ic"), ("ative", ""), ("alize", "al"), ("iciti", "ic"), ("ical", "ic"), ("ness", ""), ("ful", ""),
+    ], |s, _| measure(s) > 0);
+
+    // step 4: strip remaining suffixes on long stems
+    replace_suffix(&mut w, &[
+        ("ement", ""), ("ance", ""), ("ence", ""), ("able", ""), ("ible", ""), ("ment", ""),
+        ("ant", ""), ("ent", ""), ("ion", ""), ("ism", ""), ("ate", ""), ("iti", ""), ("ous", ""),
+        ("ive", ""), ("ize", ""), ("al", ""), ("er", ""), ("ic", ""), ("ou", ""),
+    ], |s, suffix| measure(s) > 1 && (suffix != "ion" || matches!(s.last(), Some(b's' | b't'))));
+
+    // step 5a: final e
+    if w.ends_with(b"e") {
+        let stem = &w[..w.len() - 1];
+        let m = measure(stem);
+        if m > 1 || (m == 1 && !ends_cvc(stem)) { w.pop(); }
+    }
+    // step 5b: -ll
+    if measure(&w) > 1 && w.ends_with(b"ll") { w.pop(); }
+
+    String::from_utf8(w).unwrap_or_else(|_| word.to_string())
+}
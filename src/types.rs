@@ -4,21 +4,49 @@ use serde::{Serialize, Deserialize};
 pub type TxnId = u64;
 pub type Timestamp = u64; // logical ts
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+// Target of a time-travel read: a commit timestamp, or the state right after
+// a given transaction committed.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AsOf {
+    Ts(Timestamp),
+    Txn(TxnId),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct RowKey(pub String);
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Row {
     pub key: RowKey,
+    #[serde(with = "json_payload")]
     pub payload: serde_json::Value,
 }
 
+// bincode cannot round-trip `serde_json::Value` (it needs `deserialize_any`), so
+// binary formats (WAL, segments) carry the payload as JSON text.
+mod json_payload {
+    use serde::{Serialize, Serializer, Deserialize, Deserializer, de::Error};
+
+    pub fn serialize<S: Serializer>(v: &serde_json::Value, s: S) -> Result<S::Ok, S::Error> {
+        if s.is_human_readable() { v.serialize(s) } else { v.to_string().serialize(s) }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<serde_json::Value, D::Error> {
+        if d.is_human_readable() { return serde_json::Value::deserialize(d); }
+        let text = String::deserialize(d)?;
+        serde_json::from_str(&text).map_err(D::Error::custom)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VersionedRow {
     pub begin_ts: Timestamp,
     pub end_ts: Option<Timestamp>,
     pub txn_id: TxnId,
     pub row: Row,
+    // tombstone: the key was deleted at `begin_ts`; `row.payload` is null
+    #[serde(default)]
+    pub deleted: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
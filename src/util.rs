@@ -3,8 +3,11 @@ use std::path::PathBuf;
 
 pub fn save_json<T: serde::Serialize>(path: PathBuf, value: &T) -> Result<()> {
     if let Some(parent) = path.parent() { std::fs::create_dir_all(parent)?; }
+    // temp file + rename, so a crash never leaves a half-written file
     let data = serde_json::to_vec_pretty(value)?;
-    std::fs::write(path, data)?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(tmp, path)?;
     Ok(())
 }
 
@@ -1,41 +1,111 @@
 
 use crate::types::{Vector};
+use anyhow::Result;
+use crate::vector::quant::{self, Quantized, Quantizer, RawVectors, Scorer, Stored};
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use roaring::RoaringTreemap;
 
 pub struct FlatIndex {
     pub dims: usize,
-    items: Vec<(u64, Vector)>, // id -> vector
+    items: Vec<(u64, Stored)>, // id -> vector
+    positions: HashMap<u64, usize>, // id -> slot in items
+    quantized: Option<Quantized>,
 }
 
 impl FlatIndex {
-    pub fn new(dims: usize) -> Self { Self { dims, items: Vec::new() } }
+    pub fn new(dims: usize) -> Self { Self { dims, items: Vec::new(), positions: HashMap::new(), quantized: None } }
 
-    pub fn add(&mut self, id: u64, v: Vector) {
-        self.items.push((id, v));
+    // Keeps vectors added from now on as codes of `quantizer`, with the full
+    // vectors in `raw`. Searches score the codes and re-rank the best
+    // `DEFAULT_RERANK * k` exactly.
+    pub fn with_quantizer(mut self, quantizer: Quantizer, raw: RawVectors) -> Self {
+        self.quantized = Some(Quantized::new(quantizer, raw, quant::DEFAULT_RERANK));
+        self
     }
 
-    pub fn cosine_topk(&self, q: &Vector, k: usize) -> Vec<(u64, f32)> {
-        let mut scores: Vec<(u64, f32)> = self.items.iter()
-            .map(|(id, v)| (*id, cosine(&q.0, &v.0))).collect();
-        scores.sort_by(|a,b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
-        scores.truncate(k);
-        scores
+    // Candidates per hit re-ranked exactly; only used with a quantizer.
+    pub fn with_rerank(mut self, rerank: usize) -> Self {
+        if let Some(z) = &mut self.quantized { z.rerank = rerank.max(1); }
+        self
     }
 
+    pub fn len(&self) -> usize { self.items.len() }
+    pub fn is_empty(&self) -> bool { self.items.is_empty() }
+    pub fn is_quantized(&self) -> bool { self.quantized.is_some() }
+
+    // Adds `v` under `id`, replacing any vector already stored for it.
+    pub fn add(&mut self, id: u64, v: Vector) -> Result<()> {
+        let stored = match &self.quantized {
+            Some(z) => z.store(v)?,
+            None => Stored::raw(v),
+        };
+        match self.positions.get(&id) {
+            Some(&pos) => self.items[pos].1 = stored,
+            None => {
+                self.positions.insert(id, self.items.len());
+                self.items.push((id, stored));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: u64) -> Result<Option<Vector>> {
+        let Some(pos) = self.positions.remove(&id) else { return Ok(None) };
+        let (_, v) = self.items.swap_remove(pos);
+        if let Some((moved, _)) = self.items.get(pos) { self.positions.insert(*moved, pos); }
+        v.exact(self.quantized.as_ref()).map(Some)
+    }
+
+    // The full vector, read back from the raw file when quantized.
+    pub fn get(&self, id: u64) -> Result<Option<Vector>> {
+        self.positions.get(&id).map(|&pos| self.items[pos].1.exact(self.quantized.as_ref())).transpose()
+    }
+
+    pub fn cosine_topk(&self, q: &Vector, k: usize) -> Result<Vec<(u64, f32)>> {
+        self.cosine_topk_filtered(q, k, |_| true)
+    }
+
+    // Top-k among the ids `keep` accepts; the others are not scored.
+    pub fn cosine_topk_filtered(&self, q: &Vector, k: usize, keep: impl Fn(u64) -> bool) -> Result<Vec<(u64, f32)>> {
+        self.ranked(q, k, self.items.iter().filter(|(id, _)| keep(*id)))
+    }
+
+    // Top-k among `allowed`. A bitmap smaller than the index is walked
+    // instead of the index.
+    pub fn cosine_topk_in(&self, q: &Vector, k: usize, allowed: &RoaringTreemap) -> Result<Vec<(u64, f32)>> {
+        if allowed.len() >= self.items.len() as u64 {
+            return self.cosine_topk_filtered(q, k, |id| allowed.contains(id));
+        }
+        self.ranked(q, k, allowed.iter().filter_map(|id| self.positions.get(&id).map(|&pos| &self.items[pos])))
+    }
+
+    // Scores of every vector; approximate for codes.
     pub fn cosine_scores_all(&self, q: &Vector) -> Vec<(u64, f32)> {
-        self.items.iter().map(|(id, v)| (*id, cosine(&q.0, &v.0))).collect()
+        let scorer = Scorer::new(Cow::Borrowed(&q.0), self.quantized.as_ref());
+        self.items.iter().map(|(id, v)| (*id, scorer.cosine(v))).collect()
+    }
+
+    // With a quantizer, the best `rerank * k` by code score are re-scored
+    // with their full vectors.
+    fn ranked<'a>(&'a self, q: &Vector, k: usize, items: impl Iterator<Item = &'a (u64, Stored)>) -> Result<Vec<(u64, f32)>> {
+        let scorer = Scorer::new(Cow::Borrowed(&q.0), self.quantized.as_ref());
+        let scores = items.map(|(id, v)| (*id, scorer.cosine(v))).collect();
+        match &self.quantized {
+            None => Ok(top(scores, k)),
+            Some(z) => {
+                let candidates = top(scores, k.saturating_mul(z.rerank)).into_iter()
+                    .map(|(id, _)| Ok((id, self.items[self.positions[&id]].1.exact(Some(z))?)))
+                    .collect::<Result<_>>()?;
+                Ok(quant::rerank(&q.0, k, candidates))
+            }
+        }
     }
 }
 
-fn cosine(a: &Vec<f32>, b: &Vec<f32>) -> f32 {
-    let mut dot = 0.0;
-    let mut na = 0.0;
-    let mut nb = 0.0;
-    for i in 0..a.len() {
-        dot += a[i]*b[i];
-        na += a[i]*a[i];
-        nb += b[i]*b[i];
-    }
-    if na == 0.0 || nb == 0.0 { return 0.0; }
-    dot / (na.sqrt()*nb.sqrt())
+fn top(mut scores: Vec<(u64, f32)>, k: usize) -> Vec<(u64, f32)> {
+    scores.sort_by(|a,b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    scores.truncate(k);
+    scores
 }
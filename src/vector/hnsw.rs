@@ -1,153 +1,733 @@
-
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use parking_lot::{Mutex, RwLock};
+use roaring::RoaringTreemap;
+use anyhow::Result;
 use crate::types::Vector;
-use rand::{Rng, seq::SliceRandom};
-use smallvec::SmallVec;
+use crate::vector::quant::{self, Quantized, Quantizer, RawVectors, Scorer, Stored};
 use serde::{Serialize, Deserialize};
 
-// A minimal, educational HNSW-like graph for approximate search.
-// Not production-ready; good for demos and API shape.
+// Hierarchical navigable small world graph (Malkov & Yashunin) over cosine
+// similarity. Every node lives on layer 0 and, with geometrically falling
+// probability, on the layers above it; searches descend greedily from the
+// sparse top layer and finish with a best-first search on layer 0.
+// Removed vectors stay in the graph as tombstones that searches walk through
+// but never return, until `vacuum` rebuilds the graph without them.
+//
+// Everything but `vacuum` takes &self. Searches never wait on a writer for
+// longer than one neighbor list copy: nodes sit in an append-only arena and
+// each node's links on each layer have their own lock. Writers (add, remove)
+// are serialized among themselves.
+//
+// With a quantizer (`with_quantizer`) nodes hold codes instead of vectors;
+// searches walk the graph on code scores and re-rank their best candidates
+// with the full vectors from the raw file.
+
+const DEFAULT_EF_CONSTRUCTION: usize = 100;
+// "HNS5": HNS3's layered graph with tombstones plus the quantizer, if any,
+// and codes instead of vectors. HNS4 (full vectors next to the quantizer)
+// and HNS3 snapshots are still read; older ones (a single flat graph, or
+// HNS2 without tombstones) are not
+const SNAPSHOT_MAGIC: &[u8; 4] = b"HNS5";
+const SNAPSHOT_MAGIC_V4: &[u8; 4] = b"HNS4";
+const SNAPSHOT_MAGIC_V3: &[u8; 4] = b"HNS3";
+// `topk_in` ranks the allowed ids directly below this share of the index
+const EXACT_FILTER_SHARE: f64 = 0.05;
 
-#[derive(Clone, Serialize, Deserialize)]
 struct Node {
     id: u64,
-    vec: Vector,
-    neighbors: Vec<usize>, // indices into nodes (serde-friendly)
-    level: i32,
+    vec: Stored,
+    // neighbors[layer], indices into nodes; layers 0..=level
+    neighbors: Box<[RwLock<Vec<usize>>]>,
+    // linked_from[layer]: the nodes whose links on that layer include this
+    // one; only used and changed under the writer lock
+    linked_from: Box<[Mutex<Vec<usize>>]>,
+    deleted: AtomicBool,
+}
+
+impl Node {
+    fn new(id: u64, vec: Stored, neighbors: Vec<Vec<usize>>, deleted: bool) -> Self {
+        let linked_from = neighbors.iter().map(|_| Mutex::new(Vec::new())).collect();
+        Self { id, vec, neighbors: neighbors.into_iter().map(RwLock::new).collect(), linked_from, deleted: AtomicBool::new(deleted) }
+    }
+
+    fn level(&self) -> usize { self.neighbors.len() - 1 }
+    fn is_deleted(&self) -> bool { self.deleted.load(AtomicOrdering::Acquire) }
+    fn links(&self, layer: usize) -> Vec<usize> { self.neighbors[layer].read().clone() }
+}
+
+// Chunk c holds 64 << c slots, so slots never move and 32 chunks are
+// plenty. A node is pushed before anything links to it, so readers
+// following links always find its slot filled.
+const FIRST_CHUNK_BITS: u32 = 6;
+const CHUNKS: usize = 32;
+
+struct Arena {
+    chunks: [OnceLock<Box<[OnceLock<Node>]>>; CHUNKS],
+    len: AtomicUsize,
+}
+
+impl Arena {
+    fn new() -> Self { Self { chunks: std::array::from_fn(|_| OnceLock::new()), len: AtomicUsize::new(0) } }
+
+    fn len(&self) -> usize { self.len.load(AtomicOrdering::Acquire) }
+
+    fn slot(i: usize) -> (usize, usize) {
+        let j = i + (1 << FIRST_CHUNK_BITS);
+        let chunk = (usize::BITS - 1 - j.leading_zeros() - FIRST_CHUNK_BITS) as usize;
+        (chunk, j - (1 << (chunk as u32 + FIRST_CHUNK_BITS)))
+    }
+
+    // Callers hold the writer lock.
+    fn push(&self, node: Node) -> usize {
+        let i = self.len.load(AtomicOrdering::Relaxed);
+        let (c, off) = Self::slot(i);
+        let chunk = self.chunks[c].get_or_init(|| (0..1usize << (c as u32 + FIRST_CHUNK_BITS)).map(|_| OnceLock::new()).collect());
+        let _ = chunk[off].set(node);
+        self.len.store(i + 1, AtomicOrdering::Release);
+        i
+    }
+
+    fn get(&self, i: usize) -> &Node {
+        let (c, off) = Self::slot(i);
+        self.chunks[c].get().and_then(|chunk| chunk[off].get()).expect("hnsw node linked before it was pushed")
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Node> + '_ { (0..self.len()).map(|i| self.get(i)) }
 }
 
-#[derive(Serialize, Deserialize)]
 pub struct HnswIndex {
     pub dims: usize,
-    pub m: usize,          // max neighbors per level
-    pub ef: usize,         // search breadth
-    entry: Option<usize>,  // entry point index
-    nodes: Vec<Node>,
+    pub m: usize,               // max neighbors per node above layer 0 (2*m on layer 0)
+    pub ef: usize,              // ef_search: candidate list size for queries
+    pub ef_construction: usize, // candidate list size while inserting
+    nodes: Arena,
+    // entry point index, on the top layer; never a tombstone
+    entry: RwLock<Option<usize>>,
+    // node index of each live id
+    ids: RwLock<HashMap<u64, usize>>,
+    // held by add and remove; guards the level sampler's state, so builds
+    // are reproducible
+    writer: Mutex<u64>,
+    // bumped by every add and remove, so a copy rebuilt meanwhile can tell
+    // it is stale; see `Engine::gc`
+    mutations: AtomicU64,
+    quantized: Option<Quantized>,
+}
+
+// A node index ordered by similarity to the query.
+#[derive(Clone, Copy, PartialEq)]
+struct Scored(f32, usize);
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering { self.0.total_cmp(&other.0).then(self.1.cmp(&other.1)) }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
 }
 
 impl HnswIndex {
     pub fn new(dims: usize, m: usize, ef: usize) -> Self {
-        Self { dims, m, ef, entry: None, nodes: Vec::new() }
+        Self {
+            dims,
+            m: m.max(2),
+            ef,
+            ef_construction: DEFAULT_EF_CONSTRUCTION.max(ef),
+            nodes: Arena::new(),
+            entry: RwLock::new(None),
+            ids: RwLock::new(HashMap::new()),
+            writer: Mutex::new(0x9e37_79b9_7f4a_7c15),
+            mutations: AtomicU64::new(0),
+            quantized: None,
+        }
+    }
+
+    pub fn with_ef_construction(mut self, ef_construction: usize) -> Self {
+        self.ef_construction = ef_construction.max(1);
+        self
+    }
+
+    // Keeps vectors added from now on as codes of `quantizer`, with the full
+    // vectors in `raw`; see `quantized` to convert an index that has some.
+    pub fn with_quantizer(mut self, quantizer: Quantizer, raw: RawVectors) -> Self {
+        self.quantized = Some(Quantized::new(quantizer, raw, quant::DEFAULT_RERANK));
+        self
+    }
+
+    // Candidates per hit re-ranked exactly; only used with a quantizer.
+    pub fn with_rerank(mut self, rerank: usize) -> Self {
+        if let Some(z) = &mut self.quantized { z.rerank = rerank.max(1); }
+        self
+    }
+
+    pub fn is_quantized(&self) -> bool { self.quantized.is_some() }
+
+    pub fn quantizer(&self) -> Option<&Quantizer> { self.quantized.as_ref().map(|z| &*z.quantizer) }
+
+    // Live vectors; tombstones are not counted.
+    pub fn len(&self) -> usize { self.ids.read().len() }
+    pub fn is_empty(&self) -> bool { self.ids.read().is_empty() }
+    pub fn contains(&self, id: u64) -> bool { self.ids.read().contains_key(&id) }
+    pub fn tombstones(&self) -> usize { self.nodes.len() - self.len() }
+    pub fn mutations(&self) -> u64 { self.mutations.load(AtomicOrdering::Acquire) }
+
+    // Share of the graph's nodes that are tombstones.
+    pub fn tombstone_ratio(&self) -> f64 {
+        let total = self.nodes.len();
+        if total == 0 { 0.0 } else { self.tombstones() as f64 / total as f64 }
     }
 
-    pub fn len(&self) -> usize { self.nodes.len() }
+    // The live vector stored under `id`.
+    pub fn get(&self, id: u64) -> Result<Option<Vector>> {
+        let Some(idx) = self.ids.read().get(&id).copied() else { return Ok(None) };
+        self.nodes.get(idx).vec.exact(self.quantized.as_ref()).map(Some)
+    }
+
+    pub fn ids(&self) -> Vec<u64> { self.ids.read().keys().copied().collect() }
+
+    // Up to `n` live vectors spread evenly over insertion order, to train a
+    // quantizer on.
+    pub fn sample(&self, n: usize) -> Result<Vec<Vector>> {
+        let live: Vec<&Node> = self.nodes.iter().filter(|n| !n.is_deleted()).collect();
+        let step = live.len().div_ceil(n.max(1)).max(1);
+        live.into_iter().step_by(step).map(|n| n.vec.exact(self.quantized.as_ref())).collect::<Result<_>>()
+    }
+
+    // Number of layers in the graph.
+    pub fn levels(&self) -> usize { self.entry.read().map_or(0, |e| self.nodes.get(e).level() + 1) }
+
+    fn max_neighbors(&self, layer: usize) -> usize { if layer == 0 { 2 * self.m } else { self.m } }
+
+    // Inserts `v` under `id`, replacing the vector `id` had. Fails, leaving
+    // the index as it was, if a quantized index cannot write `v` to its raw
+    // file.
+    pub fn add(&self, id: u64, v: Vector) -> Result<()> {
+        let q = v.0.clone();
+        let stored = match &self.quantized {
+            Some(z) => z.store(v)?,
+            None => Stored::raw(v),
+        };
+        self.insert(id, stored, q);
+        Ok(())
+    }
 
-    pub fn add(&mut self, id: u64, v: Vector) {
-        let level = self.sample_level();
-        let node_idx = self.nodes.len();
-        let node = Node { id, vec: v, neighbors: Vec::new(), level };
-        self.nodes.push(node);
+    // Like `add`, but keeps `v` uncoded in memory even in a quantized index,
+    // for callers that must not lose a vector `add` failed to store.
+    pub fn add_uncoded(&self, id: u64, v: Vector) {
+        let q = v.0.clone();
+        self.insert(id, Stored::raw(v), q);
+    }
 
-        if self.entry.is_none() {
-            self.entry = Some(node_idx);
+    // Links in `stored`, whose full vector is `q`.
+    fn insert(&self, id: u64, stored: Stored, q: Vec<f32>) {
+        let mut seed = self.writer.lock();
+        self.remove_locked(id);
+        self.mutations.fetch_add(1, AtomicOrdering::AcqRel);
+        let level = sample_level(&mut seed, self.m);
+        let q = Scorer::new(Cow::Owned(q), self.quantized.as_ref());
+        let idx = self.nodes.push(Node::new(id, stored, vec![Vec::new(); level + 1], false));
+        self.ids.write().insert(id, idx);
+        let entry = *self.entry.read();
+        let Some(entry) = entry else {
+            *self.entry.write() = Some(idx);
             return;
+        };
+        let top = self.nodes.get(entry).level();
+        let live = |n: &Node| !n.is_deleted();
+        let mut eps = vec![Scored(self.sim(&q, entry), entry)];
+        // greedy descent through the layers above the new node's
+        for layer in (level + 1..=top).rev() {
+            eps = self.search_layer(&q, &eps, 1, layer, &live);
+        }
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&q, &eps, self.ef_construction, layer, &live);
+            let selected = self.select_neighbors(&found, self.m);
+            // links out before links in, so a search that reaches the new
+            // node can go on from it
+            self.set_links(idx, layer, selected.iter().map(|s| s.1).collect());
+            for s in &selected {
+                let n = s.1;
+                let mut links = self.nodes.get(n).links(layer);
+                links.push(idx);
+                let full = links.len() > self.max_neighbors(layer);
+                self.set_links(n, layer, links);
+                if full { self.shrink(n, layer); }
+            }
+            eps = found;
         }
+        if level > top { *self.entry.write() = Some(idx); }
+    }
 
-        // greedy search from entry
-        let mut cur = self.entry.unwrap();
-        let mut improved = true;
-        while improved {
-            improved = false;
-            let (best_idx, best_sim) = (cur, cosine(&self.nodes[cur].vec.0, &self.nodes[node_idx].vec.0));
-            for &n in &self.nodes[cur].neighbors {
-                let s = cosine(&self.nodes[n].vec.0, &self.nodes[node_idx].vec.0);
-                if s > best_sim {
-                    cur = n;
-                    improved = true;
-                }
+    // Replaces the vector stored under `id` (inserting it if missing).
+    pub fn update(&self, id: u64, v: Vector) -> Result<()> { self.add(id, v) }
+
+    // Marks `id`'s node deleted and repairs the graph around it: every live
+    // node that linked to it is relinked, with the heuristic, among its
+    // other neighbors and the deleted node's, so no live node is left
+    // linking to a tombstone. Returns false if `id` is not in the index.
+    pub fn remove(&self, id: u64) -> bool {
+        let _writer = self.writer.lock();
+        self.remove_locked(id)
+    }
+
+    fn remove_locked(&self, id: u64) -> bool {
+        let removed = self.ids.write().remove(&id);
+        let Some(idx) = removed else { return false };
+        self.mutations.fetch_add(1, AtomicOrdering::AcqRel);
+        self.nodes.get(idx).deleted.store(true, AtomicOrdering::Release);
+        self.unlink(idx);
+        let mut entry = self.entry.write();
+        if *entry == Some(idx) {
+            // the live node on the most layers takes over
+            *entry = self.ids.read().values().copied().max_by_key(|&i| (self.nodes.get(i).level(), Reverse(i)));
+        }
+        true
+    }
+
+    // Relinks every live node that links to the tombstone `idx`.
+    fn unlink(&self, idx: usize) {
+        let node = self.nodes.get(idx);
+        for layer in 0..=node.level() {
+            let around = node.links(layer);
+            let linking = node.linked_from[layer].lock().clone();
+            for n in linking {
+                let nb = self.nodes.get(n);
+                if nb.is_deleted() { continue; }
+                let links = nb.links(layer);
+                let base = Scorer::of(&nb.vec, self.quantized.as_ref());
+                let mut pool: Vec<usize> = links.iter().chain(&around).copied()
+                    .filter(|&x| x != n && !self.nodes.get(x).is_deleted())
+                    .collect();
+                pool.sort_unstable();
+                pool.dedup();
+                let mut scored: Vec<Scored> = pool.into_iter().map(|x| Scored(self.sim(&base, x), x)).collect();
+                scored.sort_by(|a, b| b.cmp(a));
+                let kept = self.select_neighbors(&scored, self.max_neighbors(layer));
+                self.set_links(n, layer, kept.into_iter().map(|s| s.1).collect());
             }
         }
+    }
+
+    // The same index rebuilt from its live vectors, in insertion order.
+    // A quantized index copies them to a new raw file, so the slots of
+    // replaced and removed vectors are dropped with the old one.
+    pub fn vacuumed(&self) -> Result<HnswIndex> {
+        let mut fresh = self.empty_copy();
+        fresh.quantized = self.quantized.as_ref().map(Quantized::with_new_raw).transpose()?;
+        for node in self.nodes.iter().filter(|n| !n.is_deleted()) {
+            let vec = node.vec.exact(self.quantized.as_ref())?;
+            let stored = match &fresh.quantized {
+                Some(z) => z.store(vec.clone())?,
+                None => node.vec.clone(),
+            };
+            fresh.insert(node.id, stored, vec.0);
+        }
+        Ok(fresh)
+    }
+
+    // The same index rebuilt with its live vectors coded by `quantizer`.
+    pub fn quantized(&self, quantizer: Quantizer, raw: RawVectors) -> Result<HnswIndex> {
+        let rerank = self.quantized.as_ref().map_or(quant::DEFAULT_RERANK, |z| z.rerank);
+        let fresh = self.empty_copy().with_quantizer(quantizer, raw).with_rerank(rerank);
+        for node in self.nodes.iter().filter(|n| !n.is_deleted()) { fresh.add(node.id, node.vec.exact(self.quantized.as_ref())?)?; }
+        Ok(fresh)
+    }
+
+    fn empty_copy(&self) -> HnswIndex {
+        HnswIndex::new(self.dims, self.m, self.ef).with_ef_construction(self.ef_construction)
+    }
+
+    // Drops the tombstones by rebuilding the graph; returns how many.
+    pub fn vacuum(&mut self) -> Result<usize> {
+        let dropped = self.tombstones();
+        if dropped > 0 { *self = self.vacuumed()?; }
+        Ok(dropped)
+    }
+
+    pub fn topk(&self, q: &Vector, k: usize) -> Result<Vec<(u64, f32)>> {
+        self.topk_ef(q, k, self.ef)
+    }
+
+    // Like `topk` with an explicit ef_search (raised to k): larger values
+    // trade speed for recall.
+    pub fn topk_ef(&self, q: &Vector, k: usize, ef: usize) -> Result<Vec<(u64, f32)>> {
+        self.topk_filtered(q, k, ef, |_| true)
+    }
 
-        // connect new node to neighbors of cur
-        let mut cand: Vec<(usize, f32)> = self.nodes[cur].neighbors.iter()
-            .map(|&n| (n, cosine(&self.nodes[n].vec.0, &self.nodes[node_idx].vec.0)))
-            .collect();
-        cand.push((cur, cosine(&self.nodes[cur].vec.0, &self.nodes[node_idx].vec.0)));
-        cand.sort_by(|a,b| b.1.partial_cmp(&a.1).unwrap());
-        cand.truncate(self.m);
-
-        for (nidx, _) in cand {
-            self.nodes[node_idx].neighbors.push(nidx);
-            self.nodes[nidx].neighbors.push(node_idx);
-            if self.nodes[nidx].neighbors.len() > self.m {
-                // drop worst neighbor to keep degree bounded
-                let mut scored: Vec<(usize, f32)> = self.nodes[nidx].neighbors.iter()
-                    .map(|&x| (x, cosine(&self.nodes[nidx].vec.0, &self.nodes[x].vec.0))).collect();
-                scored.sort_by(|a,b| b.1.partial_cmp(&a.1).unwrap());
-                scored.truncate(self.m);
-                self.nodes[nidx].neighbors = scored.into_iter().map(|(x,_)| x).collect();
+    // Like `topk_ef`, returning only ids `keep` accepts. Rejected nodes are
+    // still walked through, so they do not cut the graph apart.
+    pub fn topk_filtered(&self, q: &Vector, k: usize, ef: usize, keep: impl Fn(u64) -> bool) -> Result<Vec<(u64, f32)>> {
+        let entry = *self.entry.read();
+        let Some(entry) = entry else { return Ok(vec![]) };
+        let scorer = Scorer::new(Cow::Borrowed(&q.0), self.quantized.as_ref());
+        let live = |n: &Node| !n.is_deleted();
+        let mut eps = vec![Scored(self.sim(&scorer, entry), entry)];
+        for layer in (1..=self.nodes.get(entry).level()).rev() {
+            eps = self.search_layer(&scorer, &eps, 1, layer, &live);
+        }
+        let found = self.search_layer(&scorer, &eps, ef.max(self.candidates(k)), 0, &|n: &Node| live(n) && keep(n.id));
+        self.finish(q, k, found)
+    }
+
+    // Top-k among `allowed`. When the bitmap holds only a small share of the
+    // index, its ids are ranked directly: a filtered walk would cover most
+    // of the graph to find k of them.
+    pub fn topk_in(&self, q: &Vector, k: usize, allowed: &RoaringTreemap) -> Result<Vec<(u64, f32)>> {
+        if (allowed.len() as f64) < self.len() as f64 * EXACT_FILTER_SHARE {
+            return self.rank(q, k, allowed.iter());
+        }
+        self.topk_filtered(q, k, self.ef, |id| allowed.contains(id))
+    }
+
+    // Exact top-k among `ids`; ids not in the index are skipped.
+    pub fn rank(&self, q: &Vector, k: usize, ids: impl IntoIterator<Item = u64>) -> Result<Vec<(u64, f32)>> {
+        let nodes: Vec<usize> = {
+            let map = self.ids.read();
+            ids.into_iter().filter_map(|id| map.get(&id).copied()).collect()
+        };
+        let scorer = Scorer::new(Cow::Borrowed(&q.0), self.quantized.as_ref());
+        let mut found: Vec<Scored> = nodes.into_iter().map(|i| Scored(self.sim(&scorer, i), i)).collect();
+        found.sort_by(|a, b| b.cmp(a));
+        self.finish(q, k, found)
+    }
+
+    // How many of a search's best candidates `finish` looks at for k hits.
+    fn candidates(&self, k: usize) -> usize { self.quantized.as_ref().map_or(k, |z| k.saturating_mul(z.rerank)) }
+
+    // The first k of `found` (most similar first) as hits; with a quantizer,
+    // the best k after re-scoring the first `candidates(k)` exactly.
+    fn finish(&self, q: &Vector, k: usize, mut found: Vec<Scored>) -> Result<Vec<(u64, f32)>> {
+        found.truncate(self.candidates(k));
+        match &self.quantized {
+            None => Ok(found.into_iter().map(|Scored(s, idx)| (self.nodes.get(idx).id, s)).collect()),
+            Some(z) => {
+                let exact = found.into_iter().map(|Scored(_, idx)| {
+                    let node = self.nodes.get(idx);
+                    Ok((node.id, z.exact(&node.vec)?))
+                }).collect::<Result<_>>()?;
+                Ok(quant::rerank(&q.0, k, exact))
             }
         }
     }
 
-    pub fn topk(&self, q: &Vector, k: usize) -> Vec<(u64, f32)> {
-        if self.nodes.is_empty() { return vec![]; }
-        let mut rng = rand::thread_rng();
-        let start = self.entry.unwrap_or(0);
-        // beam search
-        let mut frontier = vec![start];
+
+    // Best-first search of one layer from `eps`, keeping the `ef` most
+    // similar nodes seen that pass `keep`; stops once the closest unexpanded
+    // candidate is worse than the worst of those. Nodes `keep` rejects are
+    // expanded but not kept. Tombstones are skipped: no live node links to
+    // one, so they never bridge the graph. Returns the kept nodes, most
+    // similar first.
+    fn search_layer(&self, q: &Scorer, eps: &[Scored], ef: usize, layer: usize, keep: &dyn Fn(&Node) -> bool) -> Vec<Scored> {
         let mut visited = ahash::AHashSet::<usize>::default();
-        let mut best: Vec<(usize, f32)> = Vec::new();
-
-        while !frontier.is_empty() {
-            let cur = frontier.pop().unwrap();
-            if !visited.insert(cur) { continue; }
-
-            let s = cosine(&self.nodes[cur].vec.0, &q.0);
-            best.push((cur, s));
-            // push neighbors (shuffle to avoid bias)
-            let mut neigh = self.nodes[cur].neighbors.clone();
-            neigh.shuffle(&mut rng);
-            for n in neigh.into_iter().take(self.ef) {
-                if !visited.contains(&n) { frontier.push(n); }
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        let mut results: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for &e in eps {
+            if visited.insert(e.1) {
+                candidates.push(e);
+                if keep(self.nodes.get(e.1)) { results.push(Reverse(e)); }
             }
         }
-        best.sort_by(|a,b| b.1.partial_cmp(&a.1).unwrap());
-        best.truncate(k);
-        best.into_iter().map(|(idx, sc)| (self.nodes[idx].id, sc)).collect()
+        while results.len() > ef { results.pop(); }
+        while let Some(c) = candidates.pop() {
+            let worst = results.peek().map_or(f32::MIN, |r| r.0 .0);
+            if c.0 < worst && results.len() >= ef { break; }
+            for n in self.nodes.get(c.1).links(layer) {
+                if !visited.insert(n) || self.nodes.get(n).is_deleted() { continue; }
+                let s = Scored(self.sim(q, n), n);
+                if results.len() < ef || s.0 > results.peek().map_or(f32::MIN, |r| r.0 .0) {
+                    candidates.push(s);
+                    if !keep(self.nodes.get(n)) { continue; }
+                    results.push(Reverse(s));
+                    if results.len() > ef { results.pop(); }
+                }
+            }
+        }
+        let mut out: Vec<Scored> = results.into_iter().map(|r| r.0).collect();
+        out.sort_by(|a, b| b.cmp(a));
+        out
+    }
+
+    // Neighbor-selection heuristic: walking candidates from most similar,
+    // keep one only if it is closer to the base than to every neighbor kept
+    // so far, so links spread in different directions; fill up any room left
+    // with the pruned ones. `candidates` is sorted most similar first.
+    fn select_neighbors(&self, candidates: &[Scored], m: usize) -> Vec<Scored> {
+        let mut kept: Vec<Scored> = Vec::with_capacity(m);
+        let mut pruned = Vec::new();
+        for &c in candidates {
+            if kept.len() >= m { break; }
+            let base = Scorer::of(&self.nodes.get(c.1).vec, self.quantized.as_ref());
+            if kept.iter().all(|k| c.0 > self.sim(&base, k.1)) { kept.push(c); } else { pruned.push(c); }
+        }
+        kept.extend(pruned.into_iter().take(m - kept.len()));
+        kept
+    }
+
+    // Cuts node `n`'s links on `layer` back to the layer's maximum.
+    fn shrink(&self, n: usize, layer: usize) {
+        let node = self.nodes.get(n);
+        let base = Scorer::of(&node.vec, self.quantized.as_ref());
+        let mut scored: Vec<Scored> = node.links(layer).into_iter().map(|x| Scored(self.sim(&base, x), x)).collect();
+        scored.sort_by(|a, b| b.cmp(a));
+        let kept = self.select_neighbors(&scored, self.max_neighbors(layer));
+        self.set_links(n, layer, kept.into_iter().map(|s| s.1).collect());
     }
 
-    fn sample_level(&self) -> i32 {
-        // very rough geometric level sampler
-        let mut r = rand::thread_rng();
-        let mut level = 0;
-        while r.gen::<f32>() < 0.5 { level += 1; if level > 5 { break; } }
-        level
+    // Replaces node `n`'s links on `layer`, keeping `linked_from` in step.
+    // Callers hold the writer lock.
+    fn set_links(&self, n: usize, layer: usize, links: Vec<usize>) {
+        let old = std::mem::replace(&mut *self.nodes.get(n).neighbors[layer].write(), links.clone());
+        for &x in old.iter().filter(|x| !links.contains(x)) {
+            self.nodes.get(x).linked_from[layer].lock().retain(|&y| y != n);
+        }
+        for &x in links.iter().filter(|x| !old.contains(x)) {
+            self.nodes.get(x).linked_from[layer].lock().push(n);
+        }
     }
+
+    fn sim(&self, q: &Scorer, idx: usize) -> f32 { q.cosine(&self.nodes.get(idx).vec) }
+}
+
+// floor(-ln(U) / ln(m)), so each layer holds about 1/m of the one below
+fn sample_level(seed: &mut u64, m: usize) -> usize {
+    *seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    // uniform in (0, 1]
+    let u = ((z >> 11) + 1) as f64 / (1u64 << 53) as f64;
+    ((-u.ln() / (m as f64).ln()) as usize).min(16)
+}
+
+// On-disk form. `IndexData` is laid out as the HNS3 snapshots written
+// before the index took &self, so those stay readable. HNS5 nodes of a
+// quantized index hold their code and raw slot, and the full vectors are in
+// a raw file next to the snapshot (`snapshot_raw_path`); every other node,
+// and every node of an HNS3 or HNS4 snapshot, carries its full vector, coded
+// on load if the index is quantized.
+#[derive(Serialize, Deserialize)]
+struct NodeData<V = NodeVec> {
+    id: u64,
+    vec: V,
+    norm: f32,
+    neighbors: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+enum NodeVec {
+    Raw(Vector),
+    Code { code: Vec<u8>, slot: u64 },
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexData<V = NodeVec> {
+    dims: usize,
+    m: usize,
+    ef: usize,
+    ef_construction: usize,
+    entry: Option<usize>,
+    nodes: Vec<NodeData<V>>,
+    ids: HashMap<u64, usize>,
+    seed: u64,
+    mutations: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotData<V = NodeVec> {
+    graph: IndexData<V>,
+    // quantizer and candidates re-ranked per hit
+    quantization: Option<(Quantizer, usize)>,
 }
 
-fn cosine(a: &Vec<f32>, b: &Vec<f32>) -> f32 {
-    let mut dot = 0.0;
-    let mut na = 0.0;
-    let mut nb = 0.0;
-    for i in 0..a.len() {
-        dot += a[i]*b[i];
-        na += a[i]*a[i];
-        nb += b[i]*b[i];
+impl IndexData<Vector> {
+    fn upgrade(self) -> IndexData {
+        IndexData {
+            dims: self.dims,
+            m: self.m,
+            ef: self.ef,
+            ef_construction: self.ef_construction,
+            entry: self.entry,
+            nodes: self.nodes.into_iter().map(|n| NodeData { id: n.id, vec: NodeVec::Raw(n.vec), norm: n.norm, neighbors: n.neighbors, deleted: n.deleted }).collect(),
+            ids: self.ids,
+            seed: self.seed,
+            mutations: self.mutations,
+        }
+    }
+}
+
+// Where a snapshot at `path` of a quantized index keeps its full vectors.
+pub fn snapshot_raw_path(path: &std::path::Path) -> std::path::PathBuf { path.with_extension("f32") }
+
+// A snapshot read from disk but not built into an index yet, so the caller
+// can settle the ids it is built with first; see `Engine::open`.
+pub(crate) struct Snapshot {
+    data: SnapshotData,
+    // the raw file, for HNS5 snapshots of quantized indexes
+    raw: Option<std::path::PathBuf>,
+}
+
+impl Snapshot {
+    pub fn read(path: &std::path::Path) -> Result<Self> {
+        let mut f = std::fs::OpenOptions::new().read(true).open(path)?;
+        let mut header = [0u8; 8];
+        use std::io::Read;
+        f.read_exact(&mut header)?;
+        let magic = &header[..4];
+        if magic != SNAPSHOT_MAGIC && magic != SNAPSHOT_MAGIC_V4 && magic != SNAPSHOT_MAGIC_V3 {
+            anyhow::bail!("{} is not a layered HNSW snapshot; rebuild and save the index again", path.display());
+        }
+        let len = u32::from_le_bytes(header[4..].try_into()?) as usize;
+        let mut buf = vec![0u8; len];
+        f.read_exact(&mut buf)?;
+        let data = if magic == SNAPSHOT_MAGIC {
+            bincode::deserialize(&buf)?
+        } else if magic == SNAPSHOT_MAGIC_V4 {
+            let v4: SnapshotData<Vector> = bincode::deserialize(&buf)?;
+            SnapshotData { graph: v4.graph.upgrade(), quantization: v4.quantization }
+        } else {
+            SnapshotData { graph: bincode::deserialize::<IndexData<Vector>>(&buf)?.upgrade(), quantization: None }
+        };
+        let raw = (magic == SNAPSHOT_MAGIC && data.quantization.is_some()).then(|| snapshot_raw_path(path));
+        Ok(Self { data, raw })
+    }
+
+    // The index, with each live id renamed by `rename`; live nodes it has no
+    // new id for become tombstones. A quantized index gets a new raw file in
+    // `raw_dir`.
+    pub fn build(self, raw_dir: &std::path::Path, rename: impl Fn(u64) -> Option<u64>) -> Result<HnswIndex> {
+        let Snapshot { mut data, raw } = self;
+        let graph = &mut data.graph;
+        graph.ids.clear();
+        for (i, n) in graph.nodes.iter_mut().enumerate().filter(|(_, n)| !n.deleted) {
+            match rename(n.id) {
+                Some(id) => {
+                    n.id = id;
+                    graph.ids.insert(id, i);
+                }
+                None => n.deleted = true,
+            }
+        }
+        if graph.entry.is_some_and(|e| graph.nodes[e].deleted) {
+            // the live node on the most layers takes over
+            graph.entry = graph.nodes.iter().enumerate().filter(|(_, n)| !n.deleted)
+                .max_by_key(|(i, n)| (n.neighbors.len(), Reverse(*i))).map(|(i, _)| i);
+        }
+        HnswIndex::from_data(data, raw.as_deref(), raw_dir)
     }
-    if na == 0.0 || nb == 0.0 { return 0.0; }
-    dot / (na.sqrt()*nb.sqrt())
 }
 
 impl HnswIndex {
+    // Also returns how many raw slots the nodes' codes can refer to.
+    fn to_data(&self) -> Result<(SnapshotData, u64)> {
+        // holding the writer lock keeps the copy consistent
+        let seed = self.writer.lock();
+        let graph = IndexData {
+            dims: self.dims,
+            m: self.m,
+            ef: self.ef,
+            ef_construction: self.ef_construction,
+            entry: *self.entry.read(),
+            nodes: self.nodes.iter().map(|n| NodeData {
+                id: n.id,
+                vec: match &n.vec {
+                    Stored::Raw { vec, .. } => NodeVec::Raw(vec.clone()),
+                    Stored::Code { code, slot, .. } => NodeVec::Code { code: code.to_vec(), slot: *slot },
+                },
+                norm: n.vec.norm(),
+                neighbors: n.neighbors.iter().map(|l| l.read().clone()).collect(),
+                deleted: n.is_deleted(),
+            }).collect(),
+            ids: self.ids.read().clone(),
+            seed: *seed,
+            mutations: self.mutations(),
+        };
+        let slots = self.quantized.as_ref().map_or(0, |z| z.raw.len());
+        Ok((SnapshotData { graph, quantization: self.quantized.as_ref().map(|z| ((*z.quantizer).clone(), z.rerank)) }, slots))
+    }
+
+    // Coded nodes need `raw`, the raw file their slots point into; it is
+    // copied to a new one in `raw_dir`.
+    fn from_data(data: SnapshotData, raw: Option<&std::path::Path>, raw_dir: &std::path::Path) -> Result<Self> {
+        let SnapshotData { graph, quantization } = data;
+        let mut index = HnswIndex::new(graph.dims, graph.m, graph.ef).with_ef_construction(graph.ef_construction);
+        if let Some((quantizer, rerank)) = quantization {
+            let raw = match raw {
+                Some(src) => RawVectors::open_copy(src, raw_dir, graph.dims)?,
+                None => RawVectors::create_in(raw_dir, graph.dims)?,
+            };
+            index = index.with_quantizer(quantizer, raw).with_rerank(rerank);
+        }
+        for n in graph.nodes {
+            let vec = match (n.vec, &index.quantized) {
+                (NodeVec::Raw(vec), Some(z)) => z.store(vec)?,
+                (NodeVec::Raw(vec), None) => Stored::Raw { vec, norm: n.norm },
+                (NodeVec::Code { code, slot }, Some(_)) => Stored::Code { code: code.into(), slot, norm: n.norm },
+                (NodeVec::Code { slot, .. }, None) => anyhow::bail!("vector in raw slot {} is coded but the snapshot has no quantizer", slot),
+            };
+            index.nodes.push(Node::new(n.id, vec, n.neighbors, n.deleted));
+        }
+        for (i, node) in index.nodes.iter().enumerate() {
+            for (layer, links) in node.neighbors.iter().enumerate() {
+                for &x in links.read().iter() { index.nodes.get(x).linked_from[layer].lock().push(i); }
+            }
+        }
+        // older snapshots, and renamed ones, can still have live nodes
+        // linking to tombstones
+        for i in 0..index.nodes.len() {
+            if index.nodes.get(i).is_deleted() { index.unlink(i); }
+        }
+        *index.entry.write() = graph.entry;
+        *index.ids.write() = graph.ids;
+        *index.writer.lock() = graph.seed;
+        index.mutations.store(graph.mutations, AtomicOrdering::Release);
+        Ok(index)
+    }
+
+    // Written via a temp file and renamed into place, so `path` always holds
+    // a complete index. A quantized index writes its raw file first (see
+    // `snapshot_raw_path`).
     pub fn save_to(&self, path: std::path::PathBuf) -> anyhow::Result<()> {
-        std::fs::create_dir_all(path.parent().unwrap())?;
-        let mut f = std::fs::OpenOptions::new().create(true).write(true).open(path)?;
-        let bytes = bincode::serialize(self)?;
+        self.save_snapshot(&path).map(|_| ())
+    }
+
+    // Like `save_to`; returns the ids of the live vectors saved.
+    pub(crate) fn save_snapshot(&self, path: &std::path::Path) -> Result<Vec<u64>> {
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir)?;
+        let tmp = path.with_extension("tmp");
+        let (data, slots) = self.to_data()?;
+        let bytes = bincode::serialize(&data)?;
+        // the header holds a u32 length
+        let Ok(len) = u32::try_from(bytes.len()) else {
+            anyhow::bail!("index snapshot is {} bytes, over the 4 GiB a snapshot can hold", bytes.len());
+        };
+        if let Some(z) = &self.quantized {
+            let raw_tmp = path.with_extension("f32.tmp");
+            z.raw.copy_to(&raw_tmp, slots)?;
+            std::fs::rename(&raw_tmp, snapshot_raw_path(path))?;
+        }
+        let mut f = std::fs::OpenOptions::new().create(true).write(true).truncate(true).open(&tmp)?;
         use std::io::Write;
-        f.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        f.write_all(SNAPSHOT_MAGIC)?;
+        f.write_all(&len.to_le_bytes())?;
         f.write_all(&bytes)?;
-        Ok(())
+        f.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        crate::storage::wal::sync_dir(dir)?;
+        Ok(data.graph.ids.into_keys().collect())
     }
 
+    // A quantized index keeps its full vectors in a new raw file next to
+    // `path`, removed when the index is dropped.
     pub fn load_from(path: std::path::PathBuf) -> anyhow::Result<Self> {
-        let mut f = std::fs::OpenOptions::new().read(true).open(path)?;
-        let mut len_buf = [0u8;4];
-        use std::io::Read;
-        f.read_exact(&mut len_buf)?;
-        let len = u32::from_le_bytes(len_buf) as usize;
-        let mut buf = vec![0u8; len];
-        f.read_exact(&mut buf)?;
-        let idx: HnswIndex = bincode::deserialize(&buf)?;
-        Ok(idx)
+        Snapshot::read(&path)?.build(path.parent().unwrap_or(std::path::Path::new(".")), Some)
     }
 }
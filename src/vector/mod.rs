@@ -2,3 +2,5 @@
 pub mod flat;
 
 pub mod hnsw;
+
+pub mod quant;
@@ -0,0 +1,468 @@
+use std::borrow::Cow;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{bail, Result};
+use parking_lot::Mutex;
+use rand::{seq::SliceRandom, SeedableRng};
+use serde::{Serialize, Deserialize};
+use crate::types::Vector;
+
+// Lossy in-memory codes for vectors: int8 scalar quantization (one byte per
+// dimension) or product quantization (one byte per subspace). Similarities
+// against codes are asymmetric: the query stays f32 and is turned into a
+// per-query lookup table once. The full vectors move to a `RawVectors` file,
+// read back only to re-rank the best candidates exactly.
+
+// centroids per subspace, so a code entry fits in a byte
+const CODEBOOK: usize = 256;
+const KMEANS_ITERS: usize = 12;
+// candidates per hit re-ranked with full vectors, unless set otherwise
+pub const DEFAULT_RERANK: usize = 4;
+// vectors a quantizer is trained on at most
+pub const TRAIN_SAMPLE: usize = 20_000;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantization {
+    Scalar,
+    // `subspaces` must divide the vector dims
+    Product { subspaces: usize },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Quantizer {
+    Scalar(ScalarQuantizer),
+    Product(ProductQuantizer),
+}
+
+// Per-dimension affine map of [min, max] onto 0..=255.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScalarQuantizer {
+    min: Vec<f32>,
+    step: Vec<f32>,
+}
+
+// Splits vectors into `subspaces` equal slices and codes each slice as its
+// nearest centroid in that subspace's k-means codebook.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProductQuantizer {
+    dims: usize,
+    subspaces: usize,
+    // per subspace: up to CODEBOOK centroids of dims / subspaces values, flat
+    centroids: Vec<Vec<f32>>,
+}
+
+// A query prepared for scoring codes; see `Quantizer::table`.
+pub enum QueryTable {
+    // dot = offset + sum(weights[i] * code[i])
+    Scalar { offset: f32, weights: Vec<f32> },
+    // dot = sum(table[j * CODEBOOK + code[j]])
+    Product { table: Vec<f32> },
+}
+
+impl Quantizer {
+    pub fn train(kind: Quantization, samples: &[Vector]) -> Result<Self> {
+        let Some(first) = samples.first() else { bail!("no vectors to train a quantizer on") };
+        let dims = first.0.len();
+        if samples.iter().any(|v| v.0.len() != dims) { bail!("training vectors differ in length"); }
+        Ok(match kind {
+            Quantization::Scalar => Quantizer::Scalar(ScalarQuantizer::train(samples, dims)),
+            Quantization::Product { subspaces } => Quantizer::Product(ProductQuantizer::train(samples, dims, subspaces)?),
+        })
+    }
+
+    pub fn dims(&self) -> usize {
+        match self {
+            Quantizer::Scalar(s) => s.min.len(),
+            Quantizer::Product(p) => p.dims,
+        }
+    }
+
+    // Bytes per code: dims for scalar, subspaces for product quantization.
+    pub fn code_len(&self) -> usize {
+        match self {
+            Quantizer::Scalar(s) => s.min.len(),
+            Quantizer::Product(p) => p.subspaces,
+        }
+    }
+
+    pub fn encode(&self, v: &[f32]) -> Vec<u8> {
+        match self {
+            Quantizer::Scalar(s) => v.iter().zip(s.min.iter().zip(&s.step))
+                .map(|(x, (lo, step))| ((x - lo) / step).round().clamp(0.0, 255.0) as u8)
+                .collect(),
+            Quantizer::Product(p) => (0..p.subspaces).map(|j| nearest(&p.centroids[j], p.sub_slice(v, j)) as u8).collect(),
+        }
+    }
+
+    // The vector a code stands for.
+    pub fn decode(&self, code: &[u8]) -> Vector {
+        Vector(match self {
+            Quantizer::Scalar(s) => code.iter().zip(s.min.iter().zip(&s.step)).map(|(c, (lo, step))| lo + *c as f32 * step).collect(),
+            Quantizer::Product(p) => {
+                let sub = p.sub_dims();
+                code.iter().enumerate().flat_map(|(j, c)| p.centroids[j][*c as usize * sub..(*c as usize + 1) * sub].iter().copied()).collect()
+            }
+        })
+    }
+
+    // Lookup table for dot products between `q` and any code.
+    pub fn table(&self, q: &[f32]) -> QueryTable {
+        match self {
+            Quantizer::Scalar(s) => QueryTable::Scalar {
+                offset: q.iter().zip(&s.min).map(|(x, lo)| x * lo).sum(),
+                weights: q.iter().zip(&s.step).map(|(x, step)| x * step).collect(),
+            },
+            Quantizer::Product(p) => {
+                let sub = p.sub_dims();
+                let mut table = vec![0.0; p.subspaces * CODEBOOK];
+                for j in 0..p.subspaces {
+                    let qs = p.sub_slice(q, j);
+                    for (c, centroid) in p.centroids[j].chunks(sub).enumerate() {
+                        table[j * CODEBOOK + c] = dot(qs, centroid);
+                    }
+                }
+                QueryTable::Product { table }
+            }
+        }
+    }
+}
+
+impl QueryTable {
+    // Dot product of the query with the vector `code` stands for.
+    pub fn dot(&self, code: &[u8]) -> f32 {
+        match self {
+            QueryTable::Scalar { offset, weights } => offset + weights.iter().zip(code).map(|(w, c)| w * *c as f32).sum::<f32>(),
+            QueryTable::Product { table } => code.iter().enumerate().map(|(j, c)| table[j * CODEBOOK + *c as usize]).sum(),
+        }
+    }
+}
+
+impl ScalarQuantizer {
+    fn train(samples: &[Vector], dims: usize) -> Self {
+        let mut min = vec![f32::MAX; dims];
+        let mut max = vec![f32::MIN; dims];
+        for v in samples {
+            for (i, x) in v.0.iter().enumerate() {
+                min[i] = min[i].min(*x);
+                max[i] = max[i].max(*x);
+            }
+        }
+        // a constant dimension still needs a non-zero step
+        let step = min.iter().zip(&max).map(|(lo, hi)| if hi > lo { (hi - lo) / 255.0 } else { 1.0 }).collect();
+        Self { min, step }
+    }
+}
+
+impl ProductQuantizer {
+    fn train(samples: &[Vector], dims: usize, subspaces: usize) -> Result<Self> {
+        if subspaces == 0 || !dims.is_multiple_of(subspaces) {
+            bail!("{} subspaces do not divide {} dims", subspaces, dims);
+        }
+        let mut pq = Self { dims, subspaces, centroids: Vec::with_capacity(subspaces) };
+        let k = samples.len().min(CODEBOOK);
+        let sub = pq.sub_dims();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x5eed);
+        for j in 0..subspaces {
+            let points: Vec<&[f32]> = samples.iter().map(|v| pq.sub_slice(&v.0, j)).collect();
+            // Lloyd's k-means from k distinct samples
+            let mut centroids: Vec<f32> = points.choose_multiple(&mut rng, k).flat_map(|p| p.iter().copied()).collect();
+            for _ in 0..KMEANS_ITERS {
+                let mut sums = vec![0.0f32; k * sub];
+                let mut counts = vec![0usize; k];
+                for p in &points {
+                    let c = nearest(&centroids, p);
+                    counts[c] += 1;
+                    for (s, x) in sums[c * sub..(c + 1) * sub].iter_mut().zip(p.iter()) { *s += x; }
+                }
+                // an empty cluster keeps its centroid
+                for c in (0..k).filter(|c| counts[*c] > 0) {
+                    for i in 0..sub { centroids[c * sub + i] = sums[c * sub + i] / counts[c] as f32; }
+                }
+            }
+            pq.centroids.push(centroids);
+        }
+        Ok(pq)
+    }
+
+    fn sub_dims(&self) -> usize { self.dims / self.subspaces }
+
+    fn sub_slice<'a>(&self, v: &'a [f32], j: usize) -> &'a [f32] {
+        let sub = self.sub_dims();
+        &v[j * sub..(j + 1) * sub]
+    }
+}
+
+// Index of the centroid (flat, `p.len()` values each) nearest to `p` in L2.
+fn nearest(centroids: &[f32], p: &[f32]) -> usize {
+    centroids.chunks(p.len()).enumerate()
+        .map(|(c, centroid)| (c, centroid.iter().zip(p).map(|(a, b)| (a - b) * (a - b)).sum::<f32>()))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(c, _)| c)
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 { a.iter().zip(b).map(|(x, y)| x * y).sum() }
+
+fn norm(a: &[f32]) -> f32 { dot(a, a).sqrt() }
+
+// Full-precision vectors in a file of fixed-size slots, appended in order.
+// Slots are never reused, so replaced and removed vectors keep their space
+// until the index is rebuilt (see `HnswIndex::vacuumed`); the file is
+// deleted when the last handle goes.
+pub struct RawVectors {
+    path: PathBuf,
+    dims: usize,
+    file: std::fs::File,
+    // slots written; reads past it fail
+    slots: AtomicU64,
+    // one append at a time
+    append_lock: Mutex<()>,
+    // without positional IO, reads and writes seek the shared cursor first
+    #[cfg(not(unix))]
+    cursor: Mutex<()>,
+}
+
+impl RawVectors {
+    // A new, empty file in `dir`.
+    pub fn create_in(dir: impl AsRef<Path>, dims: usize) -> Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        let path = dir.as_ref().join(format!("{}.f32", uuid::Uuid::new_v4()));
+        let file = std::fs::OpenOptions::new().create_new(true).read(true).write(true).open(&path)?;
+        Ok(Self::from_file(path, dims, file, 0))
+    }
+
+    fn from_file(path: PathBuf, dims: usize, file: std::fs::File, slots: u64) -> Self {
+        Self {
+            path,
+            dims,
+            file,
+            slots: AtomicU64::new(slots),
+            append_lock: Mutex::new(()),
+            #[cfg(not(unix))]
+            cursor: Mutex::new(()),
+        }
+    }
+
+    // A new file in `dir` holding a copy of the slots in `src`, a file
+    // written by `copy_to`.
+    pub fn open_copy(src: impl AsRef<Path>, dir: impl AsRef<Path>, dims: usize) -> Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        let path = dir.as_ref().join(format!("{}.f32", uuid::Uuid::new_v4()));
+        let copied = std::fs::copy(src.as_ref(), &path).and_then(|_| std::fs::OpenOptions::new().read(true).write(true).open(&path));
+        let file = match copied {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = std::fs::remove_file(&path);
+                return Err(e.into());
+            }
+        };
+        // removed on drop from here on
+        let raw = Self::from_file(path, dims, file, 0);
+        let bytes = raw.file.metadata()?.len();
+        if bytes % raw.slot_bytes() != 0 { bail!("{} is not a whole number of {}-dim vectors", src.as_ref().display(), dims); }
+        raw.slots.store(bytes / raw.slot_bytes(), Ordering::Release);
+        Ok(raw)
+    }
+
+    // Writes the first `slots` slots to a file at `path`, replacing it.
+    pub fn copy_to(&self, path: impl AsRef<Path>, slots: u64) -> Result<()> {
+        use std::io::Write;
+        if slots > self.len() { bail!("raw vector file has {} slots, not {}", self.len(), slots); }
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path.as_ref())?);
+        let mut buf = vec![0u8; self.slot_bytes() as usize * 1024];
+        let end = slots * self.slot_bytes();
+        let mut off = 0;
+        while off < end {
+            let n = (end - off).min(buf.len() as u64) as usize;
+            self.read_at(&mut buf[..n], off)?;
+            out.write_all(&buf[..n])?;
+            off += n as u64;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+
+    // Slots written so far.
+    pub fn len(&self) -> u64 { self.slots.load(Ordering::Acquire) }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn dims(&self) -> usize { self.dims }
+
+    // Appends `v` and returns its slot.
+    pub fn append(&self, v: &[f32]) -> Result<u64> {
+        if v.len() != self.dims { bail!("vector has {} dims, file holds {}", v.len(), self.dims); }
+        let bytes: Vec<u8> = v.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let _appending = self.append_lock.lock();
+        let slot = self.slots.load(Ordering::Relaxed);
+        self.write_at(&bytes, slot * self.slot_bytes())?;
+        // published once written, so readers never see a partial slot
+        self.slots.store(slot + 1, Ordering::Release);
+        Ok(slot)
+    }
+
+    // Positional read; on unix, concurrent reads and appends do not wait on
+    // each other.
+    pub fn read(&self, slot: u64) -> Result<Vector> {
+        if slot >= self.slots.load(Ordering::Acquire) { bail!("no vector in slot {}", slot); }
+        let mut bytes = vec![0u8; self.slot_bytes() as usize];
+        self.read_at(&mut bytes, slot * self.slot_bytes())?;
+        Ok(Vector(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()))
+    }
+
+    fn slot_bytes(&self) -> u64 { (self.dims * std::mem::size_of::<f32>()) as u64 }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> { self.file.read_exact_at(buf, offset) }
+
+    #[cfg(unix)]
+    fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> { self.file.write_all_at(buf, offset) }
+
+    #[cfg(not(unix))]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+        let _cursor = self.cursor.lock();
+        (&self.file).seek(SeekFrom::Start(offset))?;
+        (&self.file).read_exact(buf)
+    }
+
+    #[cfg(not(unix))]
+    fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        use std::io::{Seek, SeekFrom, Write};
+        let _cursor = self.cursor.lock();
+        (&self.file).seek(SeekFrom::Start(offset))?;
+        (&self.file).write_all(buf)
+    }
+}
+
+impl Drop for RawVectors {
+    fn drop(&mut self) { let _ = std::fs::remove_file(&self.path); }
+}
+
+// What an index needs to keep codes instead of vectors. Rebuilt copies of
+// an index (see `HnswIndex::vacuumed`) share the quantizer and get a new
+// raw file with only the live vectors.
+#[derive(Clone)]
+pub(crate) struct Quantized {
+    pub quantizer: Arc<Quantizer>,
+    pub raw: Arc<RawVectors>,
+    // candidates per hit re-ranked exactly
+    pub rerank: usize,
+}
+
+// A vector as an index holds it, with the norm it scores with.
+#[derive(Clone)]
+pub(crate) enum Stored {
+    Raw { vec: Vector, norm: f32 },
+    // the full vector is in the raw file at `slot`; `norm` is the decoded
+    // code's
+    Code { code: Box<[u8]>, slot: u64, norm: f32 },
+}
+
+impl Stored {
+    pub fn raw(vec: Vector) -> Self {
+        let norm = norm(&vec.0);
+        Stored::Raw { vec, norm }
+    }
+
+    pub fn norm(&self) -> f32 {
+        match self { Stored::Raw { norm, .. } | Stored::Code { norm, .. } => *norm }
+    }
+
+    // The full vector; codes are only ever stored next to a `Quantized`.
+    pub fn exact(&self, quantized: Option<&Quantized>) -> Result<Vector> {
+        match (quantized, self) {
+            (Some(z), _) => z.exact(self),
+            (None, Stored::Raw { vec, .. }) => Ok(vec.clone()),
+            (None, Stored::Code { slot, .. }) => bail!("vector in raw slot {} is coded but the index has no quantizer", slot),
+        }
+    }
+}
+
+impl Quantized {
+    pub fn new(quantizer: Quantizer, raw: RawVectors, rerank: usize) -> Self {
+        Self { quantizer: Arc::new(quantizer), raw: Arc::new(raw), rerank: rerank.max(1) }
+    }
+
+    // Codes `v`, writing the full vector to the raw file.
+    pub fn store(&self, v: Vector) -> Result<Stored> {
+        let slot = self.raw.append(&v.0)?;
+        let code: Box<[u8]> = self.quantizer.encode(&v.0).into();
+        let norm = norm(&self.quantizer.decode(&code).0);
+        Ok(Stored::Code { code, slot, norm })
+    }
+
+    // The full vector behind `s`, read back from the raw file for codes.
+    pub fn exact(&self, s: &Stored) -> Result<Vector> {
+        match s {
+            Stored::Raw { vec, .. } => Ok(vec.clone()),
+            Stored::Code { slot, .. } => self.raw.read(*slot),
+        }
+    }
+
+    // The same quantizer over a new, empty raw file next to this one.
+    pub fn with_new_raw(&self) -> Result<Self> {
+        let dir = self.raw.path().parent().unwrap_or(Path::new("."));
+        let raw = RawVectors::create_in(dir, self.raw.dims())?;
+        Ok(Self { quantizer: self.quantizer.clone(), raw: Arc::new(raw), rerank: self.rerank })
+    }
+}
+
+// Cosine similarity of stored vectors to one query. Codes are scored through
+// the query's lookup table when it has one, else by decoding them, which is
+// cheaper for a handful of comparisons.
+pub(crate) struct Scorer<'a> {
+    q: Cow<'a, [f32]>,
+    qn: f32,
+    table: Option<QueryTable>,
+    quantized: Option<&'a Quantized>,
+}
+
+impl<'a> Scorer<'a> {
+    pub fn new(q: Cow<'a, [f32]>, quantized: Option<&'a Quantized>) -> Self {
+        let table = quantized.map(|z| z.quantizer.table(&q));
+        Self { qn: norm(&q), q, table, quantized }
+    }
+
+    pub fn plain(q: Cow<'a, [f32]>, quantized: Option<&'a Quantized>) -> Self {
+        Self { qn: norm(&q), q, table: None, quantized }
+    }
+
+    // A scorer for the vector `s` itself: the decoded one for codes.
+    pub fn of(s: &'a Stored, quantized: Option<&'a Quantized>) -> Self {
+        match (s, quantized) {
+            (Stored::Code { code, .. }, Some(z)) => Self::plain(Cow::Owned(z.quantizer.decode(code).0), quantized),
+            (Stored::Raw { vec, .. }, _) => Self::plain(Cow::Borrowed(&vec.0), quantized),
+            (Stored::Code { .. }, None) => Self::plain(Cow::Borrowed(&[]), None),
+        }
+    }
+
+    pub fn cosine(&self, s: &Stored) -> f32 {
+        let n = s.norm();
+        if self.qn == 0.0 || n == 0.0 { return 0.0; }
+        let d = match (s, &self.table, self.quantized) {
+            (Stored::Raw { vec, .. }, ..) => dot(&self.q, &vec.0),
+            (Stored::Code { code, .. }, Some(table), _) => table.dot(code),
+            (Stored::Code { code, .. }, None, Some(z)) => dot(&self.q, &z.quantizer.decode(code).0),
+            (Stored::Code { .. }, None, None) => 0.0,
+        };
+        d / (self.qn * n)
+    }
+}
+
+// Exact cosine of `q` with each candidate's full vector; the best k.
+pub(crate) fn rerank(q: &[f32], k: usize, candidates: Vec<(u64, Vector)>) -> Vec<(u64, f32)> {
+    let qn = norm(q);
+    let mut hits: Vec<(u64, f32)> = candidates.into_iter().map(|(id, v)| {
+        let vn = norm(&v.0);
+        (id, if qn == 0.0 || vn == 0.0 { 0.0 } else { dot(q, &v.0) / (qn * vn) })
+    }).collect();
+    hits.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    hits.truncate(k);
+    hits
+}